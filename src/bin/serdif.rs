@@ -0,0 +1,287 @@
+//! Inspect and maintain serdif files.
+
+use anyhow::{anyhow, bail, Context};
+use serdif::{Deserializer, EncryptionKey, Options, Serializer, SigningKey, Snapshot, DEFAULT_DOCUMENT};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::BufReader;
+use std::process;
+use std::time::UNIX_EPOCH;
+
+const USAGE: &str = "\
+usage: serdif [--key HEX] [--signing-key HEX] [--branch NAME] <command> <args>
+
+commands:
+    log FILE                          list the committed versions
+    cat [--version N] [--document NAME] FILE
+                                      print a document as JSON
+    stitches FILE                     list the stitches of every version
+    verify FILE                       check the structure of every version
+    compact FILE OUT                  write the latest version to a new file
+    import FILE JSON...               commit each JSON file in turn as the
+                                      default document
+    history [--document NAME] FILE PATH
+                                      list the versions that changed the
+                                      value at PATH, such as .limits.max
+    blame [--version N] [--document NAME] FILE
+                                      list every leaf value with the
+                                      version that last changed it
+    refs FILE                         list the branches and tags
+
+--key gives the key of an encrypted file, and --signing-key the key its
+trailers are signed with. --branch selects the branch to read, which is
+main by default.";
+
+type Result<T> = anyhow::Result<T>;
+
+fn main() {
+    match run(std::env::args().skip(1).collect()) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("serdif: {:#}", e);
+            process::exit(2);
+        }
+    }
+}
+
+/// The arguments left after the options, which may appear anywhere.
+struct Args {
+    options: Options,
+    version: Option<usize>,
+    document: Option<String>,
+    rest: Vec<String>,
+}
+
+fn parse(args: Vec<String>) -> Result<Args> {
+    let mut parsed = Args {
+        options: Options::default(),
+        version: None,
+        document: None,
+        rest: Vec::new(),
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| anyhow!("{} needs a value", name));
+        match arg.as_str() {
+            "--key" => {
+                let key = hex::decode(value("--key")?).context("--key")?;
+                let key = key.try_into().map_err(|_| anyhow!("--key must be 32 bytes"))?;
+                parsed.options.key = Some(EncryptionKey(key));
+            }
+            "--signing-key" => {
+                let key = hex::decode(value("--signing-key")?).context("--signing-key")?;
+                parsed.options.signing_key = Some(SigningKey(key));
+            }
+            "--branch" => parsed.options.branch = value("--branch")?,
+            "--version" => {
+                parsed.version = Some(value("--version")?.parse().context("--version")?);
+            }
+            "--document" => parsed.document = Some(value("--document")?),
+            "-h" | "--help" => parsed.rest.insert(0, "help".to_string()),
+            _ if arg.starts_with("--") => bail!("unknown option {}", arg),
+            _ => parsed.rest.push(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+fn run(args: Vec<String>) -> Result<i32> {
+    let args = parse(args)?;
+    let (command, paths) = match args.rest.split_first() {
+        Some((command, paths)) => (command.as_str(), paths),
+        None => ("help", &[][..]),
+    };
+    let path = |n: usize| -> Result<&str> {
+        if paths.len() != n {
+            bail!("{} takes {} arguments\n\n{}", command, n, USAGE);
+        }
+        Ok(&paths[0])
+    };
+    match command {
+        "log" => log(open(path(1)?, &args.options)?),
+        "cat" => cat(open(path(1)?, &args.options)?, args.version, args.document.as_deref()),
+        "stitches" => stitches(open(path(1)?, &args.options)?),
+        "verify" => return verify(path(1)?, &args.options),
+        "compact" => compact(path(2)?, &paths[1], &args.options),
+        "import" if !paths.is_empty() => import(&paths[0], &paths[1..], &args.options),
+        "history" => history(open(path(2)?, &args.options)?, &paths[1], args.document.as_deref()),
+        "blame" => blame(open(path(1)?, &args.options)?, args.version, args.document.as_deref()),
+        "refs" => refs(open(path(1)?, &args.options)?),
+        "help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => bail!("unknown command {}\n\n{}", command, USAGE),
+    }?;
+    Ok(0)
+}
+
+fn open(path: &str, options: &Options) -> Result<Deserializer> {
+    Ok(Deserializer::with_options(open_shared(path)?, options.clone())?)
+}
+
+/// Open the file for reading, with a shared lock so writers wait.
+fn open_shared(path: &str) -> Result<File> {
+    let file = File::open(path).with_context(|| format!("opening {}", path))?;
+    file.lock_shared().with_context(|| format!("locking {}", path))?;
+    Ok(file)
+}
+
+fn log(mut de: Deserializer) -> Result<()> {
+    let tags = de.refs().tags.clone();
+    for version in de.versions()?.iter().rev() {
+        let trailer = &version.trailer;
+        println!("version {}", version.version);
+        let names: Vec<&str> = tags.iter()
+            .filter(|(_, pos)| **pos == version.trailer_pos)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if !names.is_empty() {
+            println!("    tags: {}", names.join(", "));
+        }
+        if let Some(timestamp) = version.info.timestamp {
+            println!("    timestamp: {}", timestamp);
+        }
+        if let Some(message) = &version.info.message {
+            println!("    message: {}", message);
+        }
+        if let Some(undo) = version.info.undo {
+            println!("    undo to version {}", undo);
+        }
+        if let Some(redo) = version.info.redo {
+            println!("    redo to version {}", redo);
+        }
+        if let Some(merge) = version.info.merge {
+            println!("    merge of trailer at {}", merge);
+        }
+        println!("    trailer at {}", version.trailer_pos);
+        println!("    stitches: {}", version.stitches.len());
+        let documents: Vec<String> = version.documents.keys().map(|name| format!("{:?}", name)).collect();
+        println!("    documents: {}", documents.join(", "));
+        if let Some(hash) = &trailer.data_hash {
+            println!("    data hash: {}", hex::encode(&hash.0));
+        }
+        if trailer.signature.is_some() {
+            println!("    signed");
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Print the selected document, or every document as a JSON object if none
+/// is selected and the file has no default document.
+fn cat(mut de: Deserializer, version: Option<usize>, document: Option<&str>) -> Result<()> {
+    if let Some(version) = version {
+        de.select_version(version)?;
+    }
+    let names: Vec<String> = de.documents().map(str::to_string).collect();
+    let value = match document {
+        Some(name) => de.document(name)?.read_json()?,
+        None if names.iter().any(|name| name == DEFAULT_DOCUMENT) => {
+            de.document(DEFAULT_DOCUMENT)?.read_json()?
+        }
+        None => {
+            let mut documents = serde_json::Map::new();
+            for name in names {
+                let value = de.document(&name)?.read_json()?;
+                documents.insert(name, value);
+            }
+            serde_json::Value::Object(documents)
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&value)?);
+    Ok(())
+}
+
+fn history(mut de: Deserializer, path: &str, document: Option<&str>) -> Result<()> {
+    if let Some(name) = document {
+        de.document(name)?;
+    }
+    for (version, value) in serdif::history(&mut de, path)? {
+        let value = match value {
+            Some(value) => serde_json::to_string(&value)?,
+            None => "(removed)".to_string(),
+        };
+        print!("version {}: {}", version.version, value);
+        if let Some(message) = &version.info.message {
+            print!("  # {}", message);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn blame(mut de: Deserializer, version: Option<usize>, document: Option<&str>) -> Result<()> {
+    if let Some(version) = version {
+        de.select_version(version)?;
+    }
+    if let Some(name) = document {
+        de.document(name)?;
+    }
+    for blame in serdif::blame(&mut de)? {
+        let path = if blame.path.is_empty() { "." } else { &blame.path };
+        println!("{:>4} {} = {}", blame.version, path, serde_json::to_string(&blame.value)?);
+    }
+    Ok(())
+}
+
+fn refs(de: Deserializer) -> Result<()> {
+    let refs = de.refs();
+    for (name, pos) in &refs.heads {
+        println!("branch {}: trailer at {}", name, pos);
+    }
+    for (name, pos) in &refs.tags {
+        println!("tag {}: trailer at {}", name, pos);
+    }
+    Ok(())
+}
+
+fn stitches(mut de: Deserializer) -> Result<()> {
+    for version in de.versions()? {
+        for (i, (pos, stitch)) in version.stitches.iter().enumerate() {
+            print!("version {} stitch at {}: old_pos {} new_pos {} next_stitch_pos {}",
+                   version.version, pos, stitch.old_pos, stitch.new_pos, stitch.next_stitch_pos);
+            match version.paths.get(i) {
+                Some(path) if path.is_empty() => println!(" (document root)"),
+                Some(path) => println!(" ({})", path),
+                None => println!(),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the report, exiting with 1 if there are problems.
+fn verify(path: &str, options: &Options) -> Result<i32> {
+    let file = open_shared(path)?;
+    let report = serdif::verify_with_options(file, options);
+    print!("{}", report);
+    Ok(if report.is_ok() { 0 } else { 1 })
+}
+
+fn compact(path: &str, out_path: &str, options: &Options) -> Result<()> {
+    let file = open_shared(path)?;
+    let out = OpenOptions::new().read(true).write(true).create_new(true).open(out_path)
+        .with_context(|| format!("creating {}", out_path))?;
+    serdif::compact(file, out, options)?;
+    Ok(())
+}
+
+/// Import JSON files with their paths as commit messages and modification
+/// times as timestamps.
+fn import(path: &str, snapshots: &[String], options: &Options) -> Result<()> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+        .with_context(|| format!("opening {}", path))?;
+    file.lock().with_context(|| format!("locking {}", path))?;
+    let mut ser = Serializer::with_options(file, options.clone())?;
+    for snapshot_path in snapshots {
+        let snapshot = File::open(snapshot_path).with_context(|| format!("opening {}", snapshot_path))?;
+        let modified = snapshot.metadata()?.modified()?;
+        let timestamp = modified.duration_since(UNIX_EPOCH)?.as_secs();
+        let snapshot = Snapshot::from_reader(BufReader::new(snapshot))
+            .with_context(|| format!("reading {}", snapshot_path))?;
+        serdif::import(&mut ser, Some(snapshot.message(snapshot_path.as_str()).timestamp(timestamp)))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,827 @@
+//! A compact binary encoding of commands and records.
+//!
+//! Every command and record starts with a one-byte tag naming its type,
+//! followed by its fields in declaration order with no field names.
+//! Integers are LEB128 varints, zigzag-encoded when signed. Records that may
+//! gain fields over time also carry their field count.
+//!
+//! Stitches don't go through serde. They are a tag followed by three
+//! little-endian `u64`s, so they have a fixed size and can be rewritten in
+//! place.
+
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{de, ser, Serialize};
+use serde::de::{DeserializeOwned, IntoDeserializer, Visitor};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use crate::codec::Codec;
+use crate::error::{Error, Result, StdResultExt};
+use crate::meta::Stitch;
+
+/// The types that can be written, by tag. Tag 0 is never used.
+const TAGS: &[&str] = &[
+    "",
+    "SerializeBool",
+    "SerializeU8",
+    "SerializeTuple",
+    "SerializeTupleElement",
+    "SerializeTupleEnd",
+    "SerializeStruct",
+    "SerializeStructField",
+    "SerializeStructEnd",
+    "Stitch",
+    "Trailer",
+    "Catalog",
+    "SerializeUnit",
+    "SerializeI64",
+    "SerializeU64",
+    "SerializeF64",
+    "SerializeStr",
+    "SerializeSeq",
+    "SerializeSeqElement",
+    "SerializeSeqEnd",
+    "SerializeMap",
+    "SerializeMapKey",
+    "SerializeMapEnd",
+    "SerializeUnitVariant",
+    "SerializeVariant",
+    "SerializeVariantEnd",
+    "Compressed",
+    "CommitInfo",
+    "StitchPaths",
+    "Refs",
+];
+
+/// Records whose fields are preceded by their count, so fields can be
+/// appended to them.
+const RECORDS: &[&str] = &["Trailer", "Catalog", "CommitInfo", "StitchPaths", "Refs"];
+
+pub const STITCH_SIZE: u64 = 1 + 3 * 8;
+
+fn tag(name: &str) -> Result<u8> {
+    TAGS.iter().position(|t| *t == name)
+        .filter(|tag| *tag != 0)
+        .map(|tag| tag as u8)
+        .ok_or_else(|| anyhow!("type {} has no binary tag", name).into())
+}
+
+/// The codec for `Encoding::Binary`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn write<T: Serialize + ?Sized>(&self, w: &mut dyn Write, v: &T) -> Result<()> {
+        to_writer(w, v)
+    }
+
+    fn read<T: DeserializeOwned>(&self, r: &mut dyn Read) -> Result<T> {
+        from_reader(r)
+    }
+
+    fn write_stitch(&self, w: &mut dyn Write, stitch: Stitch) -> Result<()> {
+        write_stitch(w, &stitch)
+    }
+
+    fn read_stitch(&self, r: &mut dyn Read) -> Result<Stitch> {
+        read_stitch(r)
+    }
+
+    fn is_eof(&self, e: &Error) -> bool {
+        e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+    }
+}
+
+pub fn to_writer(w: &mut dyn Write, v: impl Serialize) -> Result<()> {
+    v.serialize(&mut Writer { w })
+}
+
+pub fn from_reader<T: DeserializeOwned>(r: &mut dyn Read) -> Result<T> {
+    T::deserialize(&mut Reader { r })
+}
+
+pub fn write_stitch(w: &mut dyn Write, stitch: &Stitch) -> Result<()> {
+    let mut buf = [0; STITCH_SIZE as usize];
+    buf[0] = tag("Stitch")?;
+    LittleEndian::write_u64(&mut buf[1..9], stitch.old_pos);
+    LittleEndian::write_u64(&mut buf[9..17], stitch.new_pos);
+    LittleEndian::write_u64(&mut buf[17..25], stitch.next_stitch_pos);
+    w.write_all(&buf).e()
+}
+
+pub fn read_stitch(r: &mut dyn Read) -> Result<Stitch> {
+    let mut buf = [0; STITCH_SIZE as usize];
+    r.read_exact(&mut buf).e()?;
+    if buf[0] != tag("Stitch")? {
+        return Err(anyhow!("expected a stitch, found tag {}", buf[0]).into());
+    }
+    Ok(Stitch {
+        old_pos: LittleEndian::read_u64(&buf[1..9]),
+        new_pos: LittleEndian::read_u64(&buf[9..17]),
+        next_stitch_pos: LittleEndian::read_u64(&buf[17..25]),
+    })
+}
+
+struct Writer<'a> {
+    w: &'a mut dyn Write,
+}
+
+impl Writer<'_> {
+    fn byte(&mut self, v: u8) -> Result<()> {
+        self.w.write_all(&[v]).e()
+    }
+
+    fn varint(&mut self, mut v: u64) -> Result<()> {
+        while v >= 0x80 {
+            self.byte(v as u8 | 0x80)?;
+            v >>= 7;
+        }
+        self.byte(v as u8)
+    }
+
+    fn zigzag(&mut self, v: i64) -> Result<()> {
+        self.varint(((v << 1) ^ (v >> 63)) as u64)
+    }
+
+    fn len(&mut self, len: Option<usize>) -> Result<()> {
+        let len = len.ok_or_else(|| anyhow!("binary encoding requires known lengths"))?;
+        self.varint(len as u64)
+    }
+}
+
+impl<'a> ser::Serializer for &mut Writer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.byte(v as u8)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.zigzag(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.zigzag(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.zigzag(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.zigzag(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.varint(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.varint(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.varint(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.varint(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.w.write_all(&v.to_le_bytes()).e()
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.w.write_all(&v.to_le_bytes()).e()
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.varint(v.into())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.varint(v.len() as u64)?;
+        self.w.write_all(v).e()
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.byte(0)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.byte(1)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<()> {
+        let tag = tag(name)?;
+        self.byte(tag)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<()> {
+        self.varint(variant_index.into())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.varint(variant_index.into())?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        self.len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        self.varint(variant_index.into())?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
+        self.len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct> {
+        let tag = tag(name)?;
+        self.byte(tag)?;
+        if RECORDS.contains(&name) {
+            self.varint(len as u64)?;
+        }
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        self.varint(variant_index.into())?;
+        Ok(self)
+    }
+}
+
+impl<'a> ser::SerializeSeq for &mut Writer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTuple for &mut Writer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for &mut Writer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for &mut Writer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeMap for &mut Writer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeStruct for &mut Writer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for &mut Writer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    r: &'a mut dyn Read,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8> {
+        let mut buf = [0];
+        self.r.read_exact(&mut buf).e()?;
+        Ok(buf[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            v |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(anyhow!("varint is too long").into())
+    }
+
+    fn zigzag(&mut self) -> Result<i64> {
+        let v = self.varint()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(self.varint()? as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.len()?;
+        let mut buf = Vec::new();
+        self.r.take(len as u64).read_to_end(&mut buf).e()?;
+        if buf.len() != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)).e();
+        }
+        Ok(buf)
+    }
+
+    fn tag(&mut self, name: &str) -> Result<()> {
+        let expected = tag(name)?;
+        let found = self.byte()?;
+        if found != expected {
+            let found = TAGS.get(found as usize).copied().unwrap_or("unknown");
+            return Err(anyhow!("expected {}, found {}", name, found).into());
+        }
+        Ok(())
+    }
+}
+
+struct Access<'a, 'b> {
+    de: &'a mut Reader<'b>,
+    len: usize,
+}
+
+impl<'a, 'b, 'de> de::SeqAccess<'de> for Access<'a, 'b> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where T: de::DeserializeSeed<'de>,
+    {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+impl<'a, 'b, 'de> de::MapAccess<'de> for Access<'a, 'b> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where K: de::DeserializeSeed<'de>,
+    {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where V: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+impl<'a, 'b, 'de> de::EnumAccess<'de> for &'a mut Reader<'b> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self)>
+    where V: de::DeserializeSeed<'de>,
+    {
+        let index = self.varint()? as u32;
+        let index: de::value::U32Deserializer<Error> = index.into_deserializer();
+        let value = seed.deserialize(index)?;
+        Ok((value, self))
+    }
+}
+
+impl<'a, 'b, 'de> de::VariantAccess<'de> for &'a mut Reader<'b> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where T: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_seq(Access { de: self, len })
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_seq(Access { de: self, len: fields.len() })
+    }
+}
+
+impl<'a, 'b, 'de> de::Deserializer<'de> for &'a mut Reader<'b> {
+    type Error = Error;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        Err(anyhow!("binary encoding is not self-describing").into())
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        match self.byte()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            b => Err(anyhow!("invalid bool {}", b).into()),
+        }
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_i64(self.zigzag()?)
+    }
+
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_i64(self.zigzag()?)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_i64(self.zigzag()?)
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_i64(self.zigzag()?)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_u64(self.varint()?)
+    }
+
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_u64(self.varint()?)
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_u64(self.varint()?)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_u64(self.varint()?)
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        let mut buf = [0; 4];
+        self.r.read_exact(&mut buf).e()?;
+        visitor.visit_f32(f32::from_le_bytes(buf))
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        let mut buf = [0; 8];
+        self.r.read_exact(&mut buf).e()?;
+        visitor.visit_f64(f64::from_le_bytes(buf))
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        let v = self.varint()?;
+        let c = u32::try_from(v).ok().and_then(char::from_u32)
+            .ok_or_else(|| anyhow!("invalid char {}", v))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        let s = String::from_utf8(self.bytes()?).e()?;
+        visitor.visit_string(s)
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_byte_buf(self.bytes()?)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        match self.byte()? {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            b => Err(anyhow!("invalid option {}", b).into()),
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        self.tag(name)?;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        let len = self.len()?;
+        visitor.visit_seq(Access { de: self, len })
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_seq(Access { de: self, len })
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_seq(Access { de: self, len })
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        let len = self.len()?;
+        visitor.visit_map(Access { de: self, len })
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        self.tag(name)?;
+        let len = if RECORDS.contains(&name) {
+            self.len()?
+        } else {
+            fields.len()
+        };
+        visitor.visit_seq(Access { de: self, len })
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        visitor.visit_u64(self.varint()?)
+    }
+
+    fn deserialize_ignored_any<V>(self, _visitor: V) -> Result<V::Value>
+    where V: Visitor<'de>,
+    {
+        Err(anyhow!("binary encoding is not self-describing").into())
+    }
+}
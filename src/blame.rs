@@ -0,0 +1,246 @@
+//! When the values in a document changed.
+
+use anyhow::anyhow;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::de::Deserializer;
+use crate::dcmd;
+use crate::error::Result;
+use crate::history::{self, Version};
+use crate::meta::Trailer;
+use crate::node::Node;
+use crate::path::{Path, Segment};
+use crate::state::State;
+
+/// A leaf of a document with the version that last changed it, from
+/// `blame`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blame {
+    /// Where the value is, such as `.limits.max_conn`.
+    pub path: String,
+    pub value: Value,
+    /// The version since which the value has been as it is, counting
+    /// from 1.
+    pub version: usize,
+}
+
+/// Every version that changed the value at `path`, such as
+/// `.limits.max_conn`, in the document selected in `de`, oldest first,
+/// with what it changed the value to. The value is `None` in versions
+/// that removed it, and versions from before it first existed are left
+/// out. `de` is left as it was.
+pub fn history(de: &mut Deserializer, path: &str) -> Result<Vec<(Version, Option<Value>)>> {
+    let path = Path::parse(path)?;
+    de.with_state(|state, document| {
+        let mut changes = Vec::new();
+        let mut last = None;
+        for version in history::versions(state)? {
+            state.reload(Some((version.trailer.clone(), version.trailer_pos)))?;
+            let value = match locate(state, document, &path)? {
+                Some(_) => Some(state.read_node()?.to_json()),
+                None => None,
+            };
+            state.blocks.clear();
+            if value != last {
+                changes.push((version, value.clone()));
+                last = value;
+            }
+        }
+        Ok(changes)
+    })
+}
+
+/// Every leaf of the document selected in `de`, in order, with the
+/// version that last changed it. Empty tuples, sequences, structs and maps
+/// count as leaves. `de` is left as it was.
+///
+/// The version that wrote a leaf is known from where it is in the file.
+/// Earlier versions are only read while the leaf had the same value in
+/// them, as when a stitch rewrote it along with a value around it.
+pub fn blame(de: &mut Deserializer) -> Result<Vec<Blame>> {
+    de.with_state(|state, document| {
+        let mut versions = Versions::new(state)?;
+        let current = match state.trailer_pos {
+            Some(pos) => versions.written_in(pos),
+            None => return Err(anyhow!("the file has no versions").into()),
+        };
+        state.seek_document(document)?;
+        let root = state.read_node()?;
+        let mut leaves = Vec::new();
+        collect_leaves(&mut Path::default(), &root, &mut leaves);
+
+        let mut blame = Vec::new();
+        for (path, value) in leaves {
+            versions.load(state, current)?;
+            let pos = locate(state, document, &path)?
+                .ok_or_else(|| anyhow!("no value at {}", path))?;
+            let mut version = versions.written_in(pos);
+            while version > 1 {
+                versions.load(state, version - 1)?;
+                let pos = match locate(state, document, &path)? {
+                    Some(pos) if state.read_node()? == *value => pos,
+                    _ => break,
+                };
+                version = versions.written_in(pos);
+            }
+            state.blocks.clear();
+            blame.push(Blame {
+                path: path.to_string(),
+                value: value.to_json(),
+                version,
+            });
+        }
+        Ok(blame)
+    })
+}
+
+fn collect_leaves<'a>(path: &mut Path, node: &'a Node, leaves: &mut Vec<(Path, &'a Node)>) {
+    match node {
+        Node::Tuple(elements) | Node::Seq(elements) if !elements.is_empty() => {
+            for (i, element) in elements.iter().enumerate() {
+                path.0.push(Segment::Index(i));
+                collect_leaves(path, element, leaves);
+                path.0.pop();
+            }
+        }
+        Node::Struct { fields: entries, .. } | Node::Map(entries) if !entries.is_empty() => {
+            for (key, value) in entries {
+                path.0.push(Segment::Key(key.clone()));
+                collect_leaves(path, value, leaves);
+                path.0.pop();
+            }
+        }
+        Node::Variant { variant, value: Some(value), .. } => {
+            path.0.push(Segment::Key(variant.clone()));
+            collect_leaves(path, value, leaves);
+            path.0.pop();
+        }
+        _ => leaves.push((path.clone(), node)),
+    }
+}
+
+/// The documents and stitches of a version.
+type Loaded = (BTreeMap<String, u64>, HashMap<u64, u64>);
+
+/// The committed versions, loaded into a state as they are needed.
+struct Versions {
+    trailers: Vec<(Trailer, u64)>,
+    /// Every version loaded so far.
+    loaded: HashMap<usize, Loaded>,
+}
+
+impl Versions {
+    fn new(state: &mut State) -> Result<Versions> {
+        Ok(Versions {
+            trailers: history::trailers(state)?,
+            loaded: HashMap::new(),
+        })
+    }
+
+    fn load(&mut self, state: &mut State, version: usize) -> Result<()> {
+        if !self.loaded.contains_key(&version) {
+            let trailer = self.trailers[version - 1].clone();
+            state.reload(Some(trailer))?;
+            let loaded = (state.documents.clone(), state.stitches.clone());
+            self.loaded.insert(version, loaded);
+        }
+        let (documents, stitches) = &self.loaded[&version];
+        state.documents = documents.clone();
+        state.stitches = stitches.clone();
+        Ok(())
+    }
+
+    /// The version that wrote the data at `pos`.
+    fn written_in(&self, pos: u64) -> usize {
+        self.trailers.partition_point(|(_, trailer_pos)| *trailer_pos < pos) + 1
+    }
+}
+
+/// Move to the value at `path` in `document`, following stitches and
+/// opening compressed values, and return where it was written: its
+/// position, or that of the compressed value it is inside. Returns `None`
+/// if there is no such value.
+fn locate(state: &mut State, document: &str, path: &Path) -> Result<Option<u64>> {
+    state.blocks.clear();
+    let root = match state.documents.get(document) {
+        Some(&root) => root,
+        None => return Ok(None),
+    };
+    state.seek(root)?;
+    let mut written = enter(state, root)?;
+    for segment in &path.0 {
+        if !child(state, segment)? {
+            return Ok(None);
+        }
+        written = enter(state, written)?;
+    }
+    Ok(Some(written))
+}
+
+/// Follow the stitches replacing the value at the current position and
+/// open it if it is compressed, returning where it was written. Inside a
+/// compressed value, that is `written`, where the compressed value was.
+fn enter(state: &mut State, written: u64) -> Result<u64> {
+    if !state.blocks.is_empty() {
+        return Ok(written);
+    }
+    state.enter_value()?;
+    let pos = state.pos()?;
+    state.open_block()?;
+    Ok(pos)
+}
+
+/// Move to the field, entry or element of the value at the current
+/// position named by `segment`, returning whether there is one.
+fn child(state: &mut State, segment: &Segment) -> Result<bool> {
+    match segment {
+        Segment::Key(key) => {
+            if let Some(cmd) = state.probe::<dcmd::SerializeVariant>()? {
+                return Ok(cmd.variant == *key);
+            }
+            let map = if state.probe::<dcmd::SerializeStruct>()?.is_some() {
+                false
+            } else if state.probe::<dcmd::SerializeMap>()?.is_some() {
+                true
+            } else {
+                return Ok(false);
+            };
+            loop {
+                let next = if map {
+                    state.probe::<dcmd::SerializeMapKey>()?.map(|cmd| cmd.k)
+                } else {
+                    state.probe::<dcmd::SerializeStructField>()?.map(|cmd| cmd.key)
+                };
+                match next {
+                    Some(next) if next == *key => return Ok(true),
+                    Some(_) => state.skip_value()?,
+                    None => return Ok(false),
+                }
+            }
+        }
+        Segment::Index(i) => {
+            let (len, tuple) = if let Some(cmd) = state.probe::<dcmd::SerializeTuple>()? {
+                (cmd.len, true)
+            } else if let Some(cmd) = state.probe::<dcmd::SerializeSeq>()? {
+                (cmd.items, false)
+            } else {
+                return Ok(false);
+            };
+            if *i >= len {
+                return Ok(false);
+            }
+            for j in 0..=*i {
+                if tuple {
+                    state.read::<dcmd::SerializeTupleElement>()?;
+                } else {
+                    state.read::<dcmd::SerializeSeqElement>()?;
+                }
+                if j < *i {
+                    state.skip_value()?;
+                }
+            }
+            Ok(true)
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Tamper-evident history.
+//!
+//! Each trailer carries the SHA-256 of the previous trailer as stored, and
+//! of everything written since it, which includes the stitches and payloads
+//! it commits. For the first trailer that is everything from the start of
+//! the file, header included. Changing any committed byte breaks the chain
+//! from that commit on.
+//!
+//! Anyone able to write the file can recompute the hashes, so trailers can
+//! also be signed with an HMAC-SHA256 of their encoding without the
+//! signature, keyed by a local `SigningKey`.
+
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{Read, SeekFrom};
+
+use crate::codec::Codec;
+use crate::error::{Result, StdResultExt};
+use crate::meta::{self, Bytes, Encoding, Trailer};
+use crate::state::Buffer;
+
+/// A key for signing trailers.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SigningKey(pub Vec<u8>);
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+/// The first trailer whose hashes or signature don't match the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenLink {
+    /// The version the trailer commits, counting from 1.
+    pub version: usize,
+    pub trailer_pos: u64,
+    pub reason: String,
+}
+
+/// The hashes of the previous trailer and of the data between it and the
+/// trailer at `trailer_pos`.
+pub fn link_hashes(buf: &mut dyn Buffer, encoding: Encoding, prev_trailer_pos: Option<u64>, trailer_pos: u64) -> Result<(Option<Bytes>, Bytes)> {
+    let (prev_hash, data_start) = match prev_trailer_pos {
+        Some(prev_pos) => {
+            buf.seek(SeekFrom::Start(prev_pos)).e()?;
+            encoding.read_trailer(&mut *buf)?;
+            let prev_end = buf.stream_position().e()?;
+            (Some(hash_range(buf, prev_pos, prev_end)?), prev_end)
+        }
+        None => (None, 0),
+    };
+    if data_start > trailer_pos {
+        return Err(anyhow!("trailer at {} overlaps the previous trailer", trailer_pos).into());
+    }
+    Ok((prev_hash, hash_range(buf, data_start, trailer_pos)?))
+}
+
+fn hash_range(buf: &mut dyn Buffer, start: u64, end: u64) -> Result<Bytes> {
+    buf.seek(SeekFrom::Start(start)).e()?;
+    let mut hasher = Sha256::new();
+    let mut bytes = buf.take(end - start);
+    std::io::copy(&mut bytes, &mut hasher).e()?;
+    if bytes.limit() != 0 {
+        return Err(anyhow!("file ends before {}", end).into());
+    }
+    Ok(Bytes(hasher.finalize().to_vec()))
+}
+
+/// The signature of `trailer`, ignoring any signature it already has.
+pub fn sign(trailer: &Trailer, encoding: Encoding, key: &SigningKey) -> Result<Bytes> {
+    let mac = mac(trailer, encoding, key)?;
+    Ok(Bytes(mac.finalize().into_bytes().to_vec()))
+}
+
+fn mac(trailer: &Trailer, encoding: Encoding, key: &SigningKey) -> Result<Hmac<Sha256>> {
+    let unsigned = Trailer {
+        signature: None,
+        ..trailer.clone()
+    };
+    let mut bytes = Vec::new();
+    encoding.write_trailer(&mut bytes, &unsigned)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.0)
+        .map_err(|_| anyhow!("invalid signing key"))?;
+    mac.update(&bytes);
+    Ok(mac)
+}
+
+fn check_signature(trailer: &Trailer, encoding: Encoding, key: &SigningKey) -> Result<Option<String>> {
+    let signature = match &trailer.signature {
+        Some(signature) => signature,
+        None => return Ok(Some("trailer is not signed".to_string())),
+    };
+    match mac(trailer, encoding, key)?.verify_slice(&signature.0) {
+        Ok(()) => Ok(None),
+        Err(_) => Ok(Some("trailer signature doesn't match".to_string())),
+    }
+}
+
+/// Check every trailer up to the one at `last_pos`, oldest first, in a
+/// file of the given format version.
+///
+/// Every trailer must have hashes, except in files from before the header
+/// existed, version 0, where trailers written before the hash chain did
+/// are skipped, but once a trailer has hashes every later one must too. If
+/// `key` is given, every trailer must be signed with it.
+pub fn verify_chain(buf: &mut dyn Buffer, encoding: Encoding, version: u32, key: Option<&SigningKey>, last_pos: Option<u64>) -> Result<Option<BrokenLink>> {
+    let last_pos = match last_pos {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let orig_pos = buf.stream_position().e()?;
+    buf.seek(SeekFrom::Start(last_pos)).e()?;
+    let last = encoding.read_trailer(&mut *buf)?;
+    let chain = meta::trailer_chain(&mut *buf, encoding, last, last_pos)?;
+
+    let mut hashed = version >= 1;
+    let mut prev_pos = None;
+    let mut broken = None;
+    for (i, (trailer, pos)) in chain.iter().enumerate() {
+        let reason = verify_link(buf, encoding, key, trailer, prev_pos, *pos, &mut hashed)?;
+        if let Some(reason) = reason {
+            broken = Some(BrokenLink {
+                version: i + 1,
+                trailer_pos: *pos,
+                reason,
+            });
+            break;
+        }
+        prev_pos = Some(*pos);
+    }
+    buf.seek(SeekFrom::Start(orig_pos)).e()?;
+    Ok(broken)
+}
+
+/// Check the hashes and signature of the trailer at `pos` alone, against
+/// the trailer it follows, without checking that one in turn, returning
+/// what is wrong with it if anything.
+pub fn verify_trailer(buf: &mut dyn Buffer, encoding: Encoding, version: u32, key: Option<&SigningKey>, trailer: &Trailer, pos: u64) -> Result<Option<String>> {
+    let orig_pos = buf.stream_position().e()?;
+    let mut hashed = version >= 1;
+    let reason = verify_link(buf, encoding, key, trailer, trailer.prev_trailer_pos, pos, &mut hashed)?;
+    buf.seek(SeekFrom::Start(orig_pos)).e()?;
+    Ok(reason)
+}
+
+fn verify_link(buf: &mut dyn Buffer, encoding: Encoding, key: Option<&SigningKey>, trailer: &Trailer, prev_pos: Option<u64>, pos: u64, hashed: &mut bool) -> Result<Option<String>> {
+    let data_hash = match &trailer.data_hash {
+        Some(data_hash) => data_hash,
+        None if *hashed => return Ok(Some("trailer has no hashes".to_string())),
+        None => {
+            return match key {
+                Some(key) => check_signature(trailer, encoding, key),
+                None => Ok(None),
+            };
+        }
+    };
+    *hashed = true;
+    let (prev_hash, expected_data_hash) = link_hashes(buf, encoding, prev_pos, pos)?;
+    if trailer.prev_hash != prev_hash {
+        return Ok(Some("hash of the previous trailer doesn't match".to_string()));
+    }
+    if *data_hash != expected_data_hash {
+        return Ok(Some("hash of the committed data doesn't match".to_string()));
+    }
+    match key {
+        Some(key) => check_signature(trailer, encoding, key),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,172 @@
+//! How commands, stitches and trailers are turned into bytes.
+//!
+//! Each file records its encoding in its header, and `Encoding` dispatches
+//! to the codec for it.
+
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io::{self, Read, Write};
+
+use crate::binary::BinaryCodec;
+use crate::error::{Error, Result, StdResultExt};
+use crate::meta::{Encoding, FixedSizeStitch, Stitch, Trailer};
+
+/// Encodes and decodes the commands and records of a file.
+///
+/// Decoding reads exactly the bytes of one value, so the stream is left at
+/// the start of the next.
+pub trait Codec {
+    fn write<T: Serialize + ?Sized>(&self, w: &mut dyn Write, v: &T) -> Result<()>;
+
+    fn read<T: DeserializeOwned>(&self, r: &mut dyn Read) -> Result<T>;
+
+    /// Write a stitch. Every stitch must encode to the same number of bytes,
+    /// since stitches are rewritten in place.
+    fn write_stitch(&self, w: &mut dyn Write, stitch: Stitch) -> Result<()>;
+
+    fn read_stitch(&self, r: &mut dyn Read) -> Result<Stitch>;
+
+    fn write_trailer(&self, w: &mut dyn Write, trailer: &Trailer) -> Result<()> {
+        self.write(w, trailer)
+    }
+
+    fn read_trailer(&self, r: &mut dyn Read) -> Result<Trailer> {
+        self.read(r)
+    }
+
+    /// Whether `e`, returned by one of the read methods, means the stream
+    /// ended before the value did.
+    fn is_eof(&self, e: &Error) -> bool;
+}
+
+fn is_io_eof(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::UnexpectedEof
+}
+
+/// Pretty-printed JSON, one command per line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn write<T: Serialize + ?Sized>(&self, w: &mut dyn Write, v: &T) -> Result<()> {
+        serde_json::to_writer_pretty(&mut *w, v).e()?;
+        writeln!(w).e()?;
+        Ok(())
+    }
+
+    fn read<T: DeserializeOwned>(&self, r: &mut dyn Read) -> Result<T> {
+        let mut de = serde_json::Deserializer::from_reader(r);
+        T::deserialize(&mut de).e()
+    }
+
+    fn write_stitch(&self, w: &mut dyn Write, stitch: Stitch) -> Result<()> {
+        self.write(w, &stitch.encode())
+    }
+
+    fn read_stitch(&self, r: &mut dyn Read) -> Result<Stitch> {
+        self.read::<FixedSizeStitch>(r)?.decode()
+    }
+
+    fn is_eof(&self, e: &Error) -> bool {
+        if let Some(e) = e.downcast_ref::<serde_json::Error>() {
+            e.is_eof()
+        } else if let Some(e) = e.downcast_ref::<io::Error>() {
+            is_io_eof(e)
+        } else {
+            false
+        }
+    }
+}
+
+/// MessagePack, with structs written as maps so they keep their field
+/// names.
+///
+/// Stitches are a MessagePack `bin 8` value holding three little-endian
+/// `u64`s, so they have a fixed size.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+/// The `bin 8` marker and length that start every stitch.
+const MSGPACK_STITCH_PREFIX: [u8; 2] = [0xc4, 24];
+
+impl Codec for MessagePackCodec {
+    fn write<T: Serialize + ?Sized>(&self, w: &mut dyn Write, v: &T) -> Result<()> {
+        rmp_serde::encode::write_named(w, v).e()
+    }
+
+    fn read<T: DeserializeOwned>(&self, r: &mut dyn Read) -> Result<T> {
+        rmp_serde::decode::from_read(r).e()
+    }
+
+    fn write_stitch(&self, w: &mut dyn Write, stitch: Stitch) -> Result<()> {
+        let mut buf = [0; 26];
+        buf[..2].copy_from_slice(&MSGPACK_STITCH_PREFIX);
+        LittleEndian::write_u64(&mut buf[2..10], stitch.old_pos);
+        LittleEndian::write_u64(&mut buf[10..18], stitch.new_pos);
+        LittleEndian::write_u64(&mut buf[18..26], stitch.next_stitch_pos);
+        w.write_all(&buf).e()
+    }
+
+    fn read_stitch(&self, r: &mut dyn Read) -> Result<Stitch> {
+        let mut buf = [0; 26];
+        r.read_exact(&mut buf).e()?;
+        if buf[..2] != MSGPACK_STITCH_PREFIX {
+            return Err(anyhow!("expected a stitch, found {:x?}", &buf[..2]).into());
+        }
+        Ok(Stitch {
+            old_pos: LittleEndian::read_u64(&buf[2..10]),
+            new_pos: LittleEndian::read_u64(&buf[10..18]),
+            next_stitch_pos: LittleEndian::read_u64(&buf[18..26]),
+        })
+    }
+
+    fn is_eof(&self, e: &Error) -> bool {
+        use rmp_serde::decode::Error as DecodeError;
+        match e.downcast_ref::<DecodeError>() {
+            Some(DecodeError::InvalidMarkerRead(e)) | Some(DecodeError::InvalidDataRead(e)) => is_io_eof(e),
+            _ => e.downcast_ref::<io::Error>().is_some_and(is_io_eof),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($encoding:expr, $codec:ident => $e:expr) => {
+        match $encoding {
+            Encoding::Json => { let $codec = JsonCodec; $e }
+            Encoding::Binary => { let $codec = BinaryCodec; $e }
+            Encoding::MessagePack => { let $codec = MessagePackCodec; $e }
+        }
+    };
+}
+
+impl Codec for Encoding {
+    fn write<T: Serialize + ?Sized>(&self, w: &mut dyn Write, v: &T) -> Result<()> {
+        dispatch!(self, c => c.write(w, v))
+    }
+
+    fn read<T: DeserializeOwned>(&self, r: &mut dyn Read) -> Result<T> {
+        dispatch!(self, c => c.read(r))
+    }
+
+    fn write_stitch(&self, w: &mut dyn Write, stitch: Stitch) -> Result<()> {
+        dispatch!(self, c => c.write_stitch(w, stitch))
+    }
+
+    fn read_stitch(&self, r: &mut dyn Read) -> Result<Stitch> {
+        dispatch!(self, c => c.read_stitch(r))
+    }
+
+    fn write_trailer(&self, w: &mut dyn Write, trailer: &Trailer) -> Result<()> {
+        dispatch!(self, c => c.write_trailer(w, trailer))
+    }
+
+    fn read_trailer(&self, r: &mut dyn Read) -> Result<Trailer> {
+        dispatch!(self, c => c.read_trailer(r))
+    }
+
+    fn is_eof(&self, e: &Error) -> bool {
+        dispatch!(self, c => c.is_eof(e))
+    }
+}
@@ -0,0 +1,50 @@
+//! Rewriting a file without its history.
+
+use anyhow::anyhow;
+use std::io::SeekFrom;
+
+use crate::error::{Result, StdResultExt};
+use crate::ser::Serializer;
+use crate::state::{Buffer, Options, State};
+
+/// Write the latest version of every document in `buf` to `out`, which must
+/// be empty, as a file with a single version.
+///
+/// The new file keeps the encoding, compression and encryption of the old
+/// one, so `options` must hold the key if it is encrypted. Values are
+/// written afresh, so no stitches are carried over, and with compression
+/// each document is compressed as a whole.
+///
+/// Only the branch in `options` is kept, so files with other branches or
+/// with tags, which would be lost, aren't compacted.
+pub fn compact(buf: impl Buffer, mut out: impl Buffer, options: &Options) -> Result<()> {
+    if out.seek(SeekFrom::End(0)).e()? != 0 {
+        return Err(anyhow!("compacted file must be written to an empty buffer").into());
+    }
+    let mut state = State::load(Box::new(buf), options)?;
+    let mut lost: Vec<String> = state.refs.heads.keys()
+        .filter(|name| **name != state.branch)
+        .map(|name| format!("branch {:?}", name))
+        .collect();
+    lost.extend(state.refs.tags.keys().map(|name| format!("tag {:?}", name)));
+    if !lost.is_empty() {
+        return Err(anyhow!("compacting would lose {}", lost.join(", ")).into());
+    }
+    let options = Options {
+        encoding: state.header.encoding,
+        compression: state.header.compression,
+        key: match state.header.encryption {
+            Some(_) => options.key.clone(),
+            None => None,
+        },
+        ..options.clone()
+    };
+    let mut ser = Serializer::with_options(out, options)?;
+    let names: Vec<String> = state.documents.keys().cloned().collect();
+    for name in names {
+        state.seek_document(&name)?;
+        let node = state.read_node()?;
+        ser.document(&name)?.write_base(&node)?;
+    }
+    ser.finalize()
+}
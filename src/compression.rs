@@ -0,0 +1,50 @@
+//! Compression of large values.
+//!
+//! When a file has compression, the payload of every stitch is buffered
+//! until it ends, and written as a single `Compressed` command holding its
+//! compressed commands if they take at least the threshold set in
+//! `Options` and that is smaller. Smaller payloads, like most stitches of a
+//! single field, are written as they are. Values inside a payload aren't
+//! compressed on their own, so nothing is compressed twice.
+//!
+//! New documents are written as they are, so their fields can be stitched
+//! one by one. Compaction instead compresses each document it writes as a
+//! whole, as the base of the compacted file.
+//!
+//! The commands inside a compressed value have no position in the file, so
+//! they can't be stitched. Changing any part of a compressed value replaces
+//! the whole value.
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::{Result, StdResultExt};
+
+/// The header feature enabled in files with compression.
+pub const FEATURE: &str = "compression";
+
+/// The default size in bytes below which values aren't compressed.
+pub const DEFAULT_THRESHOLD: usize = 256;
+
+/// How compressed values are compressed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::encode_all(data, 0).e(),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::decode_all(data).e(),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(data).e(),
+        }
+    }
+}
@@ -29,15 +29,59 @@ where T: Deserialize<'de>,
 
 pub use scmd::SerializeTupleEnd;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct SerializeStruct {
     pub name: String,
     pub len: usize,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct SerializeStructField {
     pub key: String,
 }
 
 pub use scmd::SerializeStructEnd;
+
+pub use scmd::SerializeUnit;
+pub use scmd::SerializeI64;
+pub use scmd::SerializeU64;
+pub use scmd::SerializeF64;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeStr {
+    pub s: String,
+}
+
+pub use scmd::SerializeSeq;
+pub use scmd::SerializeSeqElement;
+pub use scmd::SerializeSeqEnd;
+
+pub use scmd::SerializeMap;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeMapKey {
+    pub k: String,
+}
+
+pub use scmd::SerializeMapEnd;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeUnitVariant {
+    pub name: String,
+    pub unit: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeVariant {
+    pub name: String,
+    pub variant: String,
+}
+
+pub use scmd::SerializeVariantEnd;
+pub use scmd::Compressed;
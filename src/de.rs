@@ -2,14 +2,22 @@
 
 use crate::error::{Error, Result, StdResultExt};
 use crate::dcmd;
-use crate::state::{State, Buffer};
-use std::io::SeekFrom;
-
+use crate::state::{State, Buffer, Options, DEFAULT_DOCUMENT};
+use crate::meta::{self, Header, Refs};
+use crate::chain::{self, BrokenLink};
+use crate::history::{self, Version};
+use crate::json;
+use crate::lock::{self, LockMode};
+use crate::node::Node;
+use crate::path::{Path, Segment};
+use anyhow::anyhow;
+
+use std::marker::PhantomData;
 use std::ops::{AddAssign, MulAssign, Neg};
 
 use serde::Deserialize;
 use serde::de::{
-    self, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    self, DeserializeSeed, EnumAccess, IntoDeserializer,
     VariantAccess, Visitor, DeserializeOwned
 };
 
@@ -17,182 +25,383 @@ use crate::ser::Serializer;
 
 pub struct Deserializer {
     state: State,
+    /// The selected document.
+    document: String,
+    /// For each value being deserialized, innermost last, the position to
+    /// resume at if it was reached through a stitch, and whether it is read
+    /// from a compressed block.
+    resumes: Vec<(Option<u64>, bool)>,
+    /// Where the value being deserialized is in the document.
+    path: Path,
 }
 
 impl Deserializer {
     pub fn new(buf: impl Buffer) -> Result<Deserializer> {
-        Deserializer::from_state(State {
-            buf: Box::new(buf),
-        })
+        Deserializer::with_options(buf, Options::default())
+    }
+
+    /// Open `buf` for reading with the given options, such as the key of an
+    /// encrypted file.
+    pub fn with_options(buf: impl Buffer, options: Options) -> Result<Deserializer> {
+        Deserializer::from_state(State::load(Box::new(buf), &options)?)
+    }
+
+    /// Open the file at `path` for reading, and lock it until the
+    /// deserializer is dropped. Waits for the lock if someone else holds it.
+    pub fn open_file(path: impl AsRef<std::path::Path>, mode: LockMode) -> Result<Deserializer> {
+        Deserializer::open_file_with_options(path, mode, Options::default())
+    }
+
+    /// Open the file at `path` like `open_file`, with the given options.
+    pub fn open_file_with_options(path: impl AsRef<std::path::Path>, mode: LockMode, options: Options) -> Result<Deserializer> {
+        Deserializer::with_options(lock::open(path.as_ref(), false, mode, true)?, options)
+    }
+
+    /// Open the file at `path` like `open_file`, but fail if the lock is
+    /// held by someone else, with an error for which `is_would_block` is
+    /// true.
+    pub fn try_open_file(path: impl AsRef<std::path::Path>, mode: LockMode) -> Result<Deserializer> {
+        Deserializer::try_open_file_with_options(path, mode, Options::default())
+    }
+
+    /// Open the file at `path` like `try_open_file`, with the given options.
+    pub fn try_open_file_with_options(path: impl AsRef<std::path::Path>, mode: LockMode, options: Options) -> Result<Deserializer> {
+        Deserializer::with_options(lock::open(path.as_ref(), false, mode, false)?, options)
     }
 
     pub fn from_state(state: State) -> Result<Deserializer> {
-        let mut v = Deserializer { state };
-        v.reset()?;
+        let mut v = Deserializer {
+            state,
+            document: DEFAULT_DOCUMENT.to_string(),
+            resumes: Vec::new(),
+            path: Path::default(),
+        };
+        v.select_default()?;
         Ok(v)
     }
 
+    /// The header of the file being read.
+    pub fn header(&self) -> &Header {
+        &self.state.header
+    }
+
     pub fn to_state(self) -> State {
         self.state
     }
 
-    pub fn to_ser(self) -> Result<Serializer> {
+    /// Return a serializer for the file, which must have its latest
+    /// version selected.
+    pub fn to_ser(mut self) -> Result<Serializer> {
+        let latest = history::trailers(&mut self.state)?.last().map(|(_, pos)| *pos);
+        if self.state.trailer_pos != latest {
+            return Err(anyhow!("can't write to the file with an older version selected").into());
+        }
         Serializer::from_state(self.to_state())
     }
 
+    /// Start reading the default document again from its root, which fails
+    /// if it doesn't exist, as `document` does.
     pub fn reset(&mut self) -> Result<()> {
-        self.state.buf.seek(SeekFrom::Start(0)).e()?;
+        self.select_default()?;
+        self.state.seek_document(DEFAULT_DOCUMENT)
+    }
+
+    /// Select the default document, starting at its root if it exists.
+    /// Otherwise reading fails until another document is selected.
+    fn select_default(&mut self) -> Result<()> {
+        self.resumes.clear();
+        self.path.0.clear();
+        self.state.blocks.clear();
+        self.document = DEFAULT_DOCUMENT.to_string();
+        if self.state.documents.contains_key(DEFAULT_DOCUMENT) {
+            self.state.seek_document(DEFAULT_DOCUMENT)?;
+        }
         Ok(())
     }
 
-    fn read<T: DeserializeOwned>(&mut self) -> Result<T> {
-        let mut de = serde_json::Deserializer::from_reader(&mut self.state.buf);
-        let t = T::deserialize(&mut de).e()?;
-        Ok(t)
+    /// Fail unless the selected document exists.
+    fn check_document(&self) -> Result<()> {
+        if !self.state.documents.contains_key(&self.document) {
+            return Err(anyhow!("no document named {:?}", self.document).into());
+        }
+        Ok(())
     }
-}
 
-impl<'a> de::Deserializer<'static> for &'a mut Deserializer {
-    type Error = Error;
+    /// Select the named document and return the deserializer for reading
+    /// it.
+    pub fn document(&mut self, name: &str) -> Result<&mut Deserializer> {
+        self.resumes.clear();
+        self.path.0.clear();
+        self.state.blocks.clear();
+        self.state.seek_document(name)?;
+        self.document = name.to_string();
+        Ok(self)
+    }
 
-    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        panic!()
+    /// Check the hash chain of the committed trailers, and their signatures
+    /// if a signing key was given, returning the first broken link.
+    pub fn verify_chain(&mut self) -> Result<Option<BrokenLink>> {
+        let state = &mut self.state;
+        chain::verify_chain(&mut *state.buf, state.header.encoding, state.header.version,
+                            state.options.signing_key.as_ref(), state.trailer_pos)
     }
 
-    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        println!("deserialize_bool");
-        let cmd = self.read::<dcmd::SerializeBool>()?;
-        visitor.visit_bool(cmd.v)
+    /// Every committed version, oldest first, including any after the
+    /// selected version.
+    pub fn versions(&mut self) -> Result<Vec<Version>> {
+        self.with_state(|state, _| history::versions(state))
     }
 
-    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        visitor.visit_i8(panic!())
+    /// Read the given version, counting from 1, instead of the latest, and
+    /// select the default document.
+    pub fn select_version(&mut self, version: usize) -> Result<()> {
+        let trailer = history::trailer(&mut self.state, version)?;
+        self.state.reload(Some(trailer))?;
+        self.select_default()
     }
 
-    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        visitor.visit_i16(panic!())
+    /// Read the head of the named branch, and select the default document.
+    pub fn checkout(&mut self, branch: &str) -> Result<()> {
+        self.state.checkout(branch)?;
+        self.select_default()
     }
 
-    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        visitor.visit_i32(panic!())
+    /// Read the version the named tag points at, and select the default
+    /// document. Versions are counted along the branch being read, which
+    /// the tag needn't be on.
+    pub fn select_tag(&mut self, tag: &str) -> Result<()> {
+        let pos = *self.state.refs.tags.get(tag).ok_or_else(|| anyhow!("no tag named {:?}", tag))?;
+        let trailer = meta::read_trailer_at(&mut *self.state.buf, self.state.header.encoding, pos)?;
+        self.state.reload(Some((trailer, pos)))?;
+        self.select_default()
     }
 
-    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        visitor.visit_i64(panic!())
+    /// The name of the branch being read.
+    pub fn selected_branch(&self) -> &str {
+        &self.state.branch
     }
 
-    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        println!("deserialize_u8");
-        let cmd = self.read::<dcmd::SerializeU8>()?;
-        visitor.visit_u8(cmd.v)
+    /// The branches and tags in the file, with the trailers they point at.
+    /// Commits made since the file was opened aren't seen.
+    pub fn refs(&self) -> &Refs {
+        &self.state.refs
     }
 
-    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        visitor.visit_u16(panic!())
+    /// Call `f` with the state of the given version, counting from 1,
+    /// positioned at the root of the selected document, then return to
+    /// where reading left off.
+    pub(crate) fn at_version<T>(&mut self, version: usize, f: impl FnOnce(&mut State) -> Result<T>) -> Result<T> {
+        self.with_state(|state, document| {
+            let trailer = history::trailer(state, version)?;
+            state.reload(Some(trailer))?;
+            state.seek_document(document)?;
+            f(state)
+        })
     }
 
-    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        visitor.visit_u32(panic!())
+    /// Call `f` with the state and the name of the selected document, then
+    /// undo any versions `f` loaded and return to where reading left off.
+    pub(crate) fn with_state<T>(&mut self, f: impl FnOnce(&mut State, &str) -> Result<T>) -> Result<T> {
+        let state = &mut self.state;
+        let pos = state.pos()?;
+        let blocks = std::mem::take(&mut state.blocks);
+        let documents = state.documents.clone();
+        let stitches = state.stitches.clone();
+        let (trailer_pos, catalog_pos) = (state.trailer_pos, state.catalog_pos);
+
+        let result = f(state, &self.document);
+
+        state.documents = documents;
+        state.stitches = stitches;
+        state.trailer_pos = trailer_pos;
+        state.catalog_pos = catalog_pos;
+        state.blocks = blocks;
+        state.seek(pos)?;
+        result
+    }
+
+    /// The selected document, or `None` if it doesn't exist. Reading is
+    /// left where it was.
+    pub(crate) fn read_document(&mut self) -> Result<Option<Node>> {
+        self.with_state(|state, document| {
+            if !state.documents.contains_key(document) {
+                return Ok(None);
+            }
+            state.seek_document(document)?;
+            state.read_node().map(Some)
+        })
     }
 
-    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        visitor.visit_u64(panic!())
+    /// Read the next value as JSON, whatever its type.
+    pub fn read_json(&mut self) -> Result<serde_json::Value> {
+        self.check_document()?;
+        serde_json::to_value(json::Stream::new(&mut self.state)).e()
     }
 
-    fn deserialize_f32<V>(self, _visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        unimplemented!()
+    /// The names of all documents.
+    pub fn documents(&self) -> impl Iterator<Item = &str> {
+        self.state.documents.keys().map(String::as_str)
     }
 
-    fn deserialize_f64<V>(self, _visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        unimplemented!()
+    fn read<T: DeserializeOwned>(&mut self) -> Result<T> {
+        self.state.read()
     }
 
-    fn deserialize_char<V>(self, _visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        unimplemented!()
+    /// Start reading a value, following any stitches that replace it.
+    fn begin(&mut self) -> Result<()> {
+        if self.resumes.is_empty() {
+            self.check_document()?;
+        }
+        let resume = self.state.enter_value()?;
+        let block = self.state.open_block()?;
+        self.resumes.push((resume, block));
+        Ok(())
     }
 
-    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        visitor.visit_borrowed_str(panic!())
+    /// Finish the value started by the last `begin`.
+    fn end(&mut self) -> Result<()> {
+        let (resume, block) = self.resumes.pop().expect("unbalanced value");
+        if block {
+            self.state.close_block();
+        }
+        if let Some(resume) = resume {
+            self.state.seek(resume)?;
+        }
+        Ok(())
     }
+}
 
-    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        panic!()
+/// Reads the elements of a tuple or sequence, each preceded by an `E`.
+struct SeqAccess<'a, E> {
+    de: &'a mut Deserializer,
+    len: usize,
+    /// The index of the next element.
+    index: usize,
+    element: PhantomData<E>,
+}
+
+impl<'a, E> SeqAccess<'a, E> {
+    fn new(de: &'a mut Deserializer, len: usize) -> SeqAccess<'a, E> {
+        SeqAccess { de, len, index: 0, element: PhantomData }
     }
+}
 
-    fn deserialize_bytes<V>(self, _visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
+impl<'a, E: DeserializeOwned> de::SeqAccess<'static> for SeqAccess<'a, E> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where T: de::DeserializeSeed<'static>,
     {
-        unimplemented!()
+        if self.len > 0 {
+            self.de.read::<E>()?;
+            self.len -= 1;
+            self.de.path.0.push(Segment::Index(self.index));
+            self.index += 1;
+            let value = de::DeserializeSeed::deserialize(seed, &mut *self.de)
+                .map_err(|e| e.at(&self.de.path))?;
+            self.de.path.0.pop();
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
     }
 
-    fn deserialize_byte_buf<V>(self, _visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+/// Reads the fields of a struct, or the entries of a map if `map`, up to
+/// and including the end marker.
+struct MapAccess<'a> {
+    de: &'a mut Deserializer,
+    map: bool,
+    /// The key of the value read next.
+    key: Option<String>,
+}
+
+impl<'a> de::MapAccess<'static> for MapAccess<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where K: de::DeserializeSeed<'static>,
     {
-        unimplemented!()
+        let key = if self.map {
+            self.de.state.probe::<dcmd::SerializeMapKey>()?.map(|cmd| cmd.k)
+        } else {
+            self.de.state.probe::<dcmd::SerializeStructField>()?.map(|cmd| cmd.key)
+        };
+        match key {
+            Some(key) => {
+                self.key = Some(key.clone());
+                seed.deserialize(KeyDeserializer(key)).map(Some)
+            }
+            None if self.map => {
+                self.de.read::<dcmd::SerializeMapEnd>()?;
+                Ok(None)
+            }
+            None => {
+                self.de.read::<dcmd::SerializeStructEnd>()?;
+                Ok(None)
+            }
+        }
     }
 
-    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where V: de::DeserializeSeed<'static>,
     {
-        panic!()
+        let key = self.key.take().ok_or_else(|| anyhow!("map value without a key"))?;
+        self.de.path.0.push(Segment::Key(key));
+        let value = seed.deserialize(&mut *self.de).map_err(|e| e.at(&self.de.path))?;
+        self.de.path.0.pop();
+        Ok(value)
     }
+}
+
+/// Reads a map key, which is stored as a string. Keys that were integers,
+/// bools or chars are parsed back from it, as in serde_json.
+struct KeyDeserializer(String);
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where
+                V: Visitor<'static>,
+            {
+                match self.0.parse() {
+                    Ok(v) => visitor.$visit(v),
+                    Err(_) => Err(anyhow!("can't parse map key {:?}", self.0).into()),
+                }
+            }
+        )*
+    };
+}
+
+impl de::Deserializer<'static> for KeyDeserializer {
+    type Error = Error;
 
-    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'static>,
     {
-        panic!()
+        visitor.visit_string(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_char => visit_char,
     }
 
-    fn deserialize_unit_struct<V>(
+    fn deserialize_newtype_struct<V>(
         self,
         _name: &'static str,
         visitor: V,
@@ -200,107 +409,188 @@ impl<'a> de::Deserializer<'static> for &'a mut Deserializer {
     where
         V: Visitor<'static>,
     {
-        panic!()
+        visitor.visit_newtype_struct(self)
     }
 
-    fn deserialize_newtype_struct<V>(
+    fn deserialize_enum<V>(
         self,
         _name: &'static str,
+        _variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'static>,
     {
-        visitor.visit_newtype_struct(self)
+        let variant: de::value::StringDeserializer<Error> = self.0.into_deserializer();
+        visitor.visit_enum(variant)
     }
 
-    fn deserialize_seq<V>(mut self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
+    serde::forward_to_deserialize_any! {
+        <W: Visitor<'static>>
+        i128 u128 f32 f64 str string bytes byte_buf option unit unit_struct
+        seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Reads an enum variant that has a value, up to and including the end
+/// marker.
+struct Variant<'a> {
+    de: &'a mut Deserializer,
+    variant: String,
+}
+
+impl<'a> EnumAccess<'static> for Variant<'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self)>
+    where V: de::DeserializeSeed<'static>,
     {
-        panic!()
+        let variant: de::value::StrDeserializer<Error> = self.variant.as_str().into_deserializer();
+        Ok((seed.deserialize(variant)?, self))
     }
+}
 
-    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
+impl<'a> Variant<'a> {
+    /// Read the variant's value with `f`, then the end marker.
+    fn value<T>(self, f: impl FnOnce(&mut Deserializer) -> Result<T>) -> Result<T> {
+        let Variant { de, variant } = self;
+        de.path.0.push(Segment::Key(variant));
+        let value = f(&mut *de).map_err(|e| e.at(&de.path))?;
+        de.path.0.pop();
+        de.read::<dcmd::SerializeVariantEnd>()?;
+        Ok(value)
+    }
+}
+
+impl<'a> VariantAccess<'static> for Variant<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        self.value(|de| <()>::deserialize(de))
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where T: de::DeserializeSeed<'static>,
     {
-        println!("deserialize_tuple");
-        println!("len: {}", len);
-        let cmd = self.read::<dcmd::SerializeTuple>()?;
-        assert_eq!(len, cmd.len);
-
-        struct Access<'a> {
-            de: &'a mut Deserializer,
-            len: usize,
-        }
+        self.value(|de| seed.deserialize(de))
+    }
 
-        impl<'a> de::SeqAccess<'static> for Access<'a> {
-            type Error = Error;
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where V: Visitor<'static>,
+    {
+        self.value(|de| de::Deserializer::deserialize_tuple(de, len, visitor))
+    }
 
-            fn next_element_seed<T>(&mut self, seed: T)
-                                    -> Result<Option<T::Value>>
-            where T: de::DeserializeSeed<'static>,
-            {
-                println!("next_element_seed(tuple)");
-                if self.len > 0 {
-                    let cmd = self.de.read::<dcmd::SerializeTupleElement>()?;
-                    self.len -= 1;
-                    let value =
-                        de::DeserializeSeed::deserialize(seed, &mut *self.de)?;
-                    Ok(Some(value))
-                } else {
-                    let cmd = self.de.read::<dcmd::SerializeTupleEnd>()?;
-                    Ok(None)
-                }
-            }
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where V: Visitor<'static>,
+    {
+        self.value(|de| de::Deserializer::deserialize_struct(de, "", fields, visitor))
+    }
+}
 
-            fn size_hint(&self) -> Option<usize> {
-                Some(self.len)
-            }
+impl Deserializer {
+    /// Visit the value at the current position, whatever it is.
+    fn visit_value<V>(&mut self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'static>,
+    {
+        let state = &mut self.state;
+        if state.probe::<dcmd::SerializeUnit>()?.is_some() {
+            return visitor.visit_unit();
         }
-
-        visitor.visit_seq(Access {
-            de: self,
-            len,
-        })
+        if let Some(cmd) = state.probe::<dcmd::SerializeBool>()? {
+            return visitor.visit_bool(cmd.v);
+        }
+        if let Some(cmd) = state.probe::<dcmd::SerializeU8>()? {
+            return visitor.visit_u8(cmd.v);
+        }
+        if let Some(cmd) = state.probe::<dcmd::SerializeI64>()? {
+            return visitor.visit_i64(cmd.i);
+        }
+        if let Some(cmd) = state.probe::<dcmd::SerializeU64>()? {
+            return visitor.visit_u64(cmd.u);
+        }
+        if let Some(cmd) = state.probe::<dcmd::SerializeF64>()? {
+            return visitor.visit_f64(cmd.f);
+        }
+        if let Some(cmd) = state.probe::<dcmd::SerializeStr>()? {
+            return visitor.visit_string(cmd.s);
+        }
+        // Tuple visitors stop after the last element without asking for
+        // another, so end markers are read here
+        if let Some(cmd) = state.probe::<dcmd::SerializeTuple>()? {
+            let access = SeqAccess::<dcmd::SerializeTupleElement>::new(self, cmd.len);
+            let value = visitor.visit_seq(access)?;
+            self.read::<dcmd::SerializeTupleEnd>()?;
+            return Ok(value);
+        }
+        if let Some(cmd) = state.probe::<dcmd::SerializeSeq>()? {
+            let access = SeqAccess::<dcmd::SerializeSeqElement>::new(self, cmd.items);
+            let value = visitor.visit_seq(access)?;
+            self.read::<dcmd::SerializeSeqEnd>()?;
+            return Ok(value);
+        }
+        if state.probe::<dcmd::SerializeStruct>()?.is_some() {
+            return visitor.visit_map(MapAccess { de: self, map: false, key: None });
+        }
+        if state.probe::<dcmd::SerializeMap>()?.is_some() {
+            return visitor.visit_map(MapAccess { de: self, map: true, key: None });
+        }
+        if let Some(cmd) = state.probe::<dcmd::SerializeUnitVariant>()? {
+            let variant: de::value::StringDeserializer<Error> = cmd.unit.into_deserializer();
+            return visitor.visit_enum(variant);
+        }
+        if let Some(cmd) = state.probe::<dcmd::SerializeVariant>()? {
+            return visitor.visit_enum(Variant { de: self, variant: cmd.variant });
+        }
+        let pos = state.pos()?;
+        Err(anyhow!("unrecognized command at {}", pos).into())
     }
+}
 
-    fn deserialize_tuple_struct<V>(
-        self,
-        _name: &'static str,
-        _len: usize,
-        visitor: V,
-    ) -> Result<V::Value>
+/// Values are read as whatever they were written as, and the visitor
+/// converts them if it can, so a number can be read as any integer type
+/// it fits in.
+impl de::Deserializer<'static> for &mut Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'static>,
     {
-        panic!()
+        self.begin()?;
+        let value = self.visit_value(visitor)?;
+        self.end()?;
+        Ok(value)
     }
 
-    fn deserialize_map<V>(mut self, visitor: V) -> Result<V::Value>
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'static>,
     {
-        panic!()
+        self.begin()?;
+        let value = if self.state.probe::<dcmd::SerializeUnit>()?.is_some() {
+            visitor.visit_none::<Error>()?
+        } else {
+            visitor.visit_some(&mut *self)?
+        };
+        self.end()?;
+        Ok(value)
     }
 
-    fn deserialize_struct<V>(
+    fn deserialize_newtype_struct<V>(
         self,
-        name: &'static str,
-        fields: &'static [&'static str],
+        _name: &'static str,
         visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'static>,
     {
-        println!("deserialize_struct");
-        println!("name: {}", name);
-        println!("fields: {:#?}", fields);
-        let cmd = self.read::<dcmd::SerializeStruct>()?;
-        panic!()
+        visitor.visit_newtype_struct(self)
     }
 
+    /// Unit variants can also be read from strings, as in serde_json.
     fn deserialize_enum<V>(
         self,
         _name: &'static str,
@@ -310,21 +600,31 @@ impl<'a> de::Deserializer<'static> for &'a mut Deserializer {
     where
         V: Visitor<'static>,
     {
-        panic!()
+        self.begin()?;
+        let value = if let Some(cmd) = self.state.probe::<dcmd::SerializeStr>()? {
+            let variant: de::value::StringDeserializer<Error> = cmd.s.into_deserializer();
+            visitor.visit_enum(variant)?
+        } else {
+            self.visit_value(visitor)?
+        };
+        self.end()?;
+        Ok(value)
     }
 
-    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'static>,
     {
-        panic!()
+        self.begin()?;
+        self.state.skip_value()?;
+        self.end()?;
+        visitor.visit_unit()
     }
 
-    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'static>,
-    {
-        panic!()
+    serde::forward_to_deserialize_any! {
+        <W: Visitor<'static>>
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier
     }
 }
-
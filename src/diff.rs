@@ -0,0 +1,347 @@
+//! Structured differences between versions.
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Cursor;
+
+use crate::de::Deserializer;
+use crate::dcmd;
+use crate::error::Result;
+use crate::history::{self, Version};
+use crate::meta::Encoding;
+use crate::node::Node;
+use crate::path::Segment;
+use crate::ser::Serializer;
+use crate::state::{Options, State};
+
+/// A difference between two versions of a document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Where the change is, such as `.users[3].name`. Fields and keys that
+    /// aren't identifiers are written like `["a key"]`. The whole document
+    /// is the empty path.
+    pub path: String,
+    pub op: Operation,
+    /// The value before, unless added.
+    pub old: Option<Value>,
+    /// The value after, unless removed.
+    pub new: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Add,
+    Remove,
+    Replace,
+}
+
+impl Change {
+    fn add(path: &str, new: &Node) -> Change {
+        Change {
+            path: path.to_string(),
+            op: Operation::Add,
+            old: None,
+            new: Some(new.to_json()),
+        }
+    }
+
+    fn remove(path: &str, old: &Node) -> Change {
+        Change {
+            path: path.to_string(),
+            op: Operation::Remove,
+            old: Some(old.to_json()),
+            new: None,
+        }
+    }
+
+    fn replace(path: &str, old: &Node, new: &Node) -> Change {
+        Change {
+            path: path.to_string(),
+            op: Operation::Replace,
+            old: Some(old.to_json()),
+            new: Some(new.to_json()),
+        }
+    }
+}
+
+/// The changes to the document selected in `de` from one version to
+/// another, counting from 1. Version 0 is the empty file, where no document
+/// exists yet.
+///
+/// Only values reached through stitches that differ between the versions
+/// are read and compared. Everything else is known to be unchanged, so
+/// the cost follows the size of the changes more than of the document.
+///
+/// Entries of maps and structs are added or removed by key, and elements
+/// at the end of sequences by index, with removals from the last. Other
+/// differences replace the whole value. `de` is left as it was.
+pub fn diff(de: &mut Deserializer, from_version: usize, to_version: usize) -> Result<Vec<Change>> {
+    de.with_state(|state, document| {
+        let mut from = load(state, document, from_version)?;
+        let mut to = load(state, document, to_version)?;
+        diff_state(state, &mut from, &mut to)?
+            .ok_or_else(|| anyhow!("no document named {:?}", document).into())
+    })
+}
+
+/// A document as a version has it: where its root is, if it exists, and
+/// the stitches in effect.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LoadedDocument {
+    root: Option<u64>,
+    stitches: HashMap<u64, u64>,
+}
+
+impl LoadedDocument {
+    pub(crate) fn root(&self) -> Option<u64> {
+        self.root
+    }
+
+    /// The document as `state` has it loaded.
+    pub(crate) fn current(state: &State, document: &str) -> LoadedDocument {
+        LoadedDocument {
+            root: state.documents.get(document).copied(),
+            stitches: state.stitches.clone(),
+        }
+    }
+
+    /// The document in `version`, which is the version after the one this
+    /// was loaded from, so only the stitches it commits are added.
+    pub(crate) fn next(&self, document: &str, version: &Version) -> LoadedDocument {
+        let mut stitches = self.stitches.clone();
+        stitches.extend(version.stitches.iter().map(|(_, stitch)| (stitch.old_pos, stitch.new_pos)));
+        LoadedDocument {
+            root: version.documents.get(document).copied(),
+            stitches,
+        }
+    }
+}
+
+/// Whether the document differs between two loaded versions, as `diff`
+/// finds. A document in neither version is unchanged.
+pub(crate) fn changed(state: &mut State, from: &mut LoadedDocument, to: &mut LoadedDocument) -> Result<bool> {
+    Ok(diff_state(state, from, to)?.is_some_and(|changes| !changes.is_empty()))
+}
+
+/// The changes as `diff` lists them, or `None` if the document is in
+/// neither version.
+fn diff_state(state: &mut State, from: &mut LoadedDocument, to: &mut LoadedDocument) -> Result<Option<Vec<Change>>> {
+    let (from_root, to_root) = (from.root, to.root);
+    let keys = from.stitches.keys().chain(to.stitches.keys()).copied().collect();
+    let mut differ = Differ { state, from: &mut from.stitches, to: &mut to.stitches, keys, changes: Vec::new() };
+    match (from_root, to_root) {
+        (None, None) => return Ok(None),
+        (Some(root), None) => {
+            let old = differ.read(false, root)?;
+            differ.changes.push(Change::remove("", &old));
+        }
+        (None, Some(root)) => {
+            let new = differ.read(true, root)?;
+            differ.changes.push(Change::add("", &new));
+        }
+        (Some(from_root), Some(to_root)) if from_root == to_root => {
+            differ.value("", from_root)?;
+        }
+        (Some(from_root), Some(to_root)) => {
+            let old = differ.read(false, from_root)?;
+            let new = differ.read(true, to_root)?;
+            diff_nodes("", &old, &new, &mut differ.changes);
+        }
+    }
+    Ok(Some(differ.changes))
+}
+
+/// The changes from one value to another, without writing either to a
+/// file.
+///
+/// `old` is serialized to memory and `new` over it, so the serializer
+/// compares them as it would in a file, and the changes are listed as
+/// `diff` lists those between the two versions. That is finer than what
+/// the serializer stitches: it replaces a whole tuple or sequence whose
+/// length changed, and a whole map whose keys changed, where the changes
+/// list each element or entry. `Serializer::dry_run` reports the stitches
+/// instead.
+pub fn diff_values<A, B>(old: &A, new: &B) -> Result<Vec<Change>>
+where
+    A: ?Sized + Serialize,
+    B: ?Sized + Serialize,
+{
+    // Binary, so floats are read back exactly
+    let options = Options { encoding: Encoding::Binary, ..Options::default() };
+    let mut ser = Serializer::with_options(Cursor::new(Vec::new()), options)?;
+    old.serialize(&mut ser)?;
+    ser.finalize()?;
+    ser.reset()?;
+    new.serialize(&mut ser)?;
+    ser.finalize()?;
+    let mut de = ser.to_de()?;
+    if de.versions()?.len() == 1 {
+        // Nothing changed, so nothing was committed
+        return Ok(Vec::new());
+    }
+    diff(&mut de, 1, 2)
+}
+
+/// The document as the given version has it.
+fn load(state: &mut State, document: &str, version: usize) -> Result<LoadedDocument> {
+    if version == 0 {
+        return Ok(LoadedDocument::default());
+    }
+    let trailer = history::trailer(state, version)?;
+    state.reload(Some(trailer))?;
+    Ok(LoadedDocument {
+        root: state.documents.get(document).copied(),
+        stitches: std::mem::take(&mut state.stitches),
+    })
+}
+
+struct Differ<'a> {
+    state: &'a mut State,
+    from: &'a mut HashMap<u64, u64>,
+    to: &'a mut HashMap<u64, u64>,
+    /// The position of every value replaced in either version.
+    keys: BTreeSet<u64>,
+    changes: Vec<Change>,
+}
+
+impl Differ<'_> {
+    /// Diff the value at `pos`, which is the same in both versions unless
+    /// stitches replace it or values inside it.
+    fn value(&mut self, path: &str, pos: u64) -> Result<()> {
+        let old_pos = follow(self.from, pos)?;
+        let new_pos = follow(self.to, pos)?;
+        if old_pos != new_pos {
+            let old = self.read(false, old_pos)?;
+            let new = self.read(true, new_pos)?;
+            diff_nodes(path, &old, &new, &mut self.changes);
+            return Ok(());
+        }
+
+        // Nothing inside a value without replaced positions can differ
+        let state = &mut *self.state;
+        state.seek(old_pos)?;
+        state.skip_value()?;
+        let end = state.pos()?;
+        if self.keys.range(old_pos..end).next().is_none() {
+            return Ok(());
+        }
+
+        self.state.seek(old_pos)?;
+        if let Some(cmd) = self.state.probe::<dcmd::SerializeTuple>()? {
+            for i in 0..cmd.len {
+                self.state.read::<dcmd::SerializeTupleElement>()?;
+                self.child(&index_path(path, i))?;
+            }
+        } else if let Some(cmd) = self.state.probe::<dcmd::SerializeSeq>()? {
+            for i in 0..cmd.items {
+                self.state.read::<dcmd::SerializeSeqElement>()?;
+                self.child(&index_path(path, i))?;
+            }
+        } else if self.state.probe::<dcmd::SerializeStruct>()?.is_some() {
+            while let Some(field) = self.state.probe::<dcmd::SerializeStructField>()? {
+                self.child(&key_path(path, &field.key))?;
+            }
+        } else if self.state.probe::<dcmd::SerializeMap>()?.is_some() {
+            while let Some(key) = self.state.probe::<dcmd::SerializeMapKey>()? {
+                self.child(&key_path(path, &key.k))?;
+            }
+        } else if let Some(cmd) = self.state.probe::<dcmd::SerializeVariant>()? {
+            self.child(&key_path(path, &cmd.variant))?;
+        }
+        // Other values are single commands, or compressed ones, which
+        // can't have stitches inside
+        Ok(())
+    }
+
+    /// Diff the value at the current position, then skip past it.
+    fn child(&mut self, path: &str) -> Result<()> {
+        let pos = self.state.pos()?;
+        self.value(path, pos)?;
+        self.state.seek(pos)?;
+        self.state.skip_value()
+    }
+
+    /// Read the value at `pos` as the old or new version sees it.
+    fn read(&mut self, new: bool, pos: u64) -> Result<Node> {
+        let stitches = if new { &mut *self.to } else { &mut *self.from };
+        std::mem::swap(&mut self.state.stitches, stitches);
+        let node = self.state.seek(pos).and_then(|()| self.state.read_node());
+        let stitches = if new { &mut *self.to } else { &mut *self.from };
+        std::mem::swap(&mut self.state.stitches, stitches);
+        node
+    }
+}
+
+/// Where the value at `pos` is after following stitches.
+fn follow(stitches: &HashMap<u64, u64>, mut pos: u64) -> Result<u64> {
+    while let Some(&new_pos) = stitches.get(&pos) {
+        if new_pos <= pos {
+            return Err(anyhow!("stitch at {} points backwards to {}", pos, new_pos).into());
+        }
+        pos = new_pos;
+    }
+    Ok(pos)
+}
+
+/// Add the changes from `old` to `new` to `changes`.
+pub(crate) fn diff_nodes(path: &str, old: &Node, new: &Node, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (Node::Map(old_entries), Node::Map(new_entries)) => {
+            diff_entries(path, old_entries, new_entries, changes);
+        }
+        (Node::Struct { name: old_name, fields: old_fields },
+         Node::Struct { name: new_name, fields: new_fields }) if old_name == new_name => {
+            diff_entries(path, old_fields, new_fields, changes);
+        }
+        (Node::Variant { name: old_name, variant: old_variant, value: Some(old_value) },
+         Node::Variant { name: new_name, variant: new_variant, value: Some(new_value) })
+            if old_name == new_name && old_variant == new_variant => {
+            diff_nodes(&key_path(path, old_variant), old_value, new_value, changes);
+        }
+        (Node::Seq(old_elements), Node::Seq(new_elements))
+        | (Node::Tuple(old_elements), Node::Tuple(new_elements)) => {
+            let common = old_elements.len().min(new_elements.len());
+            for i in 0..common {
+                diff_nodes(&index_path(path, i), &old_elements[i], &new_elements[i], changes);
+            }
+            for i in (common..old_elements.len()).rev() {
+                changes.push(Change::remove(&index_path(path, i), &old_elements[i]));
+            }
+            for (i, element) in new_elements.iter().enumerate().skip(common) {
+                changes.push(Change::add(&index_path(path, i), element));
+            }
+        }
+        // As the serializer compares them, so 0.0 and -0.0 differ
+        (Node::F64(old_value), Node::F64(new_value)) if old_value.to_bits() != new_value.to_bits() => {
+            changes.push(Change::replace(path, old, new));
+        }
+        _ if old == new => { }
+        _ => changes.push(Change::replace(path, old, new)),
+    }
+}
+
+fn diff_entries(path: &str, old: &[(String, Node)], new: &[(String, Node)], changes: &mut Vec<Change>) {
+    let new_by_key: HashMap<&str, &Node> = new.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    let old_by_key: HashMap<&str, &Node> = old.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    for (key, old_value) in old {
+        match new_by_key.get(key.as_str()) {
+            Some(new_value) => diff_nodes(&key_path(path, key), old_value, new_value, changes),
+            None => changes.push(Change::remove(&key_path(path, key), old_value)),
+        }
+    }
+    for (key, new_value) in new {
+        if !old_by_key.contains_key(key.as_str()) {
+            changes.push(Change::add(&key_path(path, key), new_value));
+        }
+    }
+}
+
+fn index_path(path: &str, i: usize) -> String {
+    format!("{}{}", path, Segment::Index(i))
+}
+
+fn key_path(path: &str, key: &str) -> String {
+    format!("{}{}", path, Segment::Key(key.to_string()))
+}
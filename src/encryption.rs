@@ -0,0 +1,419 @@
+//! Encryption at rest.
+//!
+//! Everything after the header of an encrypted file is split into chunks of
+//! `CHUNK_SIZE` bytes, each encrypted and authenticated on its own with
+//! XChaCha20-Poly1305 under a fresh random nonce. A chunk is stored as its
+//! nonce followed by its ciphertext and tag. The associated data is a
+//! digest of the header, which holds a random ID of the file, followed by
+//! the chunk's index and whether it is the last chunk. So chunks can't be
+//! reordered, copied from another file with the same key, or cut off the
+//! end of the file, and the header, though readable, can't be changed. Only
+//! the last chunk may be short.
+//!
+//! `EncryptedBuffer` presents the decrypted file, so positions are the same
+//! as in an unencrypted file. Reading or writing touches only the chunks
+//! involved, and appending re-encrypts just the last chunk, and the one
+//! before it when a new chunk is started.
+//!
+//! Re-encrypting a chunk overwrites it in place, so if that write is torn,
+//! as by a crash, what was committed in the chunk before is lost along with
+//! what was being appended. Truncating the file to its header, leaving no
+//! chunks at all, isn't detected either, and leaves a file with no
+//! versions. A file cut off between chunks fails authentication, as its
+//! new last chunk wasn't stored as the last, but `recover` reads it anyway.
+//!
+//! Seeking to the end picks up anything appended by someone else. As
+//! writers share the last chunk, changes not yet stored when that happens
+//! can't be, and fail with a `Conflict`.
+//!
+//! The header stays readable, and holds the file's ID and a key check: the
+//! tag of an empty message, which tells a wrong key apart from a damaged
+//! file.
+
+use anyhow::anyhow;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+
+use crate::error::{Conflict, Result, StdResultExt};
+use crate::meta::Header;
+use crate::state::Buffer;
+
+/// The header feature enabled in encrypted files.
+pub const FEATURE: &str = "encryption";
+
+const CIPHER: &str = "xchacha20poly1305";
+
+/// The plaintext bytes in each chunk.
+const CHUNK_SIZE: u64 = 4096;
+const NONCE_SIZE: u64 = 24;
+const TAG_SIZE: u64 = 16;
+const STORED_CHUNK_SIZE: u64 = NONCE_SIZE + CHUNK_SIZE + TAG_SIZE;
+
+const KEY_CHECK_AAD: &[u8] = b"serdif key check";
+
+const FILE_ID_SIZE: usize = 16;
+
+/// A 256-bit key for encrypting files.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey(pub [u8; 32]);
+
+impl EncryptionKey {
+    /// A new random key.
+    pub fn generate() -> EncryptionKey {
+        EncryptionKey(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// How a file is encrypted, as recorded in its header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    pub cipher: String,
+    /// Hex random bytes identifying the file, bound to every chunk.
+    pub file_id: String,
+    /// Hex nonce and tag of an empty message encrypted with the file's key.
+    pub key_check: String,
+}
+
+impl Encryption {
+    pub fn new(key: &EncryptionKey) -> Encryption {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload { msg: &[], aad: KEY_CHECK_AAD };
+        let tag = key.cipher().encrypt(&nonce, payload)
+            .expect("encrypting an empty message");
+        let mut key_check = nonce.to_vec();
+        key_check.extend_from_slice(&tag);
+        let mut file_id = [0; FILE_ID_SIZE];
+        OsRng.fill_bytes(&mut file_id);
+        Encryption {
+            cipher: CIPHER.to_string(),
+            file_id: hex::encode(file_id),
+            key_check: hex::encode(key_check),
+        }
+    }
+
+    fn file_id(&self) -> Result<Vec<u8>> {
+        let file_id = hex::decode(&self.file_id).e()?;
+        if file_id.len() != FILE_ID_SIZE {
+            return Err(anyhow!("malformed file ID").into());
+        }
+        Ok(file_id)
+    }
+
+    /// Check that the file was encrypted with `key`.
+    pub fn check(&self, key: &EncryptionKey) -> Result<()> {
+        if self.cipher != CIPHER {
+            return Err(anyhow!("unsupported cipher {:?}", self.cipher).into());
+        }
+        self.file_id()?;
+        let key_check = hex::decode(&self.key_check).e()?;
+        if key_check.len() != (NONCE_SIZE + TAG_SIZE) as usize {
+            return Err(anyhow!("malformed key check").into());
+        }
+        let (nonce, tag) = key_check.split_at(NONCE_SIZE as usize);
+        let payload = Payload { msg: tag, aad: KEY_CHECK_AAD };
+        key.cipher().decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow!("wrong encryption key"))?;
+        Ok(())
+    }
+}
+
+/// The decrypted chunk last read or written.
+struct Chunk {
+    index: u64,
+    data: Vec<u8>,
+    /// Whether `data` has changes not yet written.
+    dirty: bool,
+}
+
+/// The decrypted view of an encrypted file.
+///
+/// Bytes before `data_start`, the header, are passed through unencrypted.
+/// Writes to a chunk are held until another chunk is used or the buffer is
+/// flushed or dropped.
+pub struct EncryptedBuffer {
+    inner: Box<dyn Buffer>,
+    cipher: XChaCha20Poly1305,
+    /// The digest of the header, bound to every chunk.
+    header_digest: Vec<u8>,
+    data_start: u64,
+    /// The decrypted position.
+    pos: u64,
+    /// The decrypted length.
+    len: u64,
+    chunk: Option<Chunk>,
+    /// The index of the chunk stored as the last one, if any.
+    last: Option<u64>,
+    /// The stored length, as last read or written through this buffer.
+    /// Anything past it was written by someone else.
+    stored_len: u64,
+    /// Whether the last chunk may have been stored as not the last, as when
+    /// the file was cut off between chunks.
+    cut: bool,
+}
+
+impl EncryptedBuffer {
+    pub fn new(mut inner: Box<dyn Buffer>, key: &EncryptionKey, header: &Header, data_start: u64) -> Result<EncryptedBuffer> {
+        let encryption = header.encryption.as_ref()
+            .ok_or_else(|| anyhow!("serdif file isn't encrypted"))?;
+        encryption.file_id()?;
+        let header_digest = Sha256::digest(serde_json::to_vec(header).e()?).to_vec();
+        let stored_len = inner.seek(SeekFrom::End(0)).e()?;
+        let mut buf = EncryptedBuffer {
+            inner,
+            cipher: key.cipher(),
+            header_digest,
+            data_start,
+            pos: 0,
+            len: plain_len(stored_len, data_start)?,
+            chunk: None,
+            last: None,
+            stored_len,
+            cut: false,
+        };
+        buf.last = buf.last_index();
+        Ok(buf)
+    }
+
+    /// Pick up anything someone else appended since the file was last read
+    /// or written through this buffer, dropping the cached chunk.
+    ///
+    /// If the cached chunk has changes, they are dropped too, as storing
+    /// them would overwrite what was appended, and this fails with a
+    /// `Conflict`.
+    fn refresh(&mut self) -> io::Result<()> {
+        let stored_len = self.inner.seek(SeekFrom::End(0))?;
+        if stored_len == self.stored_len {
+            return Ok(());
+        }
+        let len = plain_len(stored_len, self.data_start).map_err(io::Error::other)?;
+        let dirty = self.chunk.take().is_some_and(|chunk| chunk.dirty);
+        self.len = len;
+        self.stored_len = stored_len;
+        self.last = self.last_index();
+        if dirty {
+            return Err(io::Error::other(Conflict::default()));
+        }
+        Ok(())
+    }
+
+    /// Accept a last chunk stored as not the last, so a file cut off
+    /// between chunks can be recovered.
+    pub fn allow_cut(&mut self) {
+        self.cut = true;
+    }
+
+    /// The encrypted buffer, with changes flushed.
+    pub fn into_inner(mut self) -> io::Result<Box<dyn Buffer>> {
+        self.flush_chunk()?;
+        self.chunk = None;
+        Ok(std::mem::replace(&mut self.inner, Box::new(io::Cursor::new(Vec::new()))))
+    }
+
+    /// The index of the last chunk, if there are any.
+    fn last_index(&self) -> Option<u64> {
+        (self.len > self.data_start).then(|| (self.len - self.data_start - 1) / CHUNK_SIZE)
+    }
+
+    /// The associated data chunk `index` is encrypted with.
+    fn aad(&self, index: u64, last: bool) -> Vec<u8> {
+        let mut aad = self.header_digest.clone();
+        aad.extend_from_slice(&index.to_le_bytes());
+        aad.push(last as u8);
+        aad
+    }
+
+    /// The number of bytes in chunk `index`, as last flushed.
+    fn chunk_len(&self, index: u64) -> u64 {
+        let start = self.data_start + index * CHUNK_SIZE;
+        self.len.saturating_sub(start).min(CHUNK_SIZE)
+    }
+
+    /// Make chunk `index` the current chunk, flushing the old one.
+    fn load_chunk(&mut self, index: u64) -> io::Result<&mut Chunk> {
+        if self.chunk.as_ref().map(|c| c.index) != Some(index) {
+            self.flush_chunk()?;
+            let data = self.read_chunk(index)?;
+            self.chunk = Some(Chunk { index, data, dirty: false });
+        }
+        Ok(self.chunk.as_mut().expect("chunk"))
+    }
+
+    fn read_chunk(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let len = self.chunk_len(index);
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let mut stored = vec![0; (NONCE_SIZE + len + TAG_SIZE) as usize];
+        self.inner.seek(SeekFrom::Start(self.data_start + index * STORED_CHUNK_SIZE))?;
+        self.inner.read_exact(&mut stored)?;
+        let (nonce, ciphertext) = stored.split_at(NONCE_SIZE as usize);
+        let last = self.last == Some(index);
+        let decrypt = |last| {
+            let aad = self.aad(index, last);
+            self.cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+        };
+        let mut data = decrypt(last);
+        if data.is_err() && last && self.cut {
+            data = decrypt(false);
+        }
+        data.map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("encrypted chunk {} failed authentication", index))
+        })
+    }
+
+    fn write_chunk(&mut self, index: u64, data: &[u8], last: bool) -> io::Result<()> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = self.aad(index, last);
+        let ciphertext = self.cipher.encrypt(&nonce, Payload { msg: data, aad: &aad }).map_err(|_| {
+            io::Error::other(format!("failed to encrypt chunk {}", index))
+        })?;
+        self.inner.seek(SeekFrom::Start(self.data_start + index * STORED_CHUNK_SIZE))?;
+        self.inner.write_all(&nonce)?;
+        self.inner.write_all(&ciphertext)?;
+        self.stored_len = self.stored_len.max(self.inner.stream_position()?);
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        let mut chunk = match self.chunk.take() {
+            Some(chunk) if chunk.dirty => chunk,
+            chunk => {
+                self.chunk = chunk;
+                return Ok(());
+            }
+        };
+        let result = self.write_dirty(&chunk);
+        chunk.dirty = result.is_err();
+        self.chunk = Some(chunk);
+        result
+    }
+
+    fn write_dirty(&mut self, chunk: &Chunk) -> io::Result<()> {
+        if self.inner.seek(SeekFrom::End(0))? != self.stored_len {
+            return Err(io::Error::other(Conflict::default()));
+        }
+        // The chunk stored as the last one no longer is
+        if let Some(last) = self.last.filter(|&last| last < chunk.index) {
+            let data = self.read_chunk(last)?;
+            self.write_chunk(last, &data, false)?;
+            self.last = None;
+        }
+        let last = self.last_index() == Some(chunk.index);
+        self.write_chunk(chunk.index, &chunk.data, last)?;
+        if last {
+            self.last = Some(chunk.index);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EncryptedBuffer {
+    fn drop(&mut self) {
+        let _ = self.flush_chunk();
+    }
+}
+
+impl Read for EncryptedBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos < self.data_start {
+            let n = (buf.len() as u64).min(self.data_start - self.pos) as usize;
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            let n = self.inner.read(&mut buf[..n])?;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+        let offset = (self.pos - self.data_start) % CHUNK_SIZE;
+        let chunk = self.load_chunk((self.pos - self.data_start) / CHUNK_SIZE)?;
+        let available = &chunk.data[offset as usize..];
+        let n = buf.len().min(available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for EncryptedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos > self.len {
+            return Err(io::Error::other("can't write past the end of an encrypted file"));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos < self.data_start {
+            let n = (buf.len() as u64).min(self.data_start - self.pos) as usize;
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            let n = self.inner.write(&buf[..n])?;
+            self.pos += n as u64;
+            self.len = self.len.max(self.pos);
+            self.stored_len = self.stored_len.max(self.pos);
+            return Ok(n);
+        }
+        let offset = ((self.pos - self.data_start) % CHUNK_SIZE) as usize;
+        let chunk = self.load_chunk((self.pos - self.data_start) / CHUNK_SIZE)?;
+        let n = buf.len().min(CHUNK_SIZE as usize - offset);
+        if chunk.data.len() < offset + n {
+            chunk.data.resize(offset + n, 0);
+        }
+        chunk.data[offset..offset + n].copy_from_slice(&buf[..n]);
+        chunk.dirty = true;
+        self.pos += n as u64;
+        self.len = self.len.max(self.pos);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_chunk()?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for EncryptedBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => {
+                self.refresh()?;
+                self.len.checked_add_signed(offset)
+            }
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// The decrypted length of a file `stored_len` long.
+fn plain_len(stored_len: u64, data_start: u64) -> Result<u64> {
+    if stored_len <= data_start {
+        return Ok(stored_len);
+    }
+    let stored = stored_len - data_start;
+    let last = stored % STORED_CHUNK_SIZE;
+    if last != 0 && last <= NONCE_SIZE + TAG_SIZE {
+        return Err(anyhow!("encrypted file ends in a truncated chunk").into());
+    }
+    let last = last.saturating_sub(NONCE_SIZE + TAG_SIZE);
+    Ok(data_start + stored / STORED_CHUNK_SIZE * CHUNK_SIZE + last)
+}
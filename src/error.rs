@@ -1,37 +1,112 @@
-use std;
 use std::fmt::{self, Display};
 use std::error::Error as StdError;
+use std::io;
 
 use serde::{de, ser};
 
+use crate::path::Path;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
-pub struct Error(anyhow::Error);
+pub struct Error {
+    error: anyhow::Error,
+    /// Where in the document the error occurred.
+    path: Option<String>,
+}
 
 impl ser::Error for Error {
     fn custom<T: Display>(msg: T) -> Self {
-        Error(anyhow::Error::msg(msg.to_string()))
+        anyhow::Error::msg(msg.to_string()).into()
     }
 }
 
 impl de::Error for Error {
     fn custom<T: Display>(msg: T) -> Self {
-        Error(anyhow::Error::msg(msg.to_string()))
+        anyhow::Error::msg(msg.to_string()).into()
+    }
+}
+
+impl Error {
+    /// The underlying error, if it is an `E`.
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where E: Display + fmt::Debug + Send + Sync + 'static {
+        self.error.downcast_ref()
+    }
+
+    /// Where in the document the error occurred, such as
+    /// `.users[3].name`, if it was inside a value being serialized or
+    /// deserialized.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Whether the error is a `Conflict`, so the write can be retried.
+    pub fn is_conflict(&self) -> bool {
+        self.downcast_ref::<Conflict>().is_some()
+            || matches!(self.downcast_ref::<io::Error>(), Some(e) if is_io_conflict(e))
+    }
+
+    /// Whether the error is that a file couldn't be locked without waiting,
+    /// from `try_open_file`.
+    pub fn is_would_block(&self) -> bool {
+        matches!(self.downcast_ref::<io::Error>(), Some(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    /// Record that the error occurred at `path`, unless it is already
+    /// known to have occurred deeper inside.
+    pub(crate) fn at(mut self, path: &Path) -> Error {
+        if self.path.is_none() {
+            self.path = Some(path.to_string());
+        }
+        self
     }
 }
 
 impl Display for Error {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        self.0.fmt(formatter)
+        if let Some(path) = &self.path {
+            write!(formatter, "{}: ", path)?;
+        }
+        self.error.fmt(formatter)
     }
 }
 
 impl StdError for Error {}
 
+/// Someone else committed to the file since a serializer read it, so it
+/// can't commit, and fails with this until `Serializer::reload` drops what
+/// it wrote and reads the file again, so the write can be retried.
+///
+/// An encrypted file conflicts as soon as someone else writes to it while
+/// the serializer has changes it hasn't flushed, as they share a chunk.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Conflict {
+    /// The last trailer in the file as the serializer last knew it.
+    pub expected: Option<u64>,
+    /// The trailer someone else committed since, if one was found.
+    pub found: Option<u64>,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.found {
+            Some(found) => write!(f, "the file was committed to by someone else, adding the trailer at {}", found),
+            None => write!(f, "the file was written to by someone else"),
+        }
+    }
+}
+
+impl StdError for Conflict {}
+
+/// Whether an I/O error is a `Conflict`, as from an encrypted buffer.
+pub(crate) fn is_io_conflict(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|e| e.is::<Conflict>())
+}
+
 impl From<anyhow::Error> for Error {
-    fn from(e: anyhow::Error) -> Error {
-        Error(e)
+    fn from(error: anyhow::Error) -> Error {
+        Error { error, path: None }
     }
 }
 
@@ -44,25 +119,6 @@ pub trait StdResultExt<T> {
 impl<T, E> StdResultExt<T> for StdResult<T, E>
 where E: StdError + Send + Sync + 'static {
     fn e(self) -> StdResult<T, Error> {
-        self.map_err(|e| Error(anyhow::Error::new(e)))
-    }
-}
-
-pub trait ResultExt {
-    fn cmd_eof(&self) -> bool;
-}
-
-impl<T> ResultExt for Result<T> {
-    fn cmd_eof(&self) -> bool {
-        match self {
-            Ok(_) => false,
-            Err(ref e) => {
-                if let Some(e) = e.0.downcast_ref::<serde_json::Error>() {
-                    e.is_eof()
-                } else {
-                    false
-                }
-            }
-        }
+        self.map_err(|e| anyhow::Error::new(e).into())
     }
 }
@@ -0,0 +1,96 @@
+//! The versions committed to a file.
+
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::anyhow;
+
+use crate::error::Result;
+use crate::meta::{self, CommitInfo, Stitch, Trailer};
+use crate::state::State;
+
+/// A committed version of a file.
+#[derive(Debug, Clone)]
+pub struct Version {
+    /// The version, counting from 1.
+    pub version: usize,
+    pub trailer_pos: u64,
+    pub trailer: Trailer,
+    /// The root position of every document in this version.
+    pub documents: BTreeMap<String, u64>,
+    /// The stitches this version commits, with their positions.
+    pub stitches: Vec<(u64, Stitch)>,
+    /// Where the value each stitch replaces is in its document, in the
+    /// same order, if the commit recorded it.
+    pub paths: Vec<String>,
+    pub info: CommitInfo,
+}
+
+/// Every trailer committed to the branch of `state`, oldest first, whichever
+/// version it has loaded. The trailers of a branch start with those of the
+/// branch it was made from, up to the tag it was made at.
+pub fn trailers(state: &mut State) -> Result<Vec<(Trailer, u64)>> {
+    let encoding = state.header.encoding;
+    let buf = &mut *state.buf;
+    match meta::read_head(buf, encoding, &state.refs, &state.branch)? {
+        Some((last, last_pos)) => meta::trailer_chain(buf, encoding, last, last_pos),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The trailer of the given version, counting from 1.
+pub fn trailer(state: &mut State, version: usize) -> Result<(Trailer, u64)> {
+    let mut trailers = trailers(state)?;
+    if version == 0 || version > trailers.len() {
+        return Err(anyhow!("no version {}; the file has {} versions", version, trailers.len()).into());
+    }
+    Ok(trailers.swap_remove(version - 1))
+}
+
+/// Every committed version, oldest first.
+pub fn versions(state: &mut State) -> Result<Vec<Version>> {
+    let encoding = state.header.encoding;
+    let mut versions = Vec::new();
+    for (i, (trailer, pos)) in trailers(state)?.into_iter().enumerate() {
+        let buf = &mut *state.buf;
+        versions.push(Version {
+            version: i + 1,
+            trailer_pos: pos,
+            documents: meta::read_catalog(buf, encoding, &trailer)?.documents,
+            stitches: meta::read_stitches(buf, encoding, &trailer, pos)?,
+            paths: meta::read_paths(buf, encoding, &trailer)?,
+            info: meta::read_info(buf, encoding, &trailer)?,
+            trailer,
+        });
+    }
+    Ok(versions)
+}
+
+/// The last commit before the trailer at `theirs`, or that trailer itself,
+/// which the branch of `state` also has in its history, counting the
+/// commits merged into it. Returns `None` if they share no history.
+pub fn merge_base(state: &mut State, theirs: u64) -> Result<Option<u64>> {
+    let encoding = state.header.encoding;
+    let buf = &mut *state.buf;
+    let mut ours = HashSet::new();
+    let mut pending: Vec<u64> = meta::read_head(buf, encoding, &state.refs, &state.branch)?
+        .map(|(_, pos)| pos)
+        .into_iter()
+        .collect();
+    while let Some(pos) = pending.pop() {
+        if !ours.insert(pos) {
+            continue;
+        }
+        let trailer = meta::read_trailer_at(buf, encoding, pos)?;
+        pending.extend(trailer.prev_trailer_pos);
+        pending.extend(meta::read_info(buf, encoding, &trailer)?.merge);
+    }
+
+    let mut pos = Some(theirs);
+    while let Some(cur) = pos {
+        if ours.contains(&cur) {
+            return Ok(Some(cur));
+        }
+        pos = meta::read_trailer_at(buf, encoding, cur)?.prev_trailer_pos;
+    }
+    Ok(None)
+}
@@ -0,0 +1,67 @@
+//! Importing a history of JSON snapshots.
+
+use serde_json::Value;
+use std::io::Read;
+
+use crate::error::{Result, StdResultExt};
+use crate::meta::CommitInfo;
+use crate::node::Node;
+use crate::ser::Serializer;
+
+/// A version of a document to import, with what to record about its
+/// commit.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub value: Value,
+    pub info: CommitInfo,
+}
+
+impl Snapshot {
+    pub fn new(value: Value) -> Snapshot {
+        Snapshot {
+            value,
+            info: CommitInfo::default(),
+        }
+    }
+
+    /// A snapshot of the JSON read from `reader`.
+    pub fn from_reader(reader: impl Read) -> Result<Snapshot> {
+        Ok(Snapshot::new(serde_json::from_reader(reader).e()?))
+    }
+
+    pub fn message(mut self, message: impl Into<String>) -> Snapshot {
+        self.info.message = Some(message.into());
+        self
+    }
+
+    /// Set when the snapshot was taken, in seconds since the Unix epoch.
+    pub fn timestamp(mut self, timestamp: u64) -> Snapshot {
+        self.info.timestamp = Some(timestamp);
+        self
+    }
+}
+
+impl From<Value> for Snapshot {
+    fn from(value: Value) -> Snapshot {
+        Snapshot::new(value)
+    }
+}
+
+/// Commit each snapshot in turn as the default document, returning the
+/// number imported.
+///
+/// Each snapshot is diffed against the one before, so the file grows by
+/// what changed. Objects are stored as maps, and an object whose keys
+/// changed is replaced as a whole. A snapshot identical to the one before
+/// still gets a commit if it has a message or timestamp.
+pub fn import<S: Into<Snapshot>>(ser: &mut Serializer, snapshots: impl IntoIterator<Item = S>) -> Result<usize> {
+    let mut count = 0;
+    for snapshot in snapshots {
+        let snapshot = snapshot.into();
+        ser.reset()?;
+        ser.write_node(&Node::from_json(&snapshot.value))?;
+        ser.finalize_with(&snapshot.info)?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,131 @@
+//! Exporting documents as plain JSON, without knowing their Rust types.
+
+use serde::ser::{self, Error as _, SerializeMap, SerializeSeq};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::io::Write;
+
+use crate::de::Deserializer;
+use crate::dcmd;
+use crate::error::{Result, StdResultExt};
+use crate::state::State;
+
+/// The document selected in `de` as of the given version, counting from 1,
+/// as JSON.
+///
+/// Tuples and sequences become arrays, structs and maps objects, and
+/// units null. Enum variants are written as serde writes them by default.
+/// `de` is left as it was.
+pub fn to_json_value(de: &mut Deserializer, version: usize) -> Result<serde_json::Value> {
+    de.at_version(version, |state| serde_json::to_value(Stream::new(state)).e())
+}
+
+/// Like `to_json_value`, writing the JSON to `writer` as the document is
+/// read instead of building it in memory.
+pub fn to_json_writer(de: &mut Deserializer, version: usize, writer: impl Write) -> Result<()> {
+    de.at_version(version, |state| serde_json::to_writer(writer, &Stream::new(state)).e())
+}
+
+/// The value at the current position of a state, read as it is
+/// serialized.
+pub struct Stream<'a> {
+    state: RefCell<&'a mut State>,
+}
+
+impl<'a> Stream<'a> {
+    pub fn new(state: &'a mut State) -> Stream<'a> {
+        Stream { state: RefCell::new(state) }
+    }
+}
+
+impl Serialize for Stream<'_> {
+    fn serialize<S: ser::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = self.state.borrow_mut();
+        serialize_value(&mut state, s)
+    }
+}
+
+/// Serialize the value at the current position, following stitches and
+/// decompressing compressed values, as `State::read_node` does.
+fn serialize_value<S: ser::Serializer>(state: &mut State, s: S) -> std::result::Result<S::Ok, S::Error> {
+    let resume = state.enter_value().map_err(S::Error::custom)?;
+    let block = state.open_block().map_err(S::Error::custom)?;
+    let ok = serialize_commands(state, s);
+    if block {
+        state.close_block();
+    }
+    let ok = ok?;
+    if let Some(resume) = resume {
+        state.seek(resume).map_err(S::Error::custom)?;
+    }
+    Ok(ok)
+}
+
+fn serialize_commands<S: ser::Serializer>(state: &mut State, s: S) -> std::result::Result<S::Ok, S::Error> {
+    if state.probe::<dcmd::SerializeUnit>().map_err(S::Error::custom)?.is_some() {
+        return s.serialize_unit();
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeBool>().map_err(S::Error::custom)? {
+        return s.serialize_bool(cmd.v);
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeU8>().map_err(S::Error::custom)? {
+        return s.serialize_u8(cmd.v);
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeI64>().map_err(S::Error::custom)? {
+        return s.serialize_i64(cmd.i);
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeU64>().map_err(S::Error::custom)? {
+        return s.serialize_u64(cmd.u);
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeF64>().map_err(S::Error::custom)? {
+        return s.serialize_f64(cmd.f);
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeStr>().map_err(S::Error::custom)? {
+        return s.serialize_str(&cmd.s);
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeTuple>().map_err(S::Error::custom)? {
+        let mut seq = s.serialize_seq(Some(cmd.len))?;
+        for _ in 0..cmd.len {
+            state.read::<dcmd::SerializeTupleElement>().map_err(S::Error::custom)?;
+            seq.serialize_element(&Stream::new(state))?;
+        }
+        state.read::<dcmd::SerializeTupleEnd>().map_err(S::Error::custom)?;
+        return seq.end();
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeSeq>().map_err(S::Error::custom)? {
+        let mut seq = s.serialize_seq(Some(cmd.items))?;
+        for _ in 0..cmd.items {
+            state.read::<dcmd::SerializeSeqElement>().map_err(S::Error::custom)?;
+            seq.serialize_element(&Stream::new(state))?;
+        }
+        state.read::<dcmd::SerializeSeqEnd>().map_err(S::Error::custom)?;
+        return seq.end();
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeStruct>().map_err(S::Error::custom)? {
+        let mut map = s.serialize_map(Some(cmd.len))?;
+        while let Some(field) = state.probe::<dcmd::SerializeStructField>().map_err(S::Error::custom)? {
+            map.serialize_entry(&field.key, &Stream::new(state))?;
+        }
+        state.read::<dcmd::SerializeStructEnd>().map_err(S::Error::custom)?;
+        return map.end();
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeMap>().map_err(S::Error::custom)? {
+        let mut map = s.serialize_map(Some(cmd.entries))?;
+        while let Some(key) = state.probe::<dcmd::SerializeMapKey>().map_err(S::Error::custom)? {
+            map.serialize_entry(&key.k, &Stream::new(state))?;
+        }
+        state.read::<dcmd::SerializeMapEnd>().map_err(S::Error::custom)?;
+        return map.end();
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeUnitVariant>().map_err(S::Error::custom)? {
+        return s.serialize_str(&cmd.unit);
+    }
+    if let Some(cmd) = state.probe::<dcmd::SerializeVariant>().map_err(S::Error::custom)? {
+        let mut map = s.serialize_map(Some(1))?;
+        map.serialize_entry(&cmd.variant, &Stream::new(state))?;
+        state.read::<dcmd::SerializeVariantEnd>().map_err(S::Error::custom)?;
+        return map.end();
+    }
+    let pos = state.pos().map_err(S::Error::custom)?;
+    Err(S::Error::custom(format!("unrecognized command at {}", pos)))
+}
@@ -1,13 +1,53 @@
+mod binary;
+mod blame;
+mod chain;
+mod codec;
+mod compact;
+mod compression;
+mod encryption;
 mod de;
+mod diff;
 mod error;
+mod history;
+mod import;
+mod json;
+mod lock;
+mod merge;
+mod patch;
 mod ser;
+mod shared;
 
 mod state;
+mod undo;
 mod scmd;
 mod dcmd;
 mod meta;
+mod node;
+mod overlay;
+mod path;
+mod recover;
+mod verify;
 
 pub use de::{Deserializer};
-pub use error::{Error, Result};
-pub use ser::{Serializer};
+pub use error::{Conflict, Error, Result};
+pub use ser::{Serializer, ChangeSet, StitchChange};
+pub use lock::LockMode;
+pub use state::{Options, DEFAULT_BRANCH, DEFAULT_DOCUMENT};
+pub use compression::Compression;
+pub use encryption::EncryptionKey;
+pub use chain::{BrokenLink, SigningKey};
+pub use verify::{verify, verify_with_options, Report, Problem};
+pub use history::Version;
+pub use compact::compact;
+pub use json::{to_json_value, to_json_writer};
+pub use import::{import, Snapshot};
+pub use diff::{diff, diff_values, Change, Operation};
+pub use blame::{blame, history, Blame};
+pub use undo::UndoManager;
+pub use merge::{merge, merge_branch, MergeConflict, Resolution};
+pub use patch::{to_json_patch, apply_json_patch};
+pub use recover::{recover, RecoveryMode, Recovery, LostVersion};
+pub use meta::{Header, Encoding, Stitch, Trailer, CommitInfo, Refs, FORMAT_VERSION};
+pub use codec::{Codec, JsonCodec, MessagePackCodec};
+pub use binary::BinaryCodec;
 
@@ -0,0 +1,39 @@
+//! Opening files with advisory locks, so processes can take turns.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+
+use crate::error::{Result, StdResultExt};
+
+/// The lock taken on a file while it is open.
+///
+/// Locks are advisory, so they only keep out others that lock the file
+/// too, and are released when the serializer or deserializer is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Keep every other lock out, as a writer should.
+    Exclusive,
+    /// Keep exclusive locks out, but not other shared ones, as a reader
+    /// should.
+    Shared,
+    /// Take no lock. Writers still can't commit over each other, as they
+    /// fail with a `Conflict` instead.
+    Unlocked,
+}
+
+/// Open the file at `path`, for writing if `write`, creating it if it
+/// doesn't exist. If `wait` is false and the lock is held by someone else,
+/// fails with an I/O error of kind `WouldBlock` instead of waiting.
+pub(crate) fn open(path: &Path, write: bool, mode: LockMode, wait: bool) -> Result<File> {
+    let file = OpenOptions::new().read(true).write(write).create(write).truncate(false).open(path).e()?;
+    let locked = match (mode, wait) {
+        (LockMode::Exclusive, true) => file.lock().map_err(TryLockError::Error),
+        (LockMode::Exclusive, false) => file.try_lock(),
+        (LockMode::Shared, true) => file.lock_shared().map_err(TryLockError::Error),
+        (LockMode::Shared, false) => file.try_lock_shared(),
+        (LockMode::Unlocked, _) => Ok(()),
+    };
+    locked.map_err(io::Error::from).e()?;
+    Ok(file)
+}
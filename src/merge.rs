@@ -0,0 +1,275 @@
+//! Three-way merges of documents edited apart.
+
+use anyhow::anyhow;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::de::Deserializer;
+use crate::error::Result;
+use crate::history;
+use crate::meta::{self, CommitInfo};
+use crate::node::Node;
+use crate::path::{Path, Segment};
+use crate::ser::Serializer;
+use crate::state::State;
+
+/// A value changed differently on both sides of a merge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeConflict {
+    pub document: String,
+    /// Where the value is, such as `.limits.max_conn`.
+    pub path: String,
+    /// The value on each side, or `None` where it doesn't exist.
+    pub base: Option<Value>,
+    pub ours: Option<Value>,
+    pub theirs: Option<Value>,
+}
+
+/// How a conflict is resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    /// Keep our value, or leave the value out if we removed it.
+    Ours,
+    /// Take their value, or leave the value out if they removed it.
+    Theirs,
+    /// Use this value instead.
+    Value(Value),
+}
+
+/// Merge the changes made from `base` to `theirs` into the document
+/// selected in `ser`, and commit the result with `finalize`, along with
+/// anything else pending. The selected documents of `base` and `theirs`
+/// are merged, as of the versions they have selected, and are left as
+/// they were.
+///
+/// Values changed on just one side take that side's change. Where both
+/// sides changed a value differently, the fields and entries of structs
+/// and maps are merged by key, and the elements of tuples and sequences by
+/// index if no side changed their length. Anything else is a conflict,
+/// which `resolve` is called with. If it fails, nothing is written.
+///
+/// Returns the conflicts that were resolved.
+pub fn merge<F>(ser: &mut Serializer, base: &mut Deserializer, theirs: &mut Deserializer, mut resolve: F) -> Result<Vec<MergeConflict>>
+where
+    F: FnMut(&MergeConflict) -> Result<Resolution>,
+{
+    let document = ser.selected_document().to_string();
+    let base = base.read_document()?;
+    let theirs = theirs.read_document()?;
+    let ours = ser.read_document()?;
+    let mut merger = Merger { document: &document, path: Path::default(), conflicts: Vec::new(), resolve: &mut resolve };
+    let merged = merger.merge(base.as_ref(), ours.as_ref(), theirs.as_ref())?;
+    let conflicts = merger.conflicts;
+    let merged = merged.ok_or_else(|| anyhow!("can't remove document {:?} by merging", document))?;
+    ser.write_node(&merged)?;
+    ser.finalize()?;
+    Ok(conflicts)
+}
+
+/// Merge the named branch into the branch `ser` is writing, as `merge`
+/// does for every document, and commit the result, recording the head
+/// merged in. Uncommitted changes are merged along with the rest.
+///
+/// The changes merged are those made on the branch since the last commit
+/// both branches have, including through earlier merges. Nothing is done
+/// if the branch has no such changes.
+///
+/// Returns the conflicts that were resolved.
+pub fn merge_branch<F>(ser: &mut Serializer, branch: &str, mut resolve: F) -> Result<Vec<MergeConflict>>
+where
+    F: FnMut(&MergeConflict) -> Result<Resolution>,
+{
+    let (head, base, theirs) = ser.with_state(|state, _| {
+        let encoding = state.header.encoding;
+        let (trailer, head) = meta::read_head(&mut *state.buf, encoding, &state.refs, branch)?
+            .ok_or_else(|| anyhow!("no branch named {:?}", branch))?;
+        let base = history::merge_base(state, head)?;
+        if base == Some(head) {
+            return Ok((None, BTreeMap::new(), BTreeMap::new()));
+        }
+        let base = match base {
+            Some(pos) => {
+                let trailer = meta::read_trailer_at(&mut *state.buf, encoding, pos)?;
+                read_documents(state, (trailer, pos))?
+            }
+            None => BTreeMap::new(),
+        };
+        let theirs = read_documents(state, (trailer, head))?;
+        Ok((Some(head), base, theirs))
+    })?;
+    let head = match head {
+        Some(head) => head,
+        None => return Ok(Vec::new()),
+    };
+
+    let selected = ser.selected_document().to_string();
+    let mut names: Vec<String> = ser.documents().map(str::to_string).collect();
+    names.extend(theirs.keys().filter(|name| !names.contains(name)).cloned().collect::<Vec<_>>());
+    let mut conflicts = Vec::new();
+    let merge_documents = || -> Result<Vec<(String, Option<Node>)>> {
+        let mut merged = Vec::new();
+        for name in names {
+            let ours = ser.document(&name)?.read_document()?;
+            let mut merger = Merger { document: &name, path: Path::default(), conflicts: Vec::new(), resolve: &mut resolve };
+            let node = merger.merge(base.get(&name), ours.as_ref(), theirs.get(&name))?;
+            conflicts.extend(merger.conflicts);
+            // Documents can't be removed, so one removed on both sides stays
+            merged.push((name, node.or(ours)));
+        }
+        Ok(merged)
+    };
+    let merged = merge_documents();
+    ser.document(&selected)?;
+    for (name, node) in merged? {
+        if let Some(node) = node {
+            ser.document(&name)?.write_node(&node)?;
+        }
+    }
+    ser.document(&selected)?;
+    let info = CommitInfo {
+        merge: Some(head),
+        ..CommitInfo::default()
+    };
+    ser.finalize_with(&info)?;
+    Ok(conflicts)
+}
+
+/// Every document committed by a trailer.
+fn read_documents(state: &mut State, trailer: (meta::Trailer, u64)) -> Result<BTreeMap<String, Node>> {
+    state.reload(Some(trailer))?;
+    let mut documents = BTreeMap::new();
+    for name in state.documents.keys().cloned().collect::<Vec<_>>() {
+        state.seek_document(&name)?;
+        documents.insert(name, state.read_node()?);
+    }
+    Ok(documents)
+}
+
+struct Merger<'a, F> {
+    document: &'a str,
+    path: Path,
+    conflicts: Vec<MergeConflict>,
+    resolve: &'a mut F,
+}
+
+impl<F> Merger<'_, F>
+where
+    F: FnMut(&MergeConflict) -> Result<Resolution>,
+{
+    /// The merged value at the current path, or `None` if it is left out.
+    fn merge(&mut self, base: Option<&Node>, ours: Option<&Node>, theirs: Option<&Node>) -> Result<Option<Node>> {
+        if ours == theirs || base == theirs {
+            return Ok(ours.cloned());
+        }
+        if base == ours {
+            return Ok(theirs.cloned());
+        }
+        match (ours, theirs) {
+            (Some(Node::Struct { name, fields: ours }), Some(Node::Struct { name: their_name, fields: theirs }))
+                if name == their_name =>
+            {
+                let base = match base {
+                    Some(Node::Struct { name: base_name, fields }) if base_name == name => &fields[..],
+                    _ => &[],
+                };
+                let fields = self.entries(base, ours, theirs)?;
+                return Ok(Some(Node::Struct { name: name.clone(), fields }));
+            }
+            (Some(Node::Map(ours)), Some(Node::Map(theirs))) => {
+                let base = match base {
+                    Some(Node::Map(entries)) => &entries[..],
+                    _ => &[],
+                };
+                return Ok(Some(Node::Map(self.entries(base, ours, theirs)?)));
+            }
+            (Some(Node::Variant { name, variant, value: Some(ours) }),
+             Some(Node::Variant { name: their_name, variant: their_variant, value: Some(theirs) }))
+                if name == their_name && variant == their_variant =>
+            {
+                let base = match base {
+                    Some(Node::Variant { name: base_name, variant: base_variant, value: Some(value) })
+                        if base_name == name && base_variant == variant => Some(&**value),
+                    _ => None,
+                };
+                self.path.0.push(Segment::Key(variant.clone()));
+                let value = self.merge(base, Some(ours), Some(theirs))?;
+                self.path.0.pop();
+                let value = value.expect("value on both sides");
+                return Ok(Some(Node::Variant { name: name.clone(), variant: variant.clone(), value: Some(Box::new(value)) }));
+            }
+            _ => { }
+        }
+        if let (Some((tuple, base)), Some((our_tuple, ours)), Some((their_tuple, theirs)))
+            = (elements(base), elements(ours), elements(theirs))
+        {
+            if tuple == our_tuple && tuple == their_tuple && base.len() == ours.len() && base.len() == theirs.len() {
+                let elements = self.elements(base, ours, theirs)?;
+                return Ok(Some(if tuple { Node::Tuple(elements) } else { Node::Seq(elements) }));
+            }
+        }
+        self.conflict(base, ours, theirs)
+    }
+
+    /// Merge the fields of structs or entries of maps by key, in our order
+    /// with those only they added last.
+    fn entries(&mut self, base: &[(String, Node)], ours: &[(String, Node)], theirs: &[(String, Node)]) -> Result<Vec<(String, Node)>> {
+        let keys = ours.iter().map(|(k, _)| k)
+            .chain(theirs.iter().map(|(k, _)| k).filter(|k| get(ours, k).is_none()));
+        let mut merged = Vec::new();
+        for key in keys {
+            self.path.0.push(Segment::Key(key.clone()));
+            let value = self.merge(get(base, key), get(ours, key), get(theirs, key))?;
+            self.path.0.pop();
+            if let Some(value) = value {
+                merged.push((key.clone(), value));
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Merge elements by index, where every side has as many.
+    fn elements(&mut self, base: &[Node], ours: &[Node], theirs: &[Node]) -> Result<Vec<Node>> {
+        let mut merged = Vec::new();
+        for (i, ((base, ours), theirs)) in base.iter().zip(ours).zip(theirs).enumerate() {
+            self.path.0.push(Segment::Index(i));
+            let value = self.merge(Some(base), Some(ours), Some(theirs))?;
+            self.path.0.pop();
+            merged.push(value.expect("element on both sides"));
+        }
+        Ok(merged)
+    }
+
+    fn conflict(&mut self, base: Option<&Node>, ours: Option<&Node>, theirs: Option<&Node>) -> Result<Option<Node>> {
+        let conflict = MergeConflict {
+            document: self.document.to_string(),
+            path: self.path.to_string(),
+            base: base.map(Node::to_json),
+            ours: ours.map(Node::to_json),
+            theirs: theirs.map(Node::to_json),
+        };
+        let path = &self.path;
+        let resolution = (self.resolve)(&conflict).map_err(|e| e.at(path))?;
+        self.conflicts.push(conflict);
+        Ok(match resolution {
+            Resolution::Ours => ours.cloned(),
+            Resolution::Theirs => theirs.cloned(),
+            Resolution::Value(value) => match ours.or(theirs).or(base) {
+                Some(like) => Some(Node::from_json_like(&value, like)),
+                None => Some(Node::from_json(&value)),
+            },
+        })
+    }
+}
+
+fn get<'a>(entries: &'a [(String, Node)], key: &str) -> Option<&'a Node> {
+    entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// The elements of a tuple or sequence, and whether it is a tuple.
+fn elements(node: Option<&Node>) -> Option<(bool, &[Node])> {
+    match node {
+        Some(Node::Tuple(elements)) => Some((true, elements)),
+        Some(Node::Seq(elements)) => Some((false, elements)),
+        _ => None,
+    }
+}
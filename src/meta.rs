@@ -1,15 +1,19 @@
 #![allow(unused)]
 
-use anyhow::{bail, anyhow};
+use anyhow::anyhow;
 use crate::error::{Result, StdResultExt};
 use serde::{Serialize, Deserialize};
-use serde_json;
 use byteorder::{ByteOrder, LittleEndian};
-use hex;
-use std::io::SeekFrom;
-use crate::state::Buffer;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::io::{Read, Write, SeekFrom};
+use crate::state::{Buffer, Options, DEFAULT_BRANCH, DEFAULT_DOCUMENT};
+use crate::compression::{self, Compression};
+use crate::encryption::{self, Encryption};
+use crate::codec::Codec;
+use crate::scmd;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Stitch {
     pub old_pos: u64,
     pub new_pos: u64,
@@ -54,55 +58,450 @@ impl FixedSizeStitch {
 
 pub const MAGIC: u64 = 0x84124f4c417733f8;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Marks the header at the start of a serdif file.
+pub const HEADER_MAGIC: &str = "serdif";
+
+/// How every header starts, as `write_header` writes it. Files starting
+/// any other way were written before headers existed.
+const HEADER_PREFIX: &[u8] = b"{\"magic\":\"serdif";
+
+/// The newest file format version this crate understands. Files without a
+/// header are version 0.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The optional features this crate understands. Files that enable any
+/// other feature are refused.
+const KNOWN_FEATURES: &[&str] = &[compression::FEATURE, encryption::FEATURE];
+
+/// How far back from the end of the file to look for the last trailer.
+const TRAILER_SEARCH_LIMIT: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Trailer {
     pub magic: u64,
     pub first_stitch: Option<u64>,
     pub prev_trailer_pos: Option<u64>,
+    /// Position of the document catalog. Files with a single unnamed
+    /// document at offset 0 have no catalog.
+    #[serde(default)]
+    pub catalog: Option<u64>,
+    /// SHA-256 of the previous trailer. See the `chain` module.
+    #[serde(default)]
+    pub prev_hash: Option<Bytes>,
+    /// SHA-256 of the data written since the previous trailer.
+    #[serde(default)]
+    pub data_hash: Option<Bytes>,
+    /// HMAC-SHA256 of the trailer without its signature.
+    #[serde(default)]
+    pub signature: Option<Bytes>,
+    /// Position of the `CommitInfo` describing this commit, if any.
+    #[serde(default)]
+    pub info: Option<u64>,
+    /// Position of the `StitchPaths` of this commit's stitches, if any.
+    #[serde(default)]
+    pub paths: Option<u64>,
+    /// Position of the `Refs` as of this commit. Files without any are on
+    /// `DEFAULT_BRANCH` alone.
+    #[serde(default)]
+    pub refs: Option<u64>,
+}
+
+/// Bytes in a record, as hex in human-readable encodings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        scmd::data::serialize(&self.0, s)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Bytes, D::Error> {
+        scmd::data::deserialize(d).map(Bytes)
+    }
+}
+
+/// The root position of every named document as of a trailer.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Catalog {
+    pub documents: BTreeMap<String, u64>,
+}
+
+impl Catalog {
+    /// Whether this catalog can be left implicit, as in files written before
+    /// named documents existed.
+    pub fn is_implicit(&self) -> bool {
+        self.documents.len() == 1 && self.documents.get(DEFAULT_DOCUMENT) == Some(&0)
+    }
+}
+
+/// What was recorded about a commit. It is written just before the
+/// trailer, which points to it, so trailers stay small enough to find.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitInfo {
+    /// When the commit was made, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// On commits made by `UndoManager::undo`, the version whose document
+    /// was restored.
+    #[serde(default)]
+    pub undo: Option<u64>,
+    /// On commits made by `UndoManager::redo`, the version whose document
+    /// was restored.
+    #[serde(default)]
+    pub redo: Option<u64>,
+    /// On commits made by `merge_branch`, the position of the trailer
+    /// merged in, so later merges start from it.
+    #[serde(default)]
+    pub merge: Option<u64>,
+}
+
+/// Where the values a commit's stitches replace are in their documents,
+/// such as `.users[3].name`, in the order of the stitches. It is written
+/// just before the trailer, like `CommitInfo`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct StitchPaths {
+    pub paths: Vec<String>,
+}
+
+/// The branches and tags of a file as of a trailer. It is written just
+/// before the trailer, like `CommitInfo`.
+///
+/// As written, `heads` leaves out `branch`, since its head is the trailer
+/// itself. `find_refs` and `State::load_at` add it back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Refs {
+    /// The branch the trailer commits to.
+    pub branch: String,
+    /// The trailer at the head of every branch.
+    pub heads: BTreeMap<String, u64>,
+    /// The trailer every tag points at.
+    pub tags: BTreeMap<String, u64>,
+}
+
+impl Default for Refs {
+    fn default() -> Refs {
+        Refs {
+            branch: DEFAULT_BRANCH.to_string(),
+            heads: BTreeMap::new(),
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+impl Refs {
+    /// Whether these refs, as written, can be left implicit, as in files
+    /// written before branches existed.
+    pub fn is_implicit(&self) -> bool {
+        self.branch == DEFAULT_BRANCH && self.heads.is_empty() && self.tags.is_empty()
+    }
+}
+
+impl CommitInfo {
+    pub fn is_empty(&self) -> bool {
+        *self == CommitInfo::default()
+    }
+}
+
+/// How commands, stitches and trailers are encoded. Each encoding has a
+/// `Codec`, which `Encoding` dispatches to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Pretty-printed JSON, one command per line.
+    #[default]
+    Json,
+    /// One-byte tags and varints. See the `binary` module.
+    Binary,
+    /// MessagePack. See `MessagePackCodec`.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+/// The first line of a serdif file, identifying its format.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub magic: String,
+    pub version: u32,
+    pub encoding: Encoding,
+    pub features: Vec<String>,
+    /// How large values are compressed, with the `compression` feature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// How everything after the header is encrypted, with the `encryption`
+    /// feature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+}
+
+/// Just enough of a header to check its version before parsing the rest.
+#[derive(Deserialize)]
+struct HeaderVersion {
+    magic: String,
+    version: u32,
+}
+
+impl Header {
+    /// The header written to new files.
+    pub fn new(options: &Options) -> Header {
+        let mut features = Vec::new();
+        if options.compression.is_some() {
+            features.push(compression::FEATURE.to_string());
+        }
+        if options.key.is_some() {
+            features.push(encryption::FEATURE.to_string());
+        }
+        Header {
+            magic: HEADER_MAGIC.to_string(),
+            version: FORMAT_VERSION,
+            encoding: options.encoding,
+            features,
+            compression: options.compression,
+            encryption: options.key.as_ref().map(Encryption::new),
+        }
+    }
+
+    /// The implied header of files written before headers existed.
+    pub fn legacy() -> Header {
+        Header {
+            version: 0,
+            ..Header::new(&Options::default())
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    fn check(&self) -> Result<()> {
+        if let Some(feature) = self.features.iter().find(|f| !KNOWN_FEATURES.contains(&f.as_str())) {
+            return Err(anyhow!("serdif file requires unsupported feature {:?}", feature).into());
+        }
+        if self.has_feature(compression::FEATURE) != self.compression.is_some() {
+            return Err(anyhow!("serdif file header has a mismatched compression setting").into());
+        }
+        if self.has_feature(encryption::FEATURE) != self.encryption.is_some() {
+            return Err(anyhow!("serdif file header has a mismatched encryption setting").into());
+        }
+        Ok(())
+    }
 }
 
-fn find_last_trailer(buf: &mut dyn Buffer) -> Result<Option<(Trailer, u64)>> {
-    let orig_pos = buf.seek(SeekFrom::Current(0)).e()?;
-    let pos = buf.seek(SeekFrom::End(0)).e()?;
-    if pos == 0 {
+/// Read the header at the start of the file, returning it and the position
+/// where the data after it starts. Empty files have no header.
+pub fn read_header(buf: &mut dyn Buffer) -> Result<Option<(Header, u64)>> {
+    let end_pos = buf.seek(SeekFrom::End(0)).e()?;
+    if end_pos == 0 {
         return Ok(None);
     }
+    let mut prefix = Vec::new();
+    buf.seek(SeekFrom::Start(0)).e()?;
+    (&mut *buf).take(HEADER_PREFIX.len() as u64).read_to_end(&mut prefix).e()?;
+    if prefix != HEADER_PREFIX {
+        return Ok(Some((Header::legacy(), 0)));
+    }
+    let corrupt = |e| anyhow!("serdif file header is corrupt: {}", e);
+    let v = read_json_at::<HeaderVersion>(&mut *buf, 0).map_err(corrupt)?;
+    if v.magic != HEADER_MAGIC {
+        return Err(anyhow!("serdif file header is corrupt: bad magic {:?}", v.magic).into());
+    }
+    if v.version > FORMAT_VERSION {
+        return Err(anyhow!("serdif file format version {} is newer than supported version {}",
+                           v.version, FORMAT_VERSION).into());
+    }
+    let header = read_json_at::<Header>(&mut *buf, 0).map_err(corrupt)?;
+    header.check()?;
+    // The header is followed by a newline
+    let data_start = buf.stream_position().e()? + 1;
+    Ok(Some((header, data_start)))
+}
+
+pub fn write_header(buf: &mut dyn Buffer, header: &Header) -> Result<()> {
+    buf.seek(SeekFrom::Start(0)).e()?;
+    serde_json::to_writer(&mut *buf, header).e()?;
+    writeln!(&mut *buf).e()?;
+    Ok(())
+}
+
+/// Where the data after `header` starts once it is written.
+pub fn header_len(header: &Header) -> Result<u64> {
+    Ok(serde_json::to_vec(header).e()?.len() as u64 + 1)
+}
+
+fn read_json_at<T: DeserializeOwned>(buf: &mut dyn Buffer, pos: u64) -> Result<T> {
+    read_at(buf, Encoding::Json, pos)
+}
+
+fn read_at<T: DeserializeOwned>(buf: &mut dyn Buffer, encoding: Encoding, pos: u64) -> Result<T> {
+    buf.seek(SeekFrom::Start(pos)).e()?;
+    encoding.read(buf)
+}
+
+pub fn read_trailer_at(buf: &mut dyn Buffer, encoding: Encoding, pos: u64) -> Result<Trailer> {
+    buf.seek(SeekFrom::Start(pos)).e()?;
+    encoding.read_trailer(buf)
+}
 
-    for i in 0..1000 {
-        let pos = buf.seek(SeekFrom::End(i)).e()?;
-        let mut de = serde_json::Deserializer::from_reader(&mut *buf);
-        let t = Trailer::deserialize(&mut de);
-        if let Ok(t) = t {
+/// Find the last trailer after `data_start`, where the header ends.
+pub fn find_last_trailer(buf: &mut dyn Buffer, encoding: Encoding, data_start: u64) -> Result<Option<(Trailer, u64)>> {
+    let orig_pos = buf.stream_position().e()?;
+    let end_pos = buf.seek(SeekFrom::End(0)).e()?;
+    if end_pos <= data_start {
+        buf.seek(SeekFrom::Start(orig_pos)).e()?;
+        return Ok(None);
+    }
+
+    // Failing to read the end of the file, as when an encrypted chunk fails
+    // authentication, is reported as such, not as a missing trailer
+    buf.seek(SeekFrom::Start(end_pos - 1)).e()?;
+    buf.read_exact(&mut [0]).e()?;
+
+    let search_start = end_pos.saturating_sub(TRAILER_SEARCH_LIMIT).max(data_start);
+    let found = find_trailer_in(&mut *buf, encoding, search_start, end_pos)?;
+    buf.seek(SeekFrom::Start(orig_pos)).e()?;
+    match found {
+        Some(found) => Ok(Some(found)),
+        None => Err(anyhow!("unable to find trailer block").into()),
+    }
+}
+
+/// Find the last trailer starting between `start` and `end`, however far
+/// back it is.
+pub fn find_trailer_in(buf: &mut dyn Buffer, encoding: Encoding, start: u64, end: u64) -> Result<Option<(Trailer, u64)>> {
+    for pos in (start..end).rev() {
+        if let Ok(t) = read_trailer_at(&mut *buf, encoding, pos) {
             if t.magic == MAGIC {
-                buf.seek(SeekFrom::Start(orig_pos)).e()?;
                 return Ok(Some((t, pos)));
             }
         }
     }
+    Ok(None)
+}
 
+/// Every trailer leading up to and including `last`, oldest first.
+pub fn trailer_chain(buf: &mut dyn Buffer, encoding: Encoding, last: Trailer, last_pos: u64) -> Result<Vec<(Trailer, u64)>> {
+    let orig_pos = buf.stream_position().e()?;
+    let mut cur = (last, last_pos);
+    let mut stack = Vec::new();
+    while let Some(prev_trailer_pos) = cur.0.prev_trailer_pos {
+        if prev_trailer_pos >= cur.1 {
+            return Err(anyhow!("trailer at {} points forward to {}", cur.1, prev_trailer_pos).into());
+        }
+        let prev_trailer = read_trailer_at(&mut *buf, encoding, prev_trailer_pos)?;
+        stack.push(cur);
+        cur = (prev_trailer, prev_trailer_pos);
+    }
+    stack.push(cur);
+    stack.reverse();
     buf.seek(SeekFrom::Start(orig_pos)).e()?;
-    Err(anyhow!("unable to find trailer block").into())
-}
-
-fn find_trailers(buf: &mut dyn Buffer) -> Result<Option<(Vec<Trailer>, u64)>> {
-    if let Some((last_trailer, last_trailer_pos)) = find_last_trailer(&mut *buf)? {
-        let orig_pos = buf.seek(SeekFrom::Current(0)).e()?;
-        let mut cur_trailer = last_trailer;
-        let mut stack = Vec::new();
-        loop {
-            if let Some(prev_trailer_pos) = cur_trailer.prev_trailer_pos {
-                stack.push(cur_trailer);
-                buf.seek(SeekFrom::Start(prev_trailer_pos)).e()?;
-                let mut de = serde_json::Deserializer::from_reader(&mut *buf);
-                let prev_trailer = Trailer::deserialize(&mut de).e()?;
-                cur_trailer = prev_trailer;
-            } else {
-                stack.push(cur_trailer);
-                buf.seek(SeekFrom::Start(orig_pos)).e()?;
-                return Ok(Some((stack, last_trailer_pos)));
-            }
+    Ok(stack)
+}
+
+/// The stitches committed by a trailer, with their positions.
+///
+/// Each stitch's `next_stitch_pos` links to the next stitch of the same
+/// commit, and the last one links to the trailer itself.
+pub fn read_stitches(buf: &mut dyn Buffer, encoding: Encoding, trailer: &Trailer, trailer_pos: u64) -> Result<Vec<(u64, Stitch)>> {
+    let orig_pos = buf.stream_position().e()?;
+    let mut stitches = Vec::new();
+    let mut pos = match trailer.first_stitch {
+        Some(pos) => pos,
+        None => return Ok(stitches),
+    };
+    while pos != trailer_pos {
+        if pos > trailer_pos {
+            return Err(anyhow!("stitch at {} is past its trailer at {}", pos, trailer_pos).into());
         }
-    } else {
-        Ok(None)
+        buf.seek(SeekFrom::Start(pos)).e()?;
+        let stitch = encoding.read_stitch(&mut *buf)?;
+        if stitch.next_stitch_pos <= pos {
+            return Err(anyhow!("stitch at {} points backwards to {}", pos, stitch.next_stitch_pos).into());
+        }
+        let next = stitch.next_stitch_pos;
+        stitches.push((pos, stitch));
+        pos = next;
+    }
+    buf.seek(SeekFrom::Start(orig_pos)).e()?;
+    Ok(stitches)
+}
+
+pub fn read_catalog(buf: &mut dyn Buffer, encoding: Encoding, trailer: &Trailer) -> Result<Catalog> {
+    match trailer.catalog {
+        Some(pos) => {
+            let orig_pos = buf.stream_position().e()?;
+            let catalog = read_at::<Catalog>(&mut *buf, encoding, pos)?;
+            buf.seek(SeekFrom::Start(orig_pos)).e()?;
+            Ok(catalog)
+        }
+        None => {
+            let mut catalog = Catalog::default();
+            catalog.documents.insert(DEFAULT_DOCUMENT.to_string(), 0);
+            Ok(catalog)
+        }
+    }
+}
+
+pub fn read_info(buf: &mut dyn Buffer, encoding: Encoding, trailer: &Trailer) -> Result<CommitInfo> {
+    match trailer.info {
+        Some(pos) => {
+            let orig_pos = buf.stream_position().e()?;
+            let info = read_at::<CommitInfo>(&mut *buf, encoding, pos)?;
+            buf.seek(SeekFrom::Start(orig_pos)).e()?;
+            Ok(info)
+        }
+        None => Ok(CommitInfo::default()),
+    }
+}
+
+pub fn read_paths(buf: &mut dyn Buffer, encoding: Encoding, trailer: &Trailer) -> Result<Vec<String>> {
+    match trailer.paths {
+        Some(pos) => {
+            let orig_pos = buf.stream_position().e()?;
+            let paths = read_at::<StitchPaths>(&mut *buf, encoding, pos)?;
+            buf.seek(SeekFrom::Start(orig_pos)).e()?;
+            Ok(paths.paths)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+pub fn read_refs(buf: &mut dyn Buffer, encoding: Encoding, trailer: &Trailer) -> Result<Refs> {
+    match trailer.refs {
+        Some(pos) => {
+            let orig_pos = buf.stream_position().e()?;
+            let refs = read_at::<Refs>(&mut *buf, encoding, pos)?;
+            buf.seek(SeekFrom::Start(orig_pos)).e()?;
+            Ok(refs)
+        }
+        None => Ok(Refs::default()),
+    }
+}
+
+/// The branches and tags of the file, as recorded by its last trailer,
+/// which is the head of its branch. Files without trailers have none.
+pub fn find_refs(buf: &mut dyn Buffer, encoding: Encoding, data_start: u64) -> Result<Refs> {
+    match find_last_trailer(&mut *buf, encoding, data_start)? {
+        Some((last, last_pos)) => {
+            let mut refs = read_refs(buf, encoding, &last)?;
+            refs.heads.insert(refs.branch.clone(), last_pos);
+            Ok(refs)
+        }
+        None => Ok(Refs::default()),
     }
 }
+
+/// The trailer at the head of the named branch in `refs`, or `None` if the
+/// file has no trailers.
+pub fn read_head(buf: &mut dyn Buffer, encoding: Encoding, refs: &Refs, branch: &str) -> Result<Option<(Trailer, u64)>> {
+    if refs.heads.is_empty() {
+        return Ok(None);
+    }
+    let pos = *refs.heads.get(branch).ok_or_else(|| anyhow!("no branch named {:?}", branch))?;
+    let orig_pos = buf.stream_position().e()?;
+    let trailer = read_trailer_at(&mut *buf, encoding, pos)?;
+    buf.seek(SeekFrom::Start(orig_pos)).e()?;
+    Ok(Some((trailer, pos)))
+}
@@ -0,0 +1,130 @@
+//! Values read from the command stream without knowing their type.
+
+use serde_json::Value;
+
+/// A value as a version of a file stores it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Unit,
+    Bool(bool),
+    U8(u8),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+    Tuple(Vec<Node>),
+    Seq(Vec<Node>),
+    Struct {
+        name: String,
+        fields: Vec<(String, Node)>,
+    },
+    Map(Vec<(String, Node)>),
+    /// An enum variant, with its value unless it is a unit variant.
+    Variant {
+        name: String,
+        variant: String,
+        value: Option<Box<Node>>,
+    },
+}
+
+impl Node {
+    /// The node a JSON value is stored as. Arrays become sequences and
+    /// objects maps, and numbers are stored as `U64` if they fit, then
+    /// `I64`, then `F64`.
+    pub fn from_json(value: &Value) -> Node {
+        match value {
+            Value::Null => Node::Unit,
+            Value::Bool(v) => Node::Bool(*v),
+            Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(v), _) => Node::U64(v),
+                (None, Some(v)) => Node::I64(v),
+                (None, None) => Node::F64(n.as_f64().expect("number")),
+            },
+            Value::String(v) => Node::Str(v.clone()),
+            Value::Array(elements) => Node::Seq(elements.iter().map(Node::from_json).collect()),
+            Value::Object(entries) => {
+                Node::Map(entries.iter().map(|(k, v)| (k.clone(), Node::from_json(v))).collect())
+            }
+        }
+    }
+
+    /// The node a JSON value is stored as in place of `like`, keeping the
+    /// kinds of `like` and the values inside it where the JSON fits them,
+    /// so a struct stays a struct and a `u8` a `u8`.
+    pub fn from_json_like(value: &Value, like: &Node) -> Node {
+        match (like, value) {
+            (Node::U8(_), Value::Number(n)) if n.as_u64().is_some_and(|v| v <= u8::MAX.into()) => {
+                Node::U8(n.as_u64().expect("u8") as u8)
+            }
+            (Node::I64(_), Value::Number(n)) if n.as_i64().is_some() => {
+                Node::I64(n.as_i64().expect("i64"))
+            }
+            (Node::F64(_), Value::Number(n)) => Node::F64(n.as_f64().expect("number")),
+            (Node::Tuple(elements), Value::Array(values)) if elements.len() == values.len() => {
+                Node::Tuple(elements.iter().zip(values).map(|(e, v)| Node::from_json_like(v, e)).collect())
+            }
+            (Node::Seq(elements), Value::Array(values)) => {
+                Node::Seq(values.iter().enumerate().map(|(i, v)| match elements.get(i) {
+                    Some(e) => Node::from_json_like(v, e),
+                    None => Node::from_json(v),
+                }).collect())
+            }
+            (Node::Struct { name, fields }, Value::Object(values))
+                if fields.len() == values.len() && fields.iter().all(|(k, _)| values.contains_key(k)) =>
+            {
+                Node::Struct {
+                    name: name.clone(),
+                    fields: fields.iter()
+                        .map(|(k, f)| (k.clone(), Node::from_json_like(&values[k], f)))
+                        .collect(),
+                }
+            }
+            (Node::Variant { name, .. }, Value::String(variant)) => {
+                Node::Variant { name: name.clone(), variant: variant.clone(), value: None }
+            }
+            (Node::Variant { name, variant, value }, Value::Object(values)) if values.len() == 1 => {
+                let (k, v) = values.iter().next().expect("one entry");
+                let node = match value {
+                    Some(value) if k == variant => Node::from_json_like(v, value),
+                    _ => Node::from_json(v),
+                };
+                Node::Variant { name: name.clone(), variant: k.clone(), value: Some(Box::new(node)) }
+            }
+            (Node::Map(entries), Value::Object(values)) => {
+                Node::Map(values.iter().map(|(k, v)| {
+                    let node = match entries.iter().find(|(key, _)| key == k) {
+                        Some((_, e)) => Node::from_json_like(v, e),
+                        None => Node::from_json(v),
+                    };
+                    (k.clone(), node)
+                }).collect())
+            }
+            _ => Node::from_json(value),
+        }
+    }
+
+    /// The value as JSON, as `to_json_value` exports it. Enum variants are
+    /// written as serde writes them by default: a unit variant as its name,
+    /// and others as an object with the variant's name as the only key.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Node::Unit => Value::Null,
+            Node::Bool(v) => Value::Bool(*v),
+            Node::U8(v) => Value::from(*v),
+            Node::I64(v) => Value::from(*v),
+            Node::U64(v) => Value::from(*v),
+            Node::F64(v) => Value::from(*v),
+            Node::Str(v) => Value::String(v.clone()),
+            Node::Tuple(elements) | Node::Seq(elements) => {
+                Value::Array(elements.iter().map(Node::to_json).collect())
+            }
+            Node::Struct { fields: entries, .. } | Node::Map(entries) => {
+                Value::Object(entries.iter().map(|(k, v)| (k.clone(), v.to_json())).collect())
+            }
+            Node::Variant { variant, value: None, .. } => Value::String(variant.clone()),
+            Node::Variant { variant, value: Some(value), .. } => {
+                Value::Object(std::iter::once((variant.clone(), value.to_json())).collect())
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! A buffer that keeps writes in memory, for serializing without changing
+//! a file.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::state::Buffer;
+
+/// Reads through to a base buffer, and keeps everything written after its
+/// end in memory. Existing data can't be overwritten.
+pub struct Overlay {
+    base: Base,
+    base_len: u64,
+    tail: Vec<u8>,
+    pos: u64,
+}
+
+/// The buffer under an overlay, or shared by `Shared` handles.
+pub type Base = Arc<Mutex<Box<dyn Buffer>>>;
+
+impl Overlay {
+    /// Replace `buf` with an overlay on it, returning the original to
+    /// pass to `remove`.
+    pub fn install(buf: &mut Box<dyn Buffer>) -> io::Result<Base> {
+        let base_len = buf.seek(SeekFrom::End(0))?;
+        let placeholder: Box<dyn Buffer> = Box::new(io::Cursor::new(Vec::new()));
+        let base = Arc::new(Mutex::new(std::mem::replace(buf, placeholder)));
+        *buf = Box::new(Overlay {
+            base: base.clone(),
+            base_len,
+            tail: Vec::new(),
+            pos: base_len,
+        });
+        Ok(base)
+    }
+
+    /// Discard the overlay installed on `buf` and everything written to
+    /// it, putting back the original.
+    pub fn remove(buf: &mut Box<dyn Buffer>, base: Base) {
+        *buf = Box::new(io::Cursor::new(Vec::new()));
+        let base = Arc::try_unwrap(base).unwrap_or_else(|_| panic!("overlay still in use"));
+        *buf = base.into_inner().unwrap_or_else(|e| e.into_inner());
+    }
+
+    fn len(&self) -> u64 {
+        self.base_len + self.tail.len() as u64
+    }
+}
+
+impl Read for Overlay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = if self.pos < self.base_len {
+            let n = (buf.len() as u64).min(self.base_len - self.pos) as usize;
+            let mut base = self.base.lock().unwrap_or_else(|e| e.into_inner());
+            base.seek(SeekFrom::Start(self.pos))?;
+            base.read(&mut buf[..n])?
+        } else {
+            let offset = ((self.pos - self.base_len) as usize).min(self.tail.len());
+            let available = &self.tail[offset..];
+            let n = buf.len().min(available.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Overlay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos < self.base_len {
+            return Err(io::Error::other("can't overwrite existing data in an overlay"));
+        }
+        let offset = (self.pos - self.base_len) as usize;
+        if self.tail.len() < offset + buf.len() {
+            self.tail.resize(offset + buf.len(), 0);
+        }
+        self.tail[offset..offset + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Overlay {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
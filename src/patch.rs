@@ -0,0 +1,224 @@
+//! JSON Patch (RFC 6902) between versions, and applied as new versions.
+
+use anyhow::anyhow;
+use serde_json::{json, Number, Value};
+
+use crate::de::Deserializer;
+use crate::diff::{diff, Operation};
+use crate::error::Result;
+use crate::node::Node;
+use crate::path::{Path, Segment};
+use crate::ser::Serializer;
+
+/// The changes to the document selected in `de` from one version to
+/// another, as a JSON Patch array of `add`, `remove` and `replace`
+/// operations, in the order `diff` lists them. Version 0 is the empty
+/// file. `de` is left as it was.
+pub fn to_json_patch(de: &mut Deserializer, from_version: usize, to_version: usize) -> Result<Value> {
+    let mut patch = Vec::new();
+    for change in diff(de, from_version, to_version)? {
+        let path = pointer(&change.path)?;
+        patch.push(match change.op {
+            Operation::Add => json!({ "op": "add", "path": path, "value": change.new }),
+            Operation::Remove => json!({ "op": "remove", "path": path }),
+            Operation::Replace => json!({ "op": "replace", "path": path, "value": change.new }),
+        });
+    }
+    Ok(Value::Array(patch))
+}
+
+/// Apply a JSON Patch to the document selected in `ser`, and commit the
+/// result with `finalize`, along with anything else pending.
+///
+/// All the operations of RFC 6902 are supported. If any of them fails,
+/// including a `test`, nothing is written. A document that doesn't exist
+/// yet is null, so adding the whole document creates it.
+///
+/// Values keep their kinds where the JSON replacing them fits, so a
+/// document written from Rust types still reads back as them, and only
+/// the values the patch changes are stitched.
+pub fn apply_json_patch(ser: &mut Serializer, patch: &Value) -> Result<()> {
+    let operations = patch.as_array().ok_or_else(|| anyhow!("a JSON Patch must be an array"))?;
+    let mut document = ser.read_document()?.unwrap_or(Node::Unit);
+    for (i, operation) in operations.iter().enumerate() {
+        apply(&mut document, operation).map_err(|e| anyhow!("operation {}: {}", i, e))?;
+    }
+    ser.write_node(&document)?;
+    ser.finalize()
+}
+
+fn apply(document: &mut Node, operation: &Value) -> Result<()> {
+    let field = |name: &str| operation.get(name).ok_or_else(|| anyhow!("missing {:?}", name));
+    let pointer_field = |name: &str| -> Result<Vec<String>> {
+        let pointer = field(name)?.as_str().ok_or_else(|| anyhow!("{:?} must be a string", name))?;
+        tokens(pointer)
+    };
+    let path = pointer_field("path")?;
+    match field("op")?.as_str() {
+        Some("add") => add(document, &path, field("value")?),
+        Some("remove") => remove(document, &path).map(drop),
+        Some("replace") => {
+            let target = get(document, &path)?;
+            *target = Node::from_json_like(field("value")?, target);
+            Ok(())
+        }
+        Some("move") => {
+            let from = pointer_field("from")?;
+            if path.len() > from.len() && path[..from.len()] == from[..] {
+                return Err(anyhow!("can't move a value inside itself").into());
+            }
+            let value = remove(document, &from)?;
+            insert(document, &path, value)
+        }
+        Some("copy") => {
+            let value = get(document, &pointer_field("from")?)?.clone();
+            insert(document, &path, value)
+        }
+        Some("test") => {
+            if !json_equal(&get(document, &path)?.to_json(), field("value")?) {
+                return Err(anyhow!("test failed at {:?}", field("path")?).into());
+            }
+            Ok(())
+        }
+        _ => Err(anyhow!("unknown op {}", field("op")?).into()),
+    }
+}
+
+/// Whether two values are equal as a `test` compares them, with numbers
+/// equal if their values are, so `1` and `1.0` are.
+fn json_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => numbers_equal(a, b),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_equal(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len() && a.iter().all(|(k, a)| b.get(k).is_some_and(|b| json_equal(a, b)))
+        }
+        _ => a == b,
+    }
+}
+
+/// Integers are compared exactly, and other numbers as floats.
+fn numbers_equal(a: &Number, b: &Number) -> bool {
+    let integer = |n: &Number| n.as_i64().map(i128::from).or_else(|| n.as_u64().map(i128::from));
+    match (integer(a), integer(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.as_f64() == b.as_f64(),
+    }
+}
+
+fn add(document: &mut Node, path: &[String], value: &Value) -> Result<()> {
+    // Adding over a member of a struct or map replaces it, so keeps its kind
+    let in_array = match path.split_last() {
+        Some((_, parent)) => matches!(get(document, parent), Ok(Node::Seq(_))),
+        None => false,
+    };
+    let value = match get(document, path) {
+        Ok(old) if !in_array => Node::from_json_like(value, old),
+        _ => Node::from_json(value),
+    };
+    insert(document, path, value)
+}
+
+/// Add `value` at `path`, replacing a member of a struct or map, adding
+/// to a map, or inserting into a sequence. Structs and tuples have the
+/// fields their types give them, so can't gain any.
+fn insert(document: &mut Node, path: &[String], value: Node) -> Result<()> {
+    let (last, parent) = match path.split_last() {
+        Some(split) => split,
+        None => {
+            *document = value;
+            return Ok(());
+        }
+    };
+    match get(document, parent)? {
+        Node::Map(entries) => {
+            match entries.iter_mut().find(|(key, _)| key == last) {
+                Some((_, entry)) => *entry = value,
+                None => entries.push((last.clone(), value)),
+            }
+        }
+        Node::Struct { fields, .. } => {
+            let (_, field) = fields.iter_mut().find(|(key, _)| key == last)
+                .ok_or_else(|| anyhow!("can't add field {:?} to a struct", last))?;
+            *field = value;
+        }
+        Node::Seq(elements) => {
+            let i = if last == "-" { elements.len() } else { index(last, elements.len() + 1)? };
+            elements.insert(i, value);
+        }
+        Node::Tuple(_) => return Err(anyhow!("can't add element {:?} to a tuple", last).into()),
+        _ => return Err(anyhow!("can't add {:?} to a value that isn't an array or object", last).into()),
+    }
+    Ok(())
+}
+
+fn remove(document: &mut Node, path: &[String]) -> Result<Node> {
+    let (last, parent) = path.split_last().ok_or_else(|| anyhow!("can't remove the whole document"))?;
+    match get(document, parent)? {
+        Node::Map(entries) => {
+            let i = entries.iter().position(|(key, _)| key == last)
+                .ok_or_else(|| anyhow!("no member {:?}", last))?;
+            Ok(entries.remove(i).1)
+        }
+        Node::Seq(elements) => {
+            let i = index(last, elements.len())?;
+            Ok(elements.remove(i))
+        }
+        Node::Struct { .. } => Err(anyhow!("can't remove field {:?} from a struct", last).into()),
+        Node::Tuple(_) => Err(anyhow!("can't remove element {:?} from a tuple", last).into()),
+        _ => Err(anyhow!("no member {:?}", last).into()),
+    }
+}
+
+fn get<'a>(mut node: &'a mut Node, path: &[String]) -> Result<&'a mut Node> {
+    for token in path {
+        node = match node {
+            Node::Struct { fields: entries, .. } | Node::Map(entries) => {
+                entries.iter_mut().find(|(key, _)| key == token).map(|(_, v)| v)
+                    .ok_or_else(|| anyhow!("no member {:?}", token))?
+            }
+            Node::Seq(elements) | Node::Tuple(elements) => {
+                let i = index(token, elements.len())?;
+                &mut elements[i]
+            }
+            Node::Variant { variant, value: Some(value), .. } if variant == token => value,
+            _ => return Err(anyhow!("no member {:?}", token).into()),
+        };
+    }
+    Ok(node)
+}
+
+/// An array index below `len`, written without leading zeros.
+fn index(token: &str, len: usize) -> Result<usize> {
+    let valid = !token.is_empty() && token.bytes().all(|b| b.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    match token.parse() {
+        Ok(i) if valid && i < len => Ok(i),
+        _ => Err(anyhow!("no element {:?}", token).into()),
+    }
+}
+
+/// The reference tokens of a JSON Pointer (RFC 6901).
+fn tokens(pointer: &str) -> Result<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = pointer.strip_prefix('/').ok_or_else(|| anyhow!("invalid JSON Pointer {:?}", pointer))?;
+    Ok(rest.split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+/// The JSON Pointer for a path in the form `diff` writes.
+fn pointer(path: &str) -> Result<String> {
+    let mut pointer = String::new();
+    for segment in Path::parse(path)?.0 {
+        let token = match segment {
+            Segment::Key(key) => key,
+            Segment::Index(i) => i.to_string(),
+        };
+        pointer.push('/');
+        pointer.push_str(&token.replace('~', "~0").replace('/', "~1"));
+    }
+    Ok(pointer)
+}
@@ -0,0 +1,77 @@
+//! Where a value is inside a document.
+
+use anyhow::anyhow;
+use serde_json::Value;
+use std::fmt::{self, Display};
+
+use crate::error::{Result, StdResultExt};
+
+/// A step from a value to one inside it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    /// A struct field or map key.
+    Key(String),
+    /// A tuple or sequence element.
+    Index(usize),
+}
+
+/// Written like `.key`, or `["a key"]` for keys that aren't identifiers,
+/// and `[3]` for indices.
+impl Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Segment::Key(key) => {
+                let mut chars = key.chars();
+                let identifier = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if identifier {
+                    write!(f, ".{}", key)
+                } else {
+                    write!(f, "[{}]", Value::from(key.as_str()))
+                }
+            }
+            Segment::Index(i) => write!(f, "[{}]", i),
+        }
+    }
+}
+
+/// The steps from the root of a document to a value, such as
+/// `.users[3].name`. The root is the empty path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Path(pub Vec<Segment>);
+
+impl Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|segment| segment.fmt(f))
+    }
+}
+
+impl Path {
+    /// Parse a path as it is displayed.
+    pub fn parse(path: &str) -> Result<Path> {
+        let invalid = || anyhow!("invalid path {:?}", path);
+        let mut segments = Vec::new();
+        let mut rest = path;
+        while !rest.is_empty() {
+            let segment = if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                rest = &after[end..];
+                Segment::Key(after[..end].to_string())
+            } else if let Some(after) = rest.strip_prefix("[\"") {
+                // A quoted key, which is a JSON string
+                let mut strings = serde_json::Deserializer::from_str(&rest[1..]).into_iter::<String>();
+                let key = strings.next().ok_or_else(invalid)?.e()?;
+                rest = after[strings.byte_offset() - 1..].strip_prefix(']').ok_or_else(invalid)?;
+                Segment::Key(key)
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(invalid)?;
+                rest = &after[end + 1..];
+                Segment::Index(after[..end].parse().map_err(|_| invalid())?)
+            } else {
+                return Err(invalid().into());
+            };
+            segments.push(segment);
+        }
+        Ok(Path(segments))
+    }
+}
@@ -0,0 +1,249 @@
+//! Recovering files whose last commits are damaged or cut off.
+
+use anyhow::anyhow;
+use std::io::{Read, Write, SeekFrom};
+
+use crate::chain;
+use crate::codec::Codec;
+use crate::de::Deserializer;
+use crate::encryption::EncryptedBuffer;
+use crate::error::{Result, StdResultExt};
+use crate::meta::{self, Encoding, Trailer, MAGIC};
+use crate::state::{Buffer, Options, State};
+
+/// What `recover` does with the intact versions it finds.
+pub enum RecoveryMode {
+    /// Read them from the damaged file, which isn't modified.
+    ReadOnly,
+    /// Write a clean file holding just them to the given buffer, which
+    /// should be empty, and read them from it.
+    Repair(Box<dyn Buffer>),
+}
+
+/// What `recover` found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// The position of the last intact trailer, if any.
+    pub trailer_pos: Option<u64>,
+    /// The number of intact versions.
+    pub versions: usize,
+    /// The versions committed by trailers after the last intact one, which
+    /// are damaged.
+    pub lost: Vec<LostVersion>,
+    /// The number of bytes after the last intact trailer. This includes
+    /// commits that were cut off before their trailer was written, which
+    /// aren't listed in `lost`.
+    pub discarded: u64,
+}
+
+/// A version that can't be recovered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostVersion {
+    /// The version, counting from 1, found by following the trailers
+    /// before it, if they can be read.
+    pub version: Option<usize>,
+    pub trailer_pos: u64,
+    pub reason: String,
+}
+
+/// Find the last intact version of a file whose tail may be damaged,
+/// returning a deserializer for it.
+///
+/// Unlike opening the file normally, the file is searched backwards for
+/// trailers, not just read at its end, until one is found that is intact
+/// on its own, and the last intact version is then found by following the
+/// trailers before it. A trailer is intact if it, the trailers before it
+/// and the stitches and catalog they commit can all be read, and its hash
+/// chain holds, including signatures if `options` has a signing key. An encrypted file cut off between chunks is read too, though its
+/// last chunk wasn't stored as the last.
+pub fn recover(buf: impl Buffer, options: &Options, mode: RecoveryMode) -> Result<(Deserializer, Recovery)> {
+    let (mut buf, header, data_start) = State::open_damaged(Box::new(buf), options)?;
+    let encoding = header.encoding;
+    let len = buf.seek(SeekFrom::End(0)).e()?;
+
+    let mut recovery = Recovery::default();
+    let mut intact = None;
+    // Newest first
+    let mut lost = Vec::new();
+    let mut last_end = None;
+    let mut below = len;
+    'scan: while let Some(pos) = below.checked_sub(1).filter(|pos| *pos >= data_start) {
+        below = pos;
+        let trailer = match meta::read_trailer_at(&mut *buf, encoding, pos) {
+            Ok(trailer) if trailer.magic == MAGIC => trailer,
+            _ => continue,
+        };
+        // Leading whitespace decodes to the same trailer
+        let end = buf.stream_position().e()?;
+        if last_end == Some(end) {
+            continue;
+        }
+        last_end = Some(end);
+        // Only a trailer that is intact on its own is worth following the
+        // chain before it for
+        if let Err(e) = check_link(&mut *buf, &header, options, &trailer, pos) {
+            let version = meta::trailer_chain(&mut *buf, encoding, trailer, pos).ok().map(|chain| chain.len());
+            lost.push(LostVersion { version, trailer_pos: pos, reason: e.to_string() });
+            continue;
+        }
+
+        // Newest first, until it is complete
+        let mut chain = vec![(trailer, pos)];
+        while let Some(prev_pos) = chain[chain.len() - 1].0.prev_trailer_pos {
+            match read_prev_trailer(&mut *buf, encoding, prev_pos, chain[chain.len() - 1].1) {
+                Ok(prev) => chain.push((prev, prev_pos)),
+                Err(e) => {
+                    // Nothing after a trailer that can't be read is intact,
+                    // so look for one before it instead
+                    let reason = format!("trailer at {}: {}", prev_pos, e);
+                    lost.extend(chain.into_iter().map(|(_, trailer_pos)| {
+                        LostVersion { version: None, trailer_pos, reason: reason.clone() }
+                    }));
+                    below = prev_pos;
+                    last_end = None;
+                    continue 'scan;
+                }
+            }
+        }
+        chain.reverse();
+
+        let broken = find_broken(&mut *buf, &header, options, &chain)?;
+        let versions = broken.as_ref().map_or(chain.len(), |(i, _)| *i);
+        if let Some((_, reason)) = broken {
+            for (i, (_, trailer_pos)) in chain.iter().enumerate().skip(versions).rev() {
+                let reason = if i == versions {
+                    reason.clone()
+                } else {
+                    format!("version {} is damaged", versions + 1)
+                };
+                lost.push(LostVersion { version: Some(i + 1), trailer_pos: *trailer_pos, reason });
+            }
+        }
+        recovery.versions = versions;
+        intact = versions.checked_sub(1).map(|i| chain.swap_remove(i));
+        break;
+    }
+
+    let end = match &intact {
+        Some((trailer, pos)) => {
+            recovery.trailer_pos = Some(*pos);
+            trailer_end(&mut *buf, encoding, trailer, *pos)?
+        }
+        None => data_start.min(len),
+    };
+    recovery.discarded = len - end;
+    lost.reverse();
+    recovery.lost = lost;
+
+    let de = match mode {
+        RecoveryMode::ReadOnly => {
+            Deserializer::from_state(State::load_at(buf, options, header, data_start, intact)?)?
+        }
+        RecoveryMode::Repair(out) => {
+            let out = copy_prefix(&mut *buf, out, options, &header, data_start, end)?;
+            Deserializer::from_state(State::load(out, options)?)?
+        }
+    };
+    Ok((de, recovery))
+}
+
+/// Check what the trailer at `pos` commits and its own hashes, but not the
+/// trailers before it.
+fn check_link(buf: &mut dyn Buffer, header: &meta::Header, options: &Options, trailer: &Trailer, pos: u64) -> Result<()> {
+    read_link(&mut *buf, header.encoding, trailer, pos)?;
+    let key = options.signing_key.as_ref();
+    if let Some(reason) = chain::verify_trailer(&mut *buf, header.encoding, header.version, key, trailer, pos)? {
+        return Err(anyhow!("{}", reason).into());
+    }
+    Ok(())
+}
+
+/// Read what the trailer at `pos` commits.
+fn read_link(buf: &mut dyn Buffer, encoding: Encoding, trailer: &Trailer, pos: u64) -> Result<()> {
+    meta::read_catalog(&mut *buf, encoding, trailer)?;
+    meta::read_stitches(&mut *buf, encoding, trailer, pos)?;
+    meta::read_info(&mut *buf, encoding, trailer)?;
+    meta::read_paths(&mut *buf, encoding, trailer)?;
+    Ok(())
+}
+
+/// Read the trailer at `pos`, which the trailer at `next_pos` follows.
+fn read_prev_trailer(buf: &mut dyn Buffer, encoding: Encoding, pos: u64, next_pos: u64) -> Result<Trailer> {
+    if pos >= next_pos {
+        return Err(anyhow!("trailer at {} points forward to {}", next_pos, pos).into());
+    }
+    let trailer = meta::read_trailer_at(&mut *buf, encoding, pos)?;
+    if trailer.magic != MAGIC {
+        return Err(anyhow!("not a trailer").into());
+    }
+    Ok(trailer)
+}
+
+/// The index of the first trailer in `chain`, oldest first, that isn't
+/// intact, with what is wrong with it.
+fn find_broken(buf: &mut dyn Buffer, header: &meta::Header, options: &Options, chain: &[(Trailer, u64)]) -> Result<Option<(usize, String)>> {
+    let mut broken = None;
+    for (i, (trailer, pos)) in chain.iter().enumerate() {
+        if let Err(e) = read_link(&mut *buf, header.encoding, trailer, *pos) {
+            broken = Some((i, e.to_string()));
+            break;
+        }
+    }
+    let readable = broken.as_ref().map_or(chain.len(), |(i, _)| *i);
+    if let Some(last) = readable.checked_sub(1) {
+        let key = options.signing_key.as_ref();
+        if let Some(link) = chain::verify_chain(&mut *buf, header.encoding, header.version, key, Some(chain[last].1))? {
+            broken = Some((link.version - 1, link.reason));
+        }
+    }
+    Ok(broken)
+}
+
+/// Where the bytes written for the trailer at `pos` end, including any
+/// separator the encoding writes after it.
+fn trailer_end(buf: &mut dyn Buffer, encoding: Encoding, trailer: &Trailer, pos: u64) -> Result<u64> {
+    let mut encoded = Vec::new();
+    encoding.write_trailer(&mut encoded, trailer)?;
+    buf.seek(SeekFrom::Start(pos)).e()?;
+    let mut stored = Vec::new();
+    buf.take(encoded.len() as u64).read_to_end(&mut stored).e()?;
+    if stored == encoded {
+        return Ok(pos + encoded.len() as u64);
+    }
+    // Written differently, as by older versions
+    meta::read_trailer_at(&mut *buf, encoding, pos)?;
+    buf.stream_position().e()
+}
+
+/// Copy the first `end` bytes of `buf` to `out`, encrypting them the same
+/// way, and return `out`.
+fn copy_prefix(buf: &mut dyn Buffer, out: Box<dyn Buffer>, options: &Options, header: &meta::Header, data_start: u64, end: u64) -> Result<Box<dyn Buffer>> {
+    let mut out = match (&header.encryption, &options.key) {
+        (Some(_), Some(key)) => Copy::Encrypted(EncryptedBuffer::new(out, key, header, data_start)?),
+        _ => Copy::Plain(out),
+    };
+    buf.seek(SeekFrom::Start(0)).e()?;
+    let copied = std::io::copy(&mut buf.take(end), out.writer()).e()?;
+    if copied != end {
+        return Err(anyhow!("file ends before {}", end).into());
+    }
+    out.writer().flush().e()?;
+    Ok(match out {
+        Copy::Encrypted(out) => out.into_inner().e()?,
+        Copy::Plain(out) => out,
+    })
+}
+
+enum Copy {
+    Encrypted(EncryptedBuffer),
+    Plain(Box<dyn Buffer>),
+}
+
+impl Copy {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Copy::Encrypted(out) => out,
+            Copy::Plain(out) => out,
+        }
+    }
+}
@@ -1,16 +1,19 @@
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct SerializeBool {
     pub v: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct SerializeU8 {
     pub v: u8,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct SerializeTuple {
     pub len: usize,
 }
@@ -21,16 +24,138 @@ pub struct SerializeTupleElement;
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct SerializeTupleEnd;
 
-#[derive(Serialize)]
-pub struct SerializeStruct {
-    pub name: &'static str,
+#[derive(Serialize, Debug)]
+pub struct SerializeStruct<'a> {
+    pub name: &'a str,
     pub len: usize,
 }
 
-#[derive(Serialize)]
-pub struct SerializeStructField {
-    pub key: &'static str,
+#[derive(Serialize, Debug)]
+pub struct SerializeStructField<'a> {
+    pub key: &'a str,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct SerializeStructEnd;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerializeUnit;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeI64 {
+    pub i: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeU64 {
+    pub u: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeF64 {
+    pub f: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SerializeStr<'a> {
+    pub s: &'a str,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeSeq {
+    pub items: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerializeSeqElement;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerializeSeqEnd;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SerializeMap {
+    pub entries: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SerializeMapKey<'a> {
+    pub k: &'a str,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerializeMapEnd;
+
+/// A unit variant of an enum.
+#[derive(Serialize, Debug)]
+pub struct SerializeUnitVariant<'a> {
+    pub name: &'a str,
+    pub unit: &'a str,
+}
+
+/// Any other variant of an enum, followed by its value: the newtype's
+/// value, a tuple or a struct.
+#[derive(Serialize, Debug)]
+pub struct SerializeVariant<'a> {
+    pub name: &'a str,
+    pub variant: &'a str,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerializeVariantEnd;
+
+/// A value whose commands are compressed. See the `compression` module.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Compressed {
+    #[serde(with = "data")]
+    pub data: Vec<u8>,
+}
+
+/// Raw bytes, as hex in human-readable encodings.
+pub mod data {
+    use serde::{Serializer, Deserializer, Deserialize};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            s.serialize_str(&hex::encode(data))
+        } else {
+            s.serialize_bytes(data)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        if d.is_human_readable() {
+            hex::decode(String::deserialize(d)?).map_err(D::Error::custom)
+        } else {
+            byte_buf(d)
+        }
+    }
+
+    fn byte_buf<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("bytes")
+            }
+
+            fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(v)
+            }
+        }
+
+        d.deserialize_byte_buf(Visitor)
+    }
+}
@@ -1,41 +1,169 @@
 #![allow(unused)]
 
+use anyhow::anyhow;
 use serde::{ser, Serialize};
 
-use crate::error::{Error, Result, ResultExt, StdResultExt};
+use crate::error::{self, Error, Result, StdResultExt};
 use crate::{scmd, dcmd};
-use crate::state::{State, Buffer};
-use std::io::{self, SeekFrom};
-use crate::meta::{Stitch, Trailer, MAGIC};
+use crate::state::{State, Buffer, Options, DEFAULT_DOCUMENT};
+use std::collections::BTreeMap;
+use std::io::{self, SeekFrom, Write};
+use crate::meta::{self, Header, Stitch, StitchPaths, Trailer, Catalog, CommitInfo, Refs, MAGIC};
+use crate::chain;
+use crate::lock::{self, LockMode};
+use crate::history::{self, Version};
+use crate::node::Node;
+use crate::overlay::{Base, Overlay};
+use crate::shared::Shared;
+use crate::path::{Path, Segment};
 
 use crate::de::Deserializer;
 use serde::de::DeserializeOwned;
 
 pub struct Serializer {
     state: State,
+    /// The document being serialized.
+    document: String,
+    /// Whether the document has no old value to diff against.
+    fresh: bool,
+    /// Whether the document's root value has been serialized since the last
+    /// `reset`.
+    done: bool,
+    /// The values currently being serialized, innermost last.
+    frames: Vec<Frame>,
     first_stitch_pos: u64,
     new_stitches: u64,
-    last_trailer_pos: Option<u64>,
+    /// The last stitch written since the last commit, with its position.
+    /// Its `next_stitch_pos` is updated when another stitch or the trailer
+    /// follows it.
+    last_stitch: Option<(u64, Stitch)>,
+    /// Whether any documents were created since the last commit.
+    new_documents: bool,
+    /// Where the value being serialized is in the document.
+    path: Path,
+    /// The stitches written since the last commit, in order.
+    stitch_changes: Vec<StitchChange>,
+    /// Tags to record with the next commit, with the trailers they point
+    /// at.
+    new_tags: BTreeMap<String, u64>,
+    /// Whether the branch has no commits yet, so the next commit creates
+    /// it.
+    new_branch: bool,
+    /// The buffer, once snapshots share it.
+    shared: Option<Base>,
+}
+
+/// What serializing a value would change, as reported by
+/// `Serializer::dry_run`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    /// The stitches that would be written, in order.
+    pub stitches: Vec<StitchChange>,
+    /// Whether the document doesn't exist yet, so would be written whole.
+    pub created: bool,
+    /// The bytes that would be written, not counting the commit.
+    pub size: u64,
+}
+
+impl ChangeSet {
+    /// Whether serializing the value would leave the document as it is.
+    pub fn is_empty(&self) -> bool {
+        self.stitches.is_empty() && !self.created
+    }
+}
+
+/// A stitch that would be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StitchChange {
+    /// Where the replaced value is in the document, such as
+    /// `.users[3].name`.
+    pub path: String,
+    /// Position of the replaced value.
+    pub old_pos: u64,
+    /// The size of the stitch with the value replacing the old one.
+    pub size: u64,
+}
+
+enum Frame {
+    /// A value whose first command matched the old one at `old_pos`, as
+    /// have `items` of the commands inside it so far. If the old value was
+    /// reached through a stitch, reading resumes at `resume` afterwards.
+    Same { old_pos: u64, items: usize, resume: Option<u64> },
+    /// A value written as the payload of a stitch replacing the old value.
+    Stitch { stitch_pos: u64, old_pos: u64, new_pos: u64, resume: u64 },
+    /// A value replacing an old compressed value, `old`. It is replaced by
+    /// a stitch if its commands differ.
+    Block { old_pos: u64, resume: u64, old: Vec<u8> },
+    /// A value inside new data, with nothing to compare against. It is
+    /// compressed, if at all, with the stitch payload or document it is in.
+    New,
 }
 
 impl Serializer {
     pub fn new(buf: impl Buffer) -> Result<Serializer> {
-        Serializer::from_state(State {
-            buf: Box::new(buf),
-        })
+        Serializer::with_options(buf, Options::default())
+    }
+
+    /// Open `buf` for writing, creating a file with the given options if it
+    /// is empty.
+    pub fn with_options(buf: impl Buffer, options: Options) -> Result<Serializer> {
+        Serializer::from_state(State::load(Box::new(buf), &options)?)
+    }
+
+    /// Open the file at `path` for writing, creating it if it doesn't exist,
+    /// and lock it until the serializer is dropped. Waits for the lock if
+    /// someone else holds it.
+    pub fn open_file(path: impl AsRef<std::path::Path>, mode: LockMode) -> Result<Serializer> {
+        Serializer::open_file_with_options(path, mode, Options::default())
+    }
+
+    /// Open the file at `path` like `open_file`, with the given options.
+    pub fn open_file_with_options(path: impl AsRef<std::path::Path>, mode: LockMode, options: Options) -> Result<Serializer> {
+        Serializer::with_options(lock::open(path.as_ref(), true, mode, true)?, options)
+    }
+
+    /// Open the file at `path` like `open_file`, but fail if the lock is
+    /// held by someone else, with an error for which `is_would_block` is
+    /// true.
+    pub fn try_open_file(path: impl AsRef<std::path::Path>, mode: LockMode) -> Result<Serializer> {
+        Serializer::try_open_file_with_options(path, mode, Options::default())
+    }
+
+    /// Open the file at `path` like `try_open_file`, with the given options.
+    pub fn try_open_file_with_options(path: impl AsRef<std::path::Path>, mode: LockMode, options: Options) -> Result<Serializer> {
+        Serializer::with_options(lock::open(path.as_ref(), true, mode, false)?, options)
     }
 
     pub fn from_state(state: State) -> Result<Serializer> {
         let mut v = Serializer {
             state,
+            document: DEFAULT_DOCUMENT.to_string(),
+            fresh: false,
+            done: false,
+            frames: Vec::new(),
             first_stitch_pos: 0,
             new_stitches: 0,
-            last_trailer_pos: None,
+            last_stitch: None,
+            new_documents: false,
+            path: Path::default(),
+            stitch_changes: Vec::new(),
+            new_tags: BTreeMap::new(),
+            new_branch: false,
+            shared: None,
         };
+        if v.state.buf.seek(SeekFrom::End(0)).e()? == 0 {
+            meta::write_header(&mut *v.state.buf, &v.state.header)?;
+            v.state.len = v.state.buf.stream_position().e()?;
+        }
         v.reset()?;
         Ok(v)
     }
 
+    /// The header of the file being written.
+    pub fn header(&self) -> &Header {
+        &self.state.header
+    }
+
     pub fn to_state(self) -> State {
         self.state
     }
@@ -44,62 +172,815 @@ impl Serializer {
         Deserializer::from_state(self.to_state())
     }
 
+    /// A deserializer reading the last commit, which it goes on reading
+    /// while this serializer writes and commits more. Only what that commit
+    /// wrote is read, so nothing written since is seen, even half written.
+    ///
+    /// The two share the buffer, so they can be used from different
+    /// threads, and a file stays open, and locked, until both are dropped.
+    pub fn snapshot(&mut self) -> Result<Deserializer> {
+        let base = match &self.shared {
+            Some(base) => base.clone(),
+            None => Shared::install(&mut self.state.buf).e()?,
+        };
+        self.shared = Some(base.clone());
+        let state = &mut self.state;
+        let trailer = match state.trailer_pos {
+            Some(pos) => {
+                let resume = state.buf.stream_position().e()?;
+                let trailer = meta::read_trailer_at(&mut *state.buf, state.header.encoding, pos);
+                state.buf.seek(SeekFrom::Start(resume)).e()?;
+                Some((trailer?, pos))
+            }
+            None => None,
+        };
+        let buf = Box::new(Shared::handle(&base));
+        let mut snapshot = State::load_at(buf, &state.options, state.header.clone(), state.data_start, trailer)?;
+        snapshot.branch = state.branch.clone();
+        snapshot.refs = state.refs.clone();
+        Deserializer::from_state(snapshot)
+    }
+
+    /// Start a new pass over the default document.
+    ///
+    /// Changes written since the last `finalize` stay pending and are
+    /// diffed against, so they are committed by the next `finalize`. Fails
+    /// with a `Conflict` if someone else committed to the file since it was
+    /// read, after which `reload` reads it again.
     pub fn reset(&mut self) -> Result<()> {
-        let end_pos = self.state.buf.seek(SeekFrom::End(0)).e()?;
-        self.state.buf.seek(SeekFrom::Start(0)).e()?;
-        self.first_stitch_pos = end_pos;
+        self.frames.clear();
+        self.state.check_conflict()?;
+        self.select(DEFAULT_DOCUMENT)
+    }
+
+    /// Read the file again as of the head of the branch, dropping anything
+    /// written since the last `finalize`, so a write that failed with a
+    /// `Conflict` can be retried.
+    pub fn reload(&mut self) -> Result<()> {
+        self.frames.clear();
+        let state = &mut self.state;
+        let encoding = state.header.encoding;
+        state.conflict = None;
+        // An encrypted buffer fails when it first finds what someone else
+        // wrote, dropping its own unstored changes, and is fine after
+        state.len = match state.buf.seek(SeekFrom::End(0)) {
+            Err(e) if error::is_io_conflict(&e) => state.buf.seek(SeekFrom::End(0)).e()?,
+            len => len.e()?,
+        };
+        state.refs = meta::find_refs(&mut *state.buf, encoding, state.data_start)?;
+        state.last_trailer = state.refs.heads.get(&state.refs.branch).copied();
+        let head = meta::read_head(&mut *state.buf, encoding, &state.refs, &state.branch)?;
+        state.reload(head)?;
+        state.captures.clear();
         self.new_stitches = 0;
+        self.stitch_changes.clear();
+        self.last_stitch = None;
+        self.new_documents = false;
+        self.new_tags.clear();
+        self.new_branch = false;
+        self.select(DEFAULT_DOCUMENT)
+    }
+
+    /// Select the named document, creating it if it doesn't exist, and
+    /// return the serializer for writing it.
+    ///
+    /// All documents written before a `finalize` are committed together.
+    pub fn document(&mut self, name: &str) -> Result<&mut Serializer> {
+        self.select(name)?;
+        Ok(self)
+    }
+
+    /// The name of the selected document.
+    pub fn selected_document(&self) -> &str {
+        &self.document
+    }
+
+    /// The position of the last trailer committed, which changes with
+    /// every commit.
+    pub(crate) fn last_commit(&self) -> Option<u64> {
+        self.state.trailer_pos
+    }
+
+    /// Every committed version, oldest first. The selected document is
+    /// selected again, ready to be serialized.
+    pub fn versions(&mut self) -> Result<Vec<Version>> {
+        if !self.frames.is_empty() {
+            return Err(anyhow!("can't read versions in the middle of a value").into());
+        }
+        let versions = history::versions(&mut self.state)?;
+        let document = self.document.clone();
+        self.select(&document)?;
+        Ok(versions)
+    }
+
+    /// The names of all documents, including uncommitted ones.
+    pub fn documents(&self) -> impl Iterator<Item = &str> {
+        self.state.documents.keys().map(String::as_str)
+    }
+
+    fn select(&mut self, name: &str) -> Result<()> {
+        if !self.frames.is_empty() {
+            return Err(anyhow!("can't select a document in the middle of a value").into());
+        }
+        self.document = name.to_string();
+        self.done = false;
+        self.path.0.clear();
+        self.fresh = !self.state.documents.contains_key(name);
+        if self.fresh {
+            self.state.buf.seek(SeekFrom::End(0)).e()?;
+        } else {
+            self.state.seek_document(name)?;
+        }
         Ok(())
     }
 
     fn write(&mut self, v: impl Serialize) -> Result<()> {
-        serde_json::to_writer_pretty(&mut self.state.buf, &v).e()?;
-        writeln!(&mut self.state.buf).e()?;
-        Ok(())
+        self.state.write(v)
     }
 
     fn read<T: DeserializeOwned>(&mut self) -> Result<T> {
-        let mut de = serde_json::Deserializer::from_reader(&mut self.state.buf);
-        let t = T::deserialize(&mut de).e()?;
-        Ok(t)
+        self.state.read()
     }
 
-    pub fn finalize(&mut self) -> Result<()> {
-        if self.new_stitches == 0 {
-            if self.last_trailer_pos.is_some() {
-                // No new data written
+    fn writing_new(&self) -> bool {
+        match self.frames.last() {
+            Some(Frame::Same { .. }) => false,
+            Some(_) => true,
+            None => self.fresh,
+        }
+    }
+
+    /// Start a value whose first command is `newcmd`.
+    ///
+    /// When there is an old value it is read as an `O` and compared using
+    /// `same`. If they differ, or the old value is of a different kind, the
+    /// whole old value is replaced by a stitch.
+    fn begin<O>(&mut self, newcmd: impl Serialize, same: impl FnOnce(&O) -> bool) -> Result<()>
+    where O: DeserializeOwned,
+    {
+        if self.frames.is_empty() {
+            if self.done {
+                return Err(anyhow!("document {:?} was already serialized; call `reset` first", self.document).into());
+            }
+            if self.fresh {
+                let root = self.state.append()?;
+                self.state.documents.insert(self.document.clone(), root);
+                self.new_documents = true;
+            }
+        }
+        if self.writing_new() {
+            self.write(newcmd)?;
+            self.frames.push(Frame::New);
+            return Ok(());
+        }
+        let resume = self.state.enter_value()?;
+        let old_pos = self.state.pos()?;
+        if let Some(old) = self.state.read_compressed()? {
+            let resume = match resume {
+                Some(resume) => resume,
+                None => self.state.pos()?,
+            };
+            self.state.start_capture();
+            self.write(newcmd)?;
+            self.frames.push(Frame::Block { old_pos, resume, old });
+            return Ok(());
+        }
+        if let Ok(oldcmd) = self.read::<O>() {
+            if same(&oldcmd) {
+                self.frames.push(Frame::Same { old_pos, items: 0, resume });
+                return Ok(());
+            }
+        }
+        let resume = match resume {
+            Some(resume) => resume,
+            None => {
+                self.state.seek(old_pos)?;
+                self.state.skip_value()?;
+                self.state.pos()?
+            }
+        };
+        let (stitch_pos, new_pos) = self.begin_stitch(old_pos)?;
+        self.state.start_capture();
+        self.write(newcmd)?;
+        self.frames.push(Frame::Stitch { stitch_pos, old_pos, new_pos, resume });
+        Ok(())
+    }
+
+    /// Write or check a command inside a compound value. These can't be
+    /// stitched on their own, so if one doesn't match the old value's, as
+    /// when a struct skips different fields, the whole compound value is
+    /// replaced by a stitch.
+    fn item<O>(&mut self, newcmd: impl Serialize, same: impl FnOnce(&O) -> bool) -> Result<()>
+    where O: DeserializeOwned,
+    {
+        if self.writing_new() {
+            return self.write(newcmd);
+        }
+        if let Ok(oldcmd) = self.read::<O>() {
+            if same(&oldcmd) {
+                if let Some(Frame::Same { items, .. }) = self.frames.last_mut() {
+                    *items += 1;
+                }
                 return Ok(());
             }
         }
-        self.state.buf.seek(SeekFrom::End(0)).e()?;
+        self.restitch()?;
+        self.write(newcmd)
+    }
+
+    /// Replace the value of the innermost frame by a stitch, once its
+    /// layout turns out to differ from the old value's. What was serialized
+    /// of it so far matched, so it is copied from the old value as it now
+    /// reads, with any changes already stitched inside it.
+    fn restitch(&mut self) -> Result<()> {
+        let (old_pos, items, resume) = match self.frames.pop() {
+            Some(Frame::Same { old_pos, items, resume }) => (old_pos, items, resume),
+            _ => unreachable!("only values compared with an old one are restitched"),
+        };
+        self.state.seek(old_pos)?;
+        let old = self.state.read_node()?;
+        let resume = match resume {
+            Some(resume) => resume,
+            None => {
+                self.state.seek(old_pos)?;
+                self.state.skip_value()?;
+                self.state.pos()?
+            }
+        };
+        let (stitch_pos, new_pos) = self.begin_stitch(old_pos)?;
+        self.state.start_capture();
+        self.frames.push(Frame::Stitch { stitch_pos, old_pos, new_pos, resume });
+        self.write_start(&old, items)
+    }
+
+    /// Write the first command of a compound value and its first `items`
+    /// items, each with the value that follows it. A variant's value comes
+    /// before its only item, so it is always written.
+    fn write_start(&mut self, node: &Node, items: usize) -> Result<()> {
+        match node {
+            Node::Seq(elements) => {
+                self.write(scmd::SerializeSeq { items: elements.len() })?;
+                for element in &elements[..items] {
+                    self.write(scmd::SerializeSeqElement)?;
+                    self.write_node(element)?;
+                }
+            }
+            Node::Tuple(elements) => {
+                self.write(scmd::SerializeTuple { len: elements.len() })?;
+                for element in &elements[..items] {
+                    self.write(scmd::SerializeTupleElement)?;
+                    self.write_node(element)?;
+                }
+            }
+            Node::Struct { name, fields } => {
+                self.write(scmd::SerializeStruct { name, len: fields.len() })?;
+                for (key, value) in &fields[..items] {
+                    self.write(scmd::SerializeStructField { key })?;
+                    self.write_node(value)?;
+                }
+            }
+            Node::Map(entries) => {
+                self.write(scmd::SerializeMap { entries: entries.len() })?;
+                for (k, value) in &entries[..items] {
+                    self.write(scmd::SerializeMapKey { k })?;
+                    self.write_node(value)?;
+                }
+            }
+            Node::Variant { name, variant, value: Some(value) } => {
+                self.write(scmd::SerializeVariant { name, variant })?;
+                self.write_node(value)?;
+            }
+            _ => unreachable!("only compound values have items"),
+        }
+        Ok(())
+    }
+
+    /// Finish the value started by the last `begin`.
+    fn end(&mut self) -> Result<()> {
+        match self.frames.pop().expect("unbalanced value") {
+            Frame::Same { resume, .. } => {
+                if let Some(resume) = resume {
+                    self.state.seek(resume)?;
+                }
+            }
+            Frame::Stitch { stitch_pos, old_pos, new_pos, resume } => {
+                if self.state.header.compression.is_some() {
+                    self.state.end_capture()?;
+                }
+                self.end_stitch(stitch_pos, old_pos, new_pos)?;
+                self.state.seek(resume)?;
+            }
+            Frame::Block { old_pos, resume, old } => {
+                let new = self.state.captures.pop().expect("unbalanced capture");
+                if new != old {
+                    let (stitch_pos, new_pos) = self.begin_stitch(old_pos)?;
+                    self.state.write_compressible(new)?;
+                    self.end_stitch(stitch_pos, old_pos, new_pos)?;
+                }
+                self.state.seek(resume)?;
+            }
+            Frame::New => {}
+        }
+        if self.frames.is_empty() {
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    /// Start an enum variant that has a value, which is serialized next,
+    /// inside it.
+    fn begin_variant(&mut self, name: &str, variant: &str) -> Result<()> {
+        let newcmd = scmd::SerializeVariant { name, variant };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeVariant| {
+            oldcmd.name == name && oldcmd.variant == variant
+        })?;
+        self.path.0.push(Segment::Key(variant.to_string()));
+        Ok(())
+    }
+
+    /// Finish the variant started by the last `begin_variant`, once its
+    /// value is serialized.
+    fn end_variant(&mut self) -> Result<()> {
+        self.path.0.pop();
+        self.item(scmd::SerializeVariantEnd, |_: &dcmd::SerializeVariantEnd| true)?;
+        self.end()
+    }
+
+    /// Move the path on to the next element of a tuple or sequence.
+    fn next_element(&mut self) {
+        if let Some(Segment::Index(i)) = self.path.0.last_mut() {
+            *i += 1;
+        }
+    }
+
+    /// Write a placeholder stitch at the end of the stream, returning its
+    /// position and the position of its payload.
+    fn begin_stitch(&mut self, old_pos: u64) -> Result<(u64, u64)> {
+        // Link the previous stitch to this one
+        let stitch_pos = self.state.append()?;
+        self.link_last_stitch(stitch_pos)?;
+        // Write a placeholder stitch
+        self.state.seek(stitch_pos)?;
+        let new_pos = 0;
+        let next_stitch_pos = 0;
+        let tmp_stitch = Stitch { old_pos, new_pos, next_stitch_pos };
+        self.state.write_stitch(tmp_stitch)?;
+        let new_pos = self.state.pos()?;
+        Ok((stitch_pos, new_pos))
+    }
+
+    /// Rewrite the real stitch once its payload has been written.
+    fn end_stitch(&mut self, stitch_pos: u64, old_pos: u64, new_pos: u64) -> Result<()> {
+        let next_stitch_pos = self.state.pos()?;
+        let stitch = Stitch { old_pos, new_pos, next_stitch_pos };
+        self.rewrite_stitch(stitch_pos, stitch)?;
+        self.state.stitches.insert(old_pos, new_pos);
+        if self.new_stitches == 0 {
+            self.first_stitch_pos = stitch_pos;
+        }
+        self.new_stitches += 1;
+        self.last_stitch = Some((stitch_pos, stitch));
+        self.stitch_changes.push(StitchChange {
+            path: self.path.to_string(),
+            old_pos,
+            size: next_stitch_pos - stitch_pos,
+        });
+        Ok(())
+    }
+
+    fn link_last_stitch(&mut self, next_stitch_pos: u64) -> Result<()> {
+        if let Some((stitch_pos, mut stitch)) = self.last_stitch {
+            if stitch.next_stitch_pos != next_stitch_pos {
+                stitch.next_stitch_pos = next_stitch_pos;
+                self.rewrite_stitch(stitch_pos, stitch)?;
+                self.last_stitch = Some((stitch_pos, stitch));
+            }
+        }
+        Ok(())
+    }
+
+    fn rewrite_stitch(&mut self, stitch_pos: u64, stitch: Stitch) -> Result<()> {
+        self.state.seek(stitch_pos)?;
+        self.state.write_stitch(stitch)?;
+        // Verify the stitch size
+        let new_pos = self.state.pos()?;
+        assert_eq!(new_pos, stitch.new_pos);
+        Ok(())
+    }
+
+    /// Commit everything written since the last commit.
+    ///
+    /// Fails with a `Conflict` if someone else committed to the file since
+    /// it was read.
+    pub fn finalize(&mut self) -> Result<()> {
+        self.finalize_with(&CommitInfo::default())
+    }
+
+    /// Commit like `finalize`, recording `info` with the commit. A commit
+    /// is made even if nothing changed, unless `info` is empty.
+    pub fn finalize_with(&mut self, info: &CommitInfo) -> Result<()> {
+        if !self.frames.is_empty() {
+            return Err(anyhow!("can't finalize in the middle of a value").into());
+        }
+        if self.new_stitches == 0 && !self.new_documents && self.state.trailer_pos.is_some() && info.is_empty()
+            && self.new_tags.is_empty() && !self.new_branch {
+            // No new data written
+            return Ok(());
+        }
+        self.state.append()?;
+        let encoding = self.state.header.encoding;
+        let mut refs = self.state.refs.clone();
+        refs.heads.remove(&self.state.branch);
+        refs.branch = self.state.branch.clone();
+        refs.tags.extend(std::mem::take(&mut self.new_tags));
+        let catalog = Catalog {
+            documents: self.state.documents.clone(),
+        };
+        let catalog_pos = if catalog.is_implicit() {
+            None
+        } else if self.new_documents || self.state.trailer_pos.is_none() {
+            let catalog_pos = self.state.pos()?;
+            self.write(catalog)?;
+            Some(catalog_pos)
+        } else {
+            self.state.catalog_pos
+        };
+        let paths_pos = if self.stitch_changes.is_empty() {
+            None
+        } else {
+            let paths_pos = self.state.pos()?;
+            let paths = self.stitch_changes.iter().map(|stitch| stitch.path.clone()).collect();
+            self.write(StitchPaths { paths })?;
+            Some(paths_pos)
+        };
+        let info_pos = if info.is_empty() {
+            None
+        } else {
+            let info_pos = self.state.pos()?;
+            self.write(info)?;
+            Some(info_pos)
+        };
+        let refs_pos = if refs.is_implicit() {
+            None
+        } else {
+            let refs_pos = self.state.pos()?;
+            self.write(&refs)?;
+            Some(refs_pos)
+        };
+        let trailer_pos = self.state.pos()?;
+        self.link_last_stitch(trailer_pos)?;
         let first_stitch = if self.new_stitches != 0 {
             Some(self.first_stitch_pos)
         } else {
             None
         };
-        let trailer = Trailer {
+        let (prev_hash, data_hash) = chain::link_hashes(&mut *self.state.buf, encoding,
+                                                        self.state.trailer_pos, trailer_pos)?;
+        let mut trailer = Trailer {
             magic: MAGIC,
             first_stitch,
-            prev_trailer_pos: self.last_trailer_pos,
+            prev_trailer_pos: self.state.trailer_pos,
+            catalog: catalog_pos,
+            prev_hash,
+            data_hash: Some(data_hash),
+            signature: None,
+            info: info_pos,
+            paths: paths_pos,
+            refs: refs_pos,
         };
-        println!("{:?}", trailer);
-        self.write(trailer)?;
+        if let Some(key) = &self.state.options.signing_key {
+            trailer.signature = Some(chain::sign(&trailer, encoding, key)?);
+        }
+        self.state.seek(trailer_pos)?;
+        self.state.write_trailer(&trailer)?;
+        self.state.buf.flush().e()?;
+        self.state.trailer_pos = Some(trailer_pos);
+        self.state.last_trailer = Some(trailer_pos);
+        self.state.catalog_pos = catalog_pos;
+        refs.heads.insert(refs.branch.clone(), trailer_pos);
+        self.state.refs = refs;
+        self.new_stitches = 0;
+        self.stitch_changes.clear();
+        self.last_stitch = None;
+        self.new_documents = false;
+        self.new_branch = false;
+        Ok(())
+    }
+
+    /// Tag the last commit on the branch. The tag is recorded in the file
+    /// by a commit that changes nothing else.
+    pub fn tag(&mut self, name: &str) -> Result<()> {
+        self.check_committed("tag")?;
+        let trailer_pos = self.state.trailer_pos.ok_or_else(|| anyhow!("there is no commit to tag"))?;
+        if self.state.refs.tags.contains_key(name) {
+            return Err(anyhow!("tag {:?} already exists", name).into());
+        }
+        self.new_tags.insert(name.to_string(), trailer_pos);
+        self.finalize()
+    }
+
+    /// Start a branch at the given tag and switch to it. The branch is
+    /// recorded in the file by a commit that changes nothing else, whose
+    /// parent is the tagged commit.
+    ///
+    /// Committing to the branch leaves the branch the tag is on as it was.
+    /// If the commit fails, such as with a `Conflict`, the serializer stays
+    /// on the branch it was on.
+    pub fn branch(&mut self, name: &str, tag: &str) -> Result<()> {
+        self.check_committed("branch")?;
+        if self.state.refs.heads.contains_key(name) {
+            return Err(anyhow!("branch {:?} already exists", name).into());
+        }
+        let pos = *self.state.refs.tags.get(tag).ok_or_else(|| anyhow!("no tag named {:?}", tag))?;
+        let encoding = self.state.header.encoding;
+        let trailer = meta::read_trailer_at(&mut *self.state.buf, encoding, pos)?;
+        let (branch, head) = (self.state.branch.clone(), self.state.trailer_pos);
+        self.state.reload(Some((trailer, pos)))?;
+        self.state.branch = name.to_string();
+        self.new_branch = true;
+        let document = self.document.clone();
+        let result = self.select(&document).and_then(|()| self.finalize());
+        if result.is_err() {
+            // The new branch isn't in the file, so go back to the old head
+            self.new_branch = false;
+            self.state.branch = branch;
+            let head = head.map(|pos| meta::read_trailer_at(&mut *self.state.buf, encoding, pos).map(|t| (t, pos)));
+            self.state.reload(head.transpose()?)?;
+            self.select(&document)?;
+        }
+        result
+    }
+
+    /// Switch to the head of the named branch, selecting the selected
+    /// document again.
+    pub fn checkout(&mut self, branch: &str) -> Result<()> {
+        self.check_committed("check out a branch")?;
+        self.state.checkout(branch)?;
+        let document = self.document.clone();
+        self.select(&document)
+    }
+
+    /// The name of the branch being written.
+    pub fn selected_branch(&self) -> &str {
+        &self.state.branch
+    }
+
+    /// The branches and tags in the file, with the trailers they point at.
+    pub fn refs(&self) -> &Refs {
+        &self.state.refs
+    }
+
+    fn check_committed(&self, action: &str) -> Result<()> {
+        if !self.frames.is_empty() {
+            return Err(anyhow!("can't {} in the middle of a value", action).into());
+        }
+        if self.new_stitches != 0 || self.new_documents {
+            return Err(anyhow!("can't {} with uncommitted changes", action).into());
+        }
         Ok(())
     }
 
+    /// Report what serializing `value` as the selected document would
+    /// change, without writing anything.
+    ///
+    /// The value is compared with the document just as serializing it
+    /// would, including changes pending since the last `finalize`, with
+    /// the stitches it needs written to memory instead of the file. The
+    /// document is selected again afterwards, ready to be serialized.
+    pub fn dry_run<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<ChangeSet> {
+        if !self.frames.is_empty() {
+            return Err(anyhow!("can't start a dry run in the middle of a value").into());
+        }
+        let document = self.document.clone();
+        let documents = self.state.documents.clone();
+        let stitches = self.state.stitches.clone();
+        let (first_stitch_pos, new_stitches) = (self.first_stitch_pos, self.new_stitches);
+        let len = self.state.len;
+        let base = Overlay::install(&mut self.state.buf).e()?;
+        // Stitches pending before are left unlinked
+        let (last_stitch, new_documents) = (self.last_stitch.take(), self.new_documents);
+        let start = self.state.buf.seek(SeekFrom::End(0)).e()?;
+
+        let created = !self.state.documents.contains_key(&document);
+        let result = self.select(&document).and_then(|()| value.serialize(&mut *self));
+        let end = self.state.buf.seek(SeekFrom::End(0)).e();
+
+        Overlay::remove(&mut self.state.buf, base);
+        let log = self.stitch_changes.split_off(new_stitches as usize);
+        self.frames.clear();
+        self.state.captures.clear();
+        self.state.blocks.clear();
+        self.state.documents = documents;
+        self.state.stitches = stitches;
+        self.state.len = len;
+        self.first_stitch_pos = first_stitch_pos;
+        self.new_stitches = new_stitches;
+        self.last_stitch = last_stitch;
+        self.new_documents = new_documents;
+        self.select(&document)?;
+
+        result?;
+        Ok(ChangeSet {
+            stitches: log,
+            created,
+            size: end? - start,
+        })
+    }
+
+    /// Make the selected document what it was in the given version,
+    /// counting from 1, and commit it with `finalize`, along with anything
+    /// else pending.
+    ///
+    /// The old value is read without knowing its type and written over the
+    /// current one like any other, so only what differs is stitched and
+    /// earlier versions stay as they were.
+    pub fn revert_to(&mut self, version: usize) -> Result<()> {
+        self.revert_to_with(version, &CommitInfo::default())
+    }
+
+    /// Revert like `revert_to`, recording `info` with the commit, which is
+    /// made even if nothing changed unless `info` is empty.
+    pub fn revert_to_with(&mut self, version: usize, info: &CommitInfo) -> Result<()> {
+        if !self.frames.is_empty() {
+            return Err(anyhow!("can't revert in the middle of a value").into());
+        }
+        let node = self.read_version(version)?;
+        let document = self.document.clone();
+        self.select(&document)?;
+        self.write_node(&node)?;
+        self.finalize_with(info)
+    }
+
+    /// The selected document as it was committed in the given version.
+    fn read_version(&mut self, version: usize) -> Result<Node> {
+        self.with_state(|state, document| {
+            let trailer = history::trailer(state, version)?;
+            state.reload(Some(trailer))?;
+            if !state.documents.contains_key(document) {
+                return Err(anyhow!("no document named {:?} in version {}", document, version).into());
+            }
+            state.seek_document(document)?;
+            state.read_node()
+        })
+    }
+
+    /// Call `f` with the state and the name of the selected document, then
+    /// undo any versions `f` loaded and select the document again.
+    pub(crate) fn with_state<T>(&mut self, f: impl FnOnce(&mut State, &str) -> Result<T>) -> Result<T> {
+        if !self.frames.is_empty() {
+            return Err(anyhow!("can't read other versions in the middle of a value").into());
+        }
+        let state = &mut self.state;
+        let documents = state.documents.clone();
+        let stitches = state.stitches.clone();
+        let (trailer_pos, catalog_pos) = (state.trailer_pos, state.catalog_pos);
+
+        let result = f(state, &self.document);
+
+        state.documents = documents;
+        state.stitches = stitches;
+        state.trailer_pos = trailer_pos;
+        state.catalog_pos = catalog_pos;
+        state.blocks.clear();
+        let document = self.document.clone();
+        self.select(&document)?;
+        result
+    }
+
+    /// The selected document as written so far, including uncommitted
+    /// changes, or `None` if it doesn't exist yet. Selects the document
+    /// again, so it can be rewritten.
+    pub(crate) fn read_document(&mut self) -> Result<Option<Node>> {
+        let document = self.document.clone();
+        self.select(&document)?;
+        if self.fresh {
+            return Ok(None);
+        }
+        let node = self.state.read_node()?;
+        self.select(&document)?;
+        Ok(Some(node))
+    }
+
+    /// Serialize a value read without knowing its type, as from another
+    /// file.
+    pub(crate) fn write_node(&mut self, node: &Node) -> Result<()> {
+        match node {
+            Node::Unit => ser::Serializer::serialize_unit(self),
+            Node::Bool(v) => ser::Serializer::serialize_bool(self, *v),
+            Node::U8(v) => ser::Serializer::serialize_u8(self, *v),
+            Node::I64(v) => ser::Serializer::serialize_i64(self, *v),
+            Node::U64(v) => ser::Serializer::serialize_u64(self, *v),
+            Node::F64(v) => ser::Serializer::serialize_f64(self, *v),
+            Node::Str(v) => ser::Serializer::serialize_str(self, v),
+            Node::Seq(elements) => {
+                let newcmd = scmd::SerializeSeq { items: elements.len() };
+                self.begin(&newcmd, |oldcmd: &dcmd::SerializeSeq| *oldcmd == newcmd)?;
+                for (i, element) in elements.iter().enumerate() {
+                    self.item(scmd::SerializeSeqElement, |_: &dcmd::SerializeSeqElement| true)?;
+                    self.path.0.push(Segment::Index(i));
+                    self.write_node(element).map_err(|e| e.at(&self.path))?;
+                    self.path.0.pop();
+                }
+                self.item(scmd::SerializeSeqEnd, |_: &dcmd::SerializeSeqEnd| true)?;
+                self.end()
+            }
+            Node::Map(entries) => {
+                // Entries can't be stitched on their own, so a map whose
+                // keys changed is replaced
+                let same_keys = self.writing_new() || {
+                    let old_keys = self.state.map_keys()?;
+                    old_keys.is_some_and(|old| old.iter().eq(entries.iter().map(|(k, _)| k)))
+                };
+                let newcmd = scmd::SerializeMap { entries: entries.len() };
+                self.begin(&newcmd, |oldcmd: &dcmd::SerializeMap| same_keys && *oldcmd == newcmd)?;
+                for (k, value) in entries {
+                    let newcmd = scmd::SerializeMapKey { k };
+                    self.item(newcmd, |oldcmd: &dcmd::SerializeMapKey| oldcmd.k == *k)?;
+                    self.path.0.push(Segment::Key(k.clone()));
+                    self.write_node(value).map_err(|e| e.at(&self.path))?;
+                    self.path.0.pop();
+                }
+                self.item(scmd::SerializeMapEnd, |_: &dcmd::SerializeMapEnd| true)?;
+                self.end()
+            }
+            Node::Tuple(elements) => {
+                let len = elements.len();
+                let newcmd = scmd::SerializeTuple { len };
+                self.begin(&newcmd, |oldcmd: &dcmd::SerializeTuple| *oldcmd == newcmd)?;
+                for (i, element) in elements.iter().enumerate() {
+                    self.item(scmd::SerializeTupleElement, |_: &dcmd::SerializeTupleElement| true)?;
+                    self.path.0.push(Segment::Index(i));
+                    self.write_node(element).map_err(|e| e.at(&self.path))?;
+                    self.path.0.pop();
+                }
+                self.item(scmd::SerializeTupleEnd, |_: &dcmd::SerializeTupleEnd| true)?;
+                self.end()
+            }
+            Node::Struct { name, fields } => {
+                let len = fields.len();
+                let newcmd = scmd::SerializeStruct { name, len };
+                self.begin(&newcmd, |oldcmd: &dcmd::SerializeStruct| {
+                    oldcmd.name == *name && oldcmd.len == len
+                })?;
+                for (key, value) in fields {
+                    let newcmd = scmd::SerializeStructField { key };
+                    self.item(newcmd, |oldcmd: &dcmd::SerializeStructField| oldcmd.key == *key)?;
+                    self.path.0.push(Segment::Key(key.clone()));
+                    self.write_node(value).map_err(|e| e.at(&self.path))?;
+                    self.path.0.pop();
+                }
+                self.item(scmd::SerializeStructEnd, |_: &dcmd::SerializeStructEnd| true)?;
+                self.end()
+            }
+            Node::Variant { name, variant, value: None } => {
+                let newcmd = scmd::SerializeUnitVariant { name, unit: variant };
+                self.begin(&newcmd, |oldcmd: &dcmd::SerializeUnitVariant| {
+                    oldcmd.name == *name && oldcmd.unit == *variant
+                })?;
+                self.end()
+            }
+            Node::Variant { name, variant, value: Some(value) } => {
+                self.begin_variant(name, variant)?;
+                self.write_node(value).map_err(|e| e.at(&self.path))?;
+                self.end_variant()
+            }
+        }
+    }
+
+    /// Write the selected document, which must be new, as `write_node`
+    /// does, compressed as a whole if the file has compression, as the base
+    /// of a compacted file is.
+    pub(crate) fn write_base(&mut self, node: &Node) -> Result<()> {
+        if !self.fresh {
+            return Err(anyhow!("document {:?} already exists", self.document).into());
+        }
+        let captured = self.state.start_capture();
+        let result = self.write_node(node);
+        if !captured {
+            return result;
+        }
+        match result {
+            Ok(()) => self.state.end_capture(),
+            Err(e) => {
+                self.state.captures.pop();
+                Err(e)
+            }
+        }
+    }
+
     pub fn dump(&mut self) -> Result<()> {
         println!("-- dump --");
-        let pos = self.state.buf.seek(SeekFrom::Current(0)).e()?;
-        self.state.buf.seek(SeekFrom::Start(0)).e()?;
+        let pos = self.state.pos()?;
+        self.state.seek(0)?;
         let mut stdout = io::stdout();
         io::copy(&mut self.state.buf, &mut stdout).e()?;
         println!("-- dump --");
-        self.state.buf.seek(SeekFrom::Start(pos)).e()?;
+        self.state.seek(pos)?;
         Ok(())
     }
 }
 
-impl<'a> ser::Serializer for &'a mut Serializer {
+impl ser::Serializer for &mut Serializer {
     type Ok = ();
 
     type Error = Error;
@@ -113,130 +994,115 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     type SerializeStructVariant = Self;
 
     fn serialize_bool(self, v: bool) -> Result<()> {
-        let old_pos = self.state.buf.seek(SeekFrom::Current(0)).e()?;
         let newcmd = scmd::SerializeBool { v };
-        println!("newcmd: {:?}", newcmd);
-        let oldcmd = self.read::<dcmd::SerializeBool>();
-        println!("oldcmd: {:?}", oldcmd);
-        if oldcmd.cmd_eof() {
-            self.write(newcmd)?;
-        } else {
-            let oldcmd = oldcmd?;
-            if oldcmd != newcmd {
-                // Save the stream position
-                let bookmark_pos = self.state.buf.seek(SeekFrom::Current(0)).e()?;
-                // Seek to the end
-                let stitch_pos = self.state.buf.seek(SeekFrom::End(0)).e()?;
-                // Write a placeholder stitch
-                let new_pos = 0;
-                let next_stitch_pos = 0;
-                let tmp_stitch = Stitch { old_pos, new_pos, next_stitch_pos };
-                let tmp_stitch = tmp_stitch.encode();
-                self.write(tmp_stitch)?;
-                // Write the command
-                let new_pos = self.state.buf.seek(SeekFrom::Current(0)).e()?;
-                self.write(newcmd)?;
-                let next_stitch_pos = self.state.buf.seek(SeekFrom::Current(0)).e()?;
-                // Backup and rewrite the real stitch
-                self.state.buf.seek(SeekFrom::Start(stitch_pos)).e()?;
-                let stitch = Stitch { old_pos, new_pos, next_stitch_pos };
-                println!("stitch: {:?}", stitch);
-                let stitch = stitch.encode();
-                self.write(stitch)?;
-                // Verify the stitch size
-                let new_pos_2 = self.state.buf.seek(SeekFrom::Current(0)).e()?;
-                assert_eq!(new_pos, new_pos_2);
-                // Restore the original stream position
-                self.state.buf.seek(SeekFrom::Start(bookmark_pos)).e()?;
-                // Validate self.first_stitch_pos
-                self.new_stitches += 1;
-                if self.new_stitches == 1 {
-                    assert_eq!(self.first_stitch_pos, stitch_pos);
-                }
-            }
-        }
-        Ok(())
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeBool| *oldcmd == newcmd)?;
+        self.end()
     }
 
     fn serialize_i8(self, v: i8) -> Result<()> {
-        panic!()
+        self.serialize_i64(v.into())
     }
 
     fn serialize_i16(self, v: i16) -> Result<()> {
-        panic!()
+        self.serialize_i64(v.into())
     }
 
     fn serialize_i32(self, v: i32) -> Result<()> {
-        panic!()
+        self.serialize_i64(v.into())
     }
 
     fn serialize_i64(self, v: i64) -> Result<()> {
-        panic!()
+        let newcmd = scmd::SerializeI64 { i: v };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeI64| *oldcmd == newcmd)?;
+        self.end()
     }
 
     fn serialize_u8(self, v: u8) -> Result<()> {
-        println!("serialize_u8");
-        Ok(self.write(scmd::SerializeU8 { v })?)
+        let newcmd = scmd::SerializeU8 { v };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeU8| *oldcmd == newcmd)?;
+        self.end()
     }
 
     fn serialize_u16(self, v: u16) -> Result<()> {
-        panic!()
+        self.serialize_u64(v.into())
     }
 
     fn serialize_u32(self, v: u32) -> Result<()> {
-        panic!()
+        self.serialize_u64(v.into())
     }
 
     fn serialize_u64(self, v: u64) -> Result<()> {
-        panic!()
+        let newcmd = scmd::SerializeU64 { u: v };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeU64| *oldcmd == newcmd)?;
+        self.end()
     }
 
     fn serialize_f32(self, v: f32) -> Result<()> {
-        panic!()
+        self.serialize_f64(v.into())
     }
 
     fn serialize_f64(self, v: f64) -> Result<()> {
-        panic!()
+        if !v.is_finite() {
+            return Err(anyhow!("can't serialize non-finite float {}", v).into());
+        }
+        let newcmd = scmd::SerializeF64 { f: v };
+        // Compare bits, so 0.0 and -0.0 differ
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeF64| oldcmd.f.to_bits() == v.to_bits())?;
+        self.end()
     }
 
     fn serialize_char(self, v: char) -> Result<()> {
-        panic!()
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
     }
 
     fn serialize_str(self, v: &str) -> Result<()> {
-        panic!()
+        let newcmd = scmd::SerializeStr { s: v };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeStr| oldcmd.s == v)?;
+        self.end()
     }
 
     fn serialize_bytes(self, v: &[u8]) -> Result<()> {
-        panic!()
+        // Written as a sequence of `u8`, like a `Vec<u8>`, so bytes are read
+        // back as either
+        let mut seq = ser::Serializer::serialize_seq(self, Some(v.len()))?;
+        for byte in v {
+            ser::SerializeSeq::serialize_element(&mut seq, byte)?;
+        }
+        ser::SerializeSeq::end(seq)
     }
 
     fn serialize_none(self) -> Result<()> {
-        panic!()
+        self.serialize_unit()
     }
 
     fn serialize_some<T>(self, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        value.serialize(self)
     }
 
     fn serialize_unit(self) -> Result<()> {
-        panic!()
+        let newcmd = scmd::SerializeUnit;
+        self.begin(&newcmd, |_: &dcmd::SerializeUnit| true)?;
+        self.end()
     }
 
     fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
-        panic!()
+        self.serialize_unit()
     }
 
     fn serialize_unit_variant(
         self,
-        _name: &'static str,
+        name: &'static str,
         _variant_index: u32,
         variant: &'static str,
     ) -> Result<()> {
-        panic!()
+        let newcmd = scmd::SerializeUnitVariant { name, unit: variant };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeUnitVariant| {
+            oldcmd.name == name && oldcmd.unit == variant
+        })?;
+        self.end()
     }
 
     fn serialize_newtype_struct<T>(
@@ -247,12 +1113,12 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        value.serialize(self)
     }
 
     fn serialize_newtype_variant<T>(
         self,
-        _name: &'static str,
+        name: &'static str,
         _variant_index: u32,
         variant: &'static str,
         value: &T,
@@ -260,49 +1126,52 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        self.begin_variant(name, variant)?;
+        value.serialize(&mut *self).map_err(|e| e.at(&self.path))?;
+        self.end_variant()
     }
 
-    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        panic!()
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        let items = len.ok_or_else(|| anyhow!("can't serialize a sequence of unknown length"))?;
+        let newcmd = scmd::SerializeSeq { items };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeSeq| *oldcmd == newcmd)?;
+        self.path.0.push(Segment::Index(0));
+        Ok(self)
     }
 
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
         let newcmd = scmd::SerializeTuple { len };
-        println!("newcmd: {:?}", newcmd);
-        let oldcmd = self.read::<dcmd::SerializeTuple>();
-        println!("oldcmd: {:?}", oldcmd);
-        if oldcmd.cmd_eof() {
-            self.write(newcmd)?;
-        } else {
-            let oldcmd = oldcmd?;
-            if oldcmd != newcmd {
-                unimplemented!()
-            }
-        }
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeTuple| *oldcmd == newcmd)?;
+        self.path.0.push(Segment::Index(0));
         Ok(self)
     }
 
+    /// Tuple structs are written as tuples, without their names, like
+    /// newtype structs are written as their values.
     fn serialize_tuple_struct(
         self,
         _name: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleStruct> {
-        panic!()
+        self.serialize_tuple(len)
     }
 
     fn serialize_tuple_variant(
         self,
-        _name: &'static str,
+        name: &'static str,
         _variant_index: u32,
         variant: &'static str,
-        _len: usize,
+        len: usize,
     ) -> Result<Self::SerializeTupleVariant> {
-        panic!()
+        self.begin_variant(name, variant)?;
+        self.serialize_tuple(len)
     }
 
-    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        panic!()
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
+        let entries = len.ok_or_else(|| anyhow!("can't serialize a map of unknown length"))?;
+        let newcmd = scmd::SerializeMap { entries };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeMap| *oldcmd == newcmd)?;
+        Ok(self)
     }
 
     fn serialize_struct(
@@ -310,23 +1179,26 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         name: &'static str,
         len: usize,
     ) -> Result<Self::SerializeStruct> {
-        println!("serialize_struct");
-        self.write(scmd::SerializeStruct { name, len })?;
+        let newcmd = scmd::SerializeStruct { name, len };
+        self.begin(&newcmd, |oldcmd: &dcmd::SerializeStruct| {
+            oldcmd.name == name && oldcmd.len == len
+        })?;
         Ok(self)
     }
 
     fn serialize_struct_variant(
         self,
-        _name: &'static str,
+        name: &'static str,
         _variant_index: u32,
         variant: &'static str,
-        _len: usize,
+        len: usize,
     ) -> Result<Self::SerializeStructVariant> {
-        panic!()
+        self.begin_variant(name, variant)?;
+        self.serialize_struct(variant, len)
     }
 }
 
-impl<'a> ser::SerializeSeq for &'a mut Serializer {
+impl ser::SerializeSeq for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -334,15 +1206,22 @@ impl<'a> ser::SerializeSeq for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        let newcmd = scmd::SerializeSeqElement;
+        self.item(newcmd, |_: &dcmd::SerializeSeqElement| true)?;
+        value.serialize(&mut **self).map_err(|e| e.at(&self.path))?;
+        self.next_element();
+        Ok(())
     }
 
     fn end(self) -> Result<()> {
-        panic!()
+        self.path.0.pop();
+        let newcmd = scmd::SerializeSeqEnd;
+        self.item(newcmd, |_: &dcmd::SerializeSeqEnd| true)?;
+        self.end()
     }
 }
 
-impl<'a> ser::SerializeTuple for &'a mut Serializer {
+impl ser::SerializeTuple for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -351,39 +1230,21 @@ impl<'a> ser::SerializeTuple for &'a mut Serializer {
         T: ?Sized + Serialize,
     {
         let newcmd = scmd::SerializeTupleElement;
-        println!("newcmd: {:?}", newcmd);
-        let oldcmd = self.read::<dcmd::SerializeTupleElement>();
-        println!("oldcmd: {:?}", oldcmd);
-        if oldcmd.cmd_eof() {
-            self.write(newcmd)?;
-        } else {
-            let oldcmd = oldcmd?;
-            if oldcmd != newcmd {
-                unimplemented!()
-            }
-        }
-        value.serialize(&mut **self)?;
+        self.item(newcmd, |_: &dcmd::SerializeTupleElement| true)?;
+        value.serialize(&mut **self).map_err(|e| e.at(&self.path))?;
+        self.next_element();
         Ok(())
     }
 
     fn end(self) -> Result<()> {
+        self.path.0.pop();
         let newcmd = scmd::SerializeTupleEnd;
-        println!("newcmd: {:?}", newcmd);
-        let oldcmd = self.read::<dcmd::SerializeTupleEnd>();
-        println!("oldcmd: {:?}", oldcmd);
-        if oldcmd.cmd_eof() {
-            self.write(newcmd)?;
-        } else {
-            let oldcmd = oldcmd?;
-            if oldcmd != newcmd {
-                unimplemented!()
-            }
-        }
-        Ok(())
+        self.item(newcmd, |_: &dcmd::SerializeTupleEnd| true)?;
+        self.end()
     }
 }
 
-impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
+impl ser::SerializeTupleStruct for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -391,15 +1252,15 @@ impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        ser::SerializeTuple::serialize_element(self, value)
     }
 
     fn end(self) -> Result<()> {
-        panic!()
+        ser::SerializeTuple::end(self)
     }
 }
 
-impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
+impl ser::SerializeTupleVariant for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -407,15 +1268,16 @@ impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        ser::SerializeTuple::serialize_element(self, value)
     }
 
     fn end(self) -> Result<()> {
-        panic!()
+        ser::SerializeTuple::end(&mut *self)?;
+        self.end_variant()
     }
 }
 
-impl<'a> ser::SerializeMap for &'a mut Serializer {
+impl ser::SerializeMap for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -423,25 +1285,208 @@ impl<'a> ser::SerializeMap for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        let k = key.serialize(KeySerializer)?;
+        let newcmd = scmd::SerializeMapKey { k: &k };
+        self.item(newcmd, |oldcmd: &dcmd::SerializeMapKey| oldcmd.k == k)?;
+        self.path.0.push(Segment::Key(k));
+        Ok(())
     }
 
-    // It doesn't make a difference whether the colon is printed at the end of
-    // `serialize_key` or at the beginning of `serialize_value`. In this case
-    // the code is a bit simpler having it here.
     fn serialize_value<T>(&mut self, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        value.serialize(&mut **self).map_err(|e| e.at(&self.path))?;
+        self.path.0.pop();
+        Ok(())
     }
 
     fn end(self) -> Result<()> {
-        panic!()
+        let newcmd = scmd::SerializeMapEnd;
+        self.item(newcmd, |_: &dcmd::SerializeMapEnd| true)?;
+        self.end()
+    }
+}
+
+/// Serializes a map key to the string it is stored as. As in serde_json,
+/// integers, bools and chars are stored as strings too, and parsed back
+/// when read.
+struct KeySerializer;
+
+fn key_error() -> Error {
+    anyhow!("can't serialize a map key that isn't a string, integer, bool or char").into()
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = Error;
+
+    type SerializeSeq = ser::Impossible<String, Error>;
+    type SerializeTuple = ser::Impossible<String, Error>;
+    type SerializeTupleStruct = ser::Impossible<String, Error>;
+    type SerializeTupleVariant = ser::Impossible<String, Error>;
+    type SerializeMap = ser::Impossible<String, Error>;
+    type SerializeStruct = ser::Impossible<String, Error>;
+    type SerializeStructVariant = ser::Impossible<String, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_char(self, v: char) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_none(self) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_some<T>(self, _value: &T) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(key_error())
+    }
+
+    fn serialize_unit(self) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(key_error())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(key_error())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(key_error())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct> {
+        Err(key_error())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(key_error())
     }
 }
 
-impl<'a> ser::SerializeStruct for &'a mut Serializer {
+impl ser::SerializeStruct for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -449,18 +1494,22 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        self.write(scmd::SerializeStructField { key })?;
-        value.serialize(&mut **self)?;
+        let newcmd = scmd::SerializeStructField { key };
+        self.item(newcmd, |oldcmd: &dcmd::SerializeStructField| oldcmd.key == key)?;
+        self.path.0.push(Segment::Key(key.to_string()));
+        value.serialize(&mut **self).map_err(|e| e.at(&self.path))?;
+        self.path.0.pop();
         Ok(())
     }
 
     fn end(self) -> Result<()> {
-        self.write(scmd::SerializeStructEnd)?;
-        Ok(())
+        let newcmd = scmd::SerializeStructEnd;
+        self.item(newcmd, |_: &dcmd::SerializeStructEnd| true)?;
+        self.end()
     }
 }
 
-impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
+impl ser::SerializeStructVariant for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -468,11 +1517,12 @@ impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        panic!()
+        ser::SerializeStruct::serialize_field(self, key, value)
     }
 
     fn end(self) -> Result<()> {
-        panic!()
+        ser::SerializeStruct::end(&mut *self)?;
+        self.end_variant()
     }
 }
 
@@ -0,0 +1,71 @@
+//! A buffer shared between a serializer and deserializers reading it.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::overlay::Base;
+use crate::state::Buffer;
+
+/// One of several handles on a buffer, each with its own position.
+pub struct Shared {
+    buf: Base,
+    pos: u64,
+}
+
+impl Shared {
+    /// Replace `buf` with a handle on it, returning the original to pass to
+    /// `handle`.
+    pub fn install(buf: &mut Box<dyn Buffer>) -> io::Result<Base> {
+        let pos = buf.stream_position()?;
+        let placeholder: Box<dyn Buffer> = Box::new(io::Cursor::new(Vec::new()));
+        let base = Arc::new(Mutex::new(std::mem::replace(buf, placeholder)));
+        *buf = Box::new(Shared { buf: base.clone(), pos });
+        Ok(base)
+    }
+
+    /// Another handle on `base`, at its start.
+    pub fn handle(base: &Base) -> Shared {
+        Shared { buf: base.clone(), pos: 0 }
+    }
+
+    fn with_buf<T>(&mut self, f: impl FnOnce(&mut dyn Buffer) -> io::Result<T>) -> io::Result<T> {
+        let mut buf = self.buf.lock().unwrap_or_else(|e| e.into_inner());
+        buf.seek(SeekFrom::Start(self.pos))?;
+        let result = f(&mut **buf);
+        self.pos = buf.stream_position()?;
+        result
+    }
+}
+
+impl Read for Shared {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with_buf(|inner| inner.read(buf))
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_buf(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_buf(|inner| inner.flush())
+    }
+}
+
+impl Seek for Shared {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => {
+                let mut buf = self.buf.lock().unwrap_or_else(|e| e.into_inner());
+                buf.seek(SeekFrom::End(0))?.checked_add_signed(offset)
+            }
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
@@ -1,10 +1,613 @@
-use std::io::{Read, Write, Seek};
+use anyhow::anyhow;
+use crate::codec::Codec;
+use crate::error::{self, Conflict, Result, StdResultExt};
+use crate::meta::{self, Encoding, Header, Refs, Stitch, Trailer};
+use crate::compression::{self, Compression};
+use crate::encryption::{EncryptedBuffer, EncryptionKey};
+use crate::chain::SigningKey;
+use crate::{dcmd, scmd};
+use crate::node::Node;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Read, Write, Seek, SeekFrom};
 
 pub trait Buffer: Read + Write + Seek + Send + Sync + 'static { }
 
 impl<T> Buffer for T
 where T: Read + Write + Seek + Send + Sync + 'static { }
 
+/// Where commands are read from.
+trait Source: Read + Seek { }
+
+impl<T> Source for T where T: Read + Seek { }
+
+/// The name of the document read and written when no other document is
+/// selected.
+pub const DEFAULT_DOCUMENT: &str = "";
+
+/// The branch read and written when no other branch is selected, which
+/// every file without branches is on.
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// Settings for opening a file.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The encoding of newly created files. Existing files keep the
+    /// encoding recorded in their header.
+    pub encoding: Encoding,
+    /// The compression of newly created files. Existing files keep the
+    /// compression recorded in their header.
+    pub compression: Option<Compression>,
+    /// The size in bytes below which values aren't compressed.
+    pub compression_threshold: usize,
+    /// The key of encrypted files. New files are encrypted if it is set.
+    pub key: Option<EncryptionKey>,
+    /// The key trailers are signed with, and checked against by
+    /// `Deserializer::verify_chain`.
+    pub signing_key: Option<SigningKey>,
+    /// The branch to read and write.
+    pub branch: String,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            encoding: Encoding::default(),
+            compression: None,
+            compression_threshold: compression::DEFAULT_THRESHOLD,
+            key: None,
+            signing_key: None,
+            branch: DEFAULT_BRANCH.to_string(),
+        }
+    }
+}
+
 pub struct State {
     pub buf: Box<dyn Buffer>,
+    pub options: Options,
+    /// The file's header. Empty files get the header new files are written
+    /// with.
+    pub header: Header,
+    /// Where the data after the header starts.
+    pub data_start: u64,
+    /// Root position of every document, including documents created since
+    /// the last commit.
+    pub documents: BTreeMap<String, u64>,
+    /// Every stitch in effect, from the position of the replaced value to
+    /// the position of its replacement.
+    pub stitches: HashMap<u64, u64>,
+    /// The branch being read and written.
+    pub branch: String,
+    /// The branches and tags, as of the last trailer in the file when it
+    /// was loaded, or committed since.
+    pub refs: Refs,
+    /// Position of the last committed trailer.
+    pub trailer_pos: Option<u64>,
+    /// The length of the file when it was read, or since written through
+    /// this state. Anything past it was written by someone else.
+    pub len: u64,
+    /// Position of the last trailer in the file when it was loaded, or
+    /// committed since, on any branch.
+    pub last_trailer: Option<u64>,
+    /// The conflict found when appending, which is found again until the
+    /// file is read again.
+    pub conflict: Option<Conflict>,
+    /// Position of the catalog referenced by the last committed trailer.
+    pub catalog_pos: Option<u64>,
+    /// The decompressed values being read, innermost last. While any are
+    /// open, commands are read from the innermost instead of `buf`.
+    pub blocks: Vec<Cursor<Vec<u8>>>,
+    /// The values being written, innermost last, held back until they end
+    /// so they can be compressed.
+    pub captures: Vec<Vec<u8>>,
+}
+
+impl State {
+    /// Load the state committed by the head of the branch in `options`.
+    pub fn load(buf: Box<dyn Buffer>, options: &Options) -> Result<State> {
+        let (mut buf, header, data_start) = State::open(buf, options)?;
+        let refs = meta::find_refs(&mut *buf, header.encoding, data_start)?;
+        let head = meta::read_head(&mut *buf, header.encoding, &refs, &options.branch)?;
+        let mut state = State::load_at(buf, options, header, data_start, head)?;
+        state.last_trailer = refs.heads.get(&refs.branch).copied();
+        state.refs = refs;
+        Ok(state)
+    }
+
+    /// Read the header of `buf`, returning it with the position where the
+    /// data after it starts, and the buffer to read that data from, which
+    /// is decrypted if the file is encrypted.
+    pub fn open(buf: Box<dyn Buffer>, options: &Options) -> Result<(Box<dyn Buffer>, Header, u64)> {
+        State::open_inner(buf, options, false)
+    }
+
+    /// Open `buf` like `open`, reading an encrypted file even if it was cut
+    /// off between chunks, to recover it.
+    pub fn open_damaged(buf: Box<dyn Buffer>, options: &Options) -> Result<(Box<dyn Buffer>, Header, u64)> {
+        State::open_inner(buf, options, true)
+    }
+
+    fn open_inner(mut buf: Box<dyn Buffer>, options: &Options, cut: bool) -> Result<(Box<dyn Buffer>, Header, u64)> {
+        let (header, data_start) = match meta::read_header(&mut *buf)? {
+            Some(header) => header,
+            None => {
+                let header = Header::new(options);
+                let data_start = meta::header_len(&header)?;
+                (header, data_start)
+            }
+        };
+        if let Some(encryption) = &header.encryption {
+            let key = options.key.as_ref()
+                .ok_or_else(|| anyhow!("serdif file is encrypted but no key was given"))?;
+            encryption.check(key)?;
+            let mut encrypted = EncryptedBuffer::new(buf, key, &header, data_start)?;
+            if cut {
+                encrypted.allow_cut();
+            }
+            buf = Box::new(encrypted);
+        }
+        Ok((buf, header, data_start))
+    }
+
+    /// Load the state committed by the given trailer, with the branches and
+    /// tags it records.
+    pub fn load_at(buf: Box<dyn Buffer>, options: &Options, header: Header, data_start: u64, trailer: Option<(Trailer, u64)>) -> Result<State> {
+        let mut state = State {
+            buf,
+            options: options.clone(),
+            header,
+            data_start,
+            documents: BTreeMap::new(),
+            stitches: HashMap::new(),
+            branch: options.branch.clone(),
+            refs: Refs::default(),
+            trailer_pos: None,
+            len: 0,
+            last_trailer: None,
+            conflict: None,
+            catalog_pos: None,
+            blocks: Vec::new(),
+            captures: Vec::new(),
+        };
+        state.len = state.buf.seek(SeekFrom::End(0)).e()?;
+        if let Some((trailer, pos)) = &trailer {
+            state.refs = meta::read_refs(&mut *state.buf, state.header.encoding, trailer)?;
+            state.refs.heads.insert(state.refs.branch.clone(), *pos);
+            state.last_trailer = Some(*pos);
+        }
+        state.reload(trailer)?;
+        Ok(state)
+    }
+
+    /// Load the head of the named branch.
+    pub fn checkout(&mut self, branch: &str) -> Result<()> {
+        let head = meta::read_head(&mut *self.buf, self.header.encoding, &self.refs, branch)?;
+        self.reload(head)?;
+        self.branch = branch.to_string();
+        Ok(())
+    }
+
+    /// Replace the documents and stitches with those committed by the given
+    /// trailer, dropping anything uncommitted.
+    pub fn reload(&mut self, trailer: Option<(Trailer, u64)>) -> Result<()> {
+        let mut documents = BTreeMap::new();
+        let mut stitches = HashMap::new();
+        let mut trailer_pos = None;
+        let mut catalog_pos = None;
+        if let Some((trailer, pos)) = trailer {
+            let encoding = self.header.encoding;
+            let buf = &mut *self.buf;
+            documents = meta::read_catalog(buf, encoding, &trailer)?.documents;
+            catalog_pos = trailer.catalog;
+            trailer_pos = Some(pos);
+            for (trailer, pos) in meta::trailer_chain(buf, encoding, trailer, pos)? {
+                for (_, stitch) in meta::read_stitches(buf, encoding, &trailer, pos)? {
+                    stitches.insert(stitch.old_pos, stitch.new_pos);
+                }
+            }
+        }
+        self.documents = documents;
+        self.stitches = stitches;
+        self.trailer_pos = trailer_pos;
+        self.catalog_pos = catalog_pos;
+        self.blocks.clear();
+        Ok(())
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match self.blocks.last_mut() {
+            Some(block) => block,
+            None => &mut self.buf,
+        }
+    }
+
+    pub fn pos(&mut self) -> Result<u64> {
+        self.source().stream_position().e()
+    }
+
+    pub fn seek(&mut self, pos: u64) -> Result<()> {
+        self.source().seek(SeekFrom::Start(pos)).e()?;
+        Ok(())
+    }
+
+    pub fn write(&mut self, v: impl Serialize) -> Result<()> {
+        match self.captures.last_mut() {
+            Some(capture) => self.header.encoding.write(capture, &v),
+            None => {
+                self.header.encoding.write(&mut self.buf, &v)?;
+                self.wrote()
+            }
+        }
+    }
+
+    /// Check that no one else committed to the file since it was read or
+    /// last committed through this state, returning where it ends.
+    ///
+    /// What someone else appended without committing is left alone, and
+    /// appended after, as whichever of the writers commits second fails.
+    pub fn check_conflict(&mut self) -> Result<u64> {
+        if let Some(conflict) = &self.conflict {
+            return Err(anyhow::Error::new(conflict.clone()).into());
+        }
+        let conflict = match self.buf.seek(SeekFrom::End(0)) {
+            Ok(end) if end == self.len => return Ok(end),
+            Ok(end) if end < self.len => {
+                return Err(anyhow!("the file was truncated to {} bytes while open", end).into());
+            }
+            Ok(end) => {
+                let encoding = self.header.encoding;
+                match meta::find_trailer_in(&mut *self.buf, encoding, self.len, end)? {
+                    Some((_, found)) => Conflict { expected: self.last_trailer, found: Some(found) },
+                    None => return Ok(end),
+                }
+            }
+            Err(e) if error::is_io_conflict(&e) => Conflict { expected: self.last_trailer, found: None },
+            Err(e) => return Err(e).e(),
+        };
+        self.conflict = Some(conflict.clone());
+        Err(anyhow::Error::new(conflict).into())
+    }
+
+    /// Seek to the end of the file to append to it, checking no one else
+    /// committed.
+    pub fn append(&mut self) -> Result<u64> {
+        let end = self.check_conflict()?;
+        self.buf.seek(SeekFrom::Start(end)).e()?;
+        self.len = end;
+        Ok(end)
+    }
+
+    /// Note that the file was written up to the current position.
+    fn wrote(&mut self) -> Result<()> {
+        let pos = self.buf.stream_position().e()?;
+        self.len = self.len.max(pos);
+        Ok(())
+    }
+
+    pub fn read<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let encoding = self.header.encoding;
+        encoding.read(self.source())
+    }
+
+    /// Start holding back written commands, if the file has compression.
+    /// Returns whether it does.
+    pub fn start_capture(&mut self) -> bool {
+        if self.header.compression.is_some() {
+            self.captures.push(Vec::new());
+        }
+        self.header.compression.is_some()
+    }
+
+    /// Write the commands held back since the matching `start_capture`,
+    /// compressing them if they are large enough.
+    pub fn end_capture(&mut self) -> Result<()> {
+        let data = self.captures.pop().expect("unbalanced capture");
+        self.write_compressible(data)
+    }
+
+    /// Write the encoded commands of a value, compressing them if they are
+    /// large enough.
+    pub fn write_compressible(&mut self, data: Vec<u8>) -> Result<()> {
+        if let Some(compression) = self.header.compression {
+            if data.len() >= self.options.compression_threshold {
+                let cmd = scmd::Compressed {
+                    data: compression.compress(&data)?,
+                };
+                let mut compressed = Vec::new();
+                self.header.encoding.write(&mut compressed, &cmd)?;
+                if compressed.len() < data.len() {
+                    return self.write_raw(&compressed);
+                }
+            }
+        }
+        self.write_raw(&data)
+    }
+
+    fn write_raw(&mut self, data: &[u8]) -> Result<()> {
+        match self.captures.last_mut() {
+            Some(capture) => capture.extend_from_slice(data),
+            None => {
+                self.buf.write_all(data).e()?;
+                self.wrote()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the value at the current position if it is compressed,
+    /// returning its decompressed commands.
+    pub fn read_compressed(&mut self) -> Result<Option<Vec<u8>>> {
+        let compression = match self.header.compression {
+            Some(compression) => compression,
+            None => return Ok(None),
+        };
+        match self.probe::<dcmd::Compressed>()? {
+            Some(cmd) => compression.decompress(&cmd.data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// If the value at the current position is compressed, read from its
+    /// decompressed commands until the matching `close_block`. Returns
+    /// whether it was.
+    pub fn open_block(&mut self) -> Result<bool> {
+        match self.read_compressed()? {
+            Some(data) => {
+                self.blocks.push(Cursor::new(data));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn close_block(&mut self) {
+        self.blocks.pop().expect("unbalanced block");
+    }
+
+    pub fn write_stitch(&mut self, stitch: Stitch) -> Result<()> {
+        assert!(self.captures.is_empty());
+        self.header.encoding.write_stitch(&mut self.buf, stitch)?;
+        self.wrote()
+    }
+
+    pub fn write_trailer(&mut self, trailer: &Trailer) -> Result<()> {
+        assert!(self.captures.is_empty());
+        self.header.encoding.write_trailer(&mut self.buf, trailer)?;
+        self.wrote()
+    }
+
+    /// Read a `T`, or rewind and return `None` if the next command is
+    /// something else.
+    pub fn probe<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let pos = self.pos()?;
+        match self.read::<T>() {
+            Ok(t) => Ok(Some(t)),
+            Err(e) if self.header.encoding.is_eof(&e) => Err(e),
+            Err(_) => {
+                self.seek(pos)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Seek to the root of the named document.
+    pub fn seek_document(&mut self, name: &str) -> Result<()> {
+        let root = self.documents.get(name).copied()
+            .ok_or_else(|| anyhow!("no document named {:?}", name))?;
+        self.seek(root)
+    }
+
+    /// Follow any stitches that replace the value at the current position.
+    ///
+    /// Leaves the stream at the start of the replacement value and returns
+    /// the position just past the replaced value, where reading resumes once
+    /// the replacement has been read.
+    pub fn enter_value(&mut self) -> Result<Option<u64>> {
+        if !self.blocks.is_empty() {
+            // Nothing inside a compressed value is stitched
+            return Ok(None);
+        }
+        let start = self.pos()?;
+        let mut pos = start;
+        while let Some(&new_pos) = self.stitches.get(&pos) {
+            if new_pos <= pos {
+                return Err(anyhow!("stitch at {} points backwards to {}", pos, new_pos).into());
+            }
+            pos = new_pos;
+        }
+        if pos == start {
+            return Ok(None);
+        }
+        self.skip_value()?;
+        let resume = self.pos()?;
+        self.seek(pos)?;
+        Ok(Some(resume))
+    }
+
+    /// Skip the value at the current position, without following stitches.
+    pub fn skip_value(&mut self) -> Result<()> {
+        self.scan_value(&mut |_| ())
+    }
+
+    /// Skip the value at the current position, without following stitches,
+    /// calling `visit` with the position of it and every value inside it.
+    /// Compressed values count as one.
+    pub fn scan_value(&mut self, visit: &mut dyn FnMut(u64)) -> Result<()> {
+        visit(self.pos()?);
+        if self.probe::<dcmd::SerializeUnit>()?.is_some() {
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeBool>()?.is_some() {
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeU8>()?.is_some() {
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeI64>()?.is_some() {
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeU64>()?.is_some() {
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeF64>()?.is_some() {
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeStr>()?.is_some() {
+            return Ok(());
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeTuple>()? {
+            for _ in 0..cmd.len {
+                self.read::<dcmd::SerializeTupleElement>()?;
+                self.scan_value(visit)?;
+            }
+            self.read::<dcmd::SerializeTupleEnd>()?;
+            return Ok(());
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeSeq>()? {
+            for _ in 0..cmd.items {
+                self.read::<dcmd::SerializeSeqElement>()?;
+                self.scan_value(visit)?;
+            }
+            self.read::<dcmd::SerializeSeqEnd>()?;
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeStruct>()?.is_some() {
+            while self.probe::<dcmd::SerializeStructField>()?.is_some() {
+                self.scan_value(visit)?;
+            }
+            self.read::<dcmd::SerializeStructEnd>()?;
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeMap>()?.is_some() {
+            while self.probe::<dcmd::SerializeMapKey>()?.is_some() {
+                self.scan_value(visit)?;
+            }
+            self.read::<dcmd::SerializeMapEnd>()?;
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeUnitVariant>()?.is_some() {
+            return Ok(());
+        }
+        if self.probe::<dcmd::SerializeVariant>()?.is_some() {
+            self.scan_value(visit)?;
+            self.read::<dcmd::SerializeVariantEnd>()?;
+            return Ok(());
+        }
+        if self.probe::<dcmd::Compressed>()?.is_some() {
+            return Ok(());
+        }
+        let pos = self.pos()?;
+        Err(anyhow!("unrecognized command at {}", pos).into())
+    }
+
+    /// The keys of the value at the current position as this state sees
+    /// it, if it is a map, leaving the position unchanged.
+    pub fn map_keys(&mut self) -> Result<Option<Vec<String>>> {
+        let pos = self.pos()?;
+        self.enter_value()?;
+        let block = self.open_block()?;
+        let keys = self.read_map_keys();
+        if block {
+            self.close_block();
+        }
+        self.seek(pos)?;
+        keys
+    }
+
+    fn read_map_keys(&mut self) -> Result<Option<Vec<String>>> {
+        if self.probe::<dcmd::SerializeMap>()?.is_none() {
+            return Ok(None);
+        }
+        let mut keys = Vec::new();
+        while let Some(key) = self.probe::<dcmd::SerializeMapKey>()? {
+            keys.push(key.k);
+            self.skip_value()?;
+        }
+        Ok(Some(keys))
+    }
+
+    /// Read the value at the current position as this state sees it,
+    /// following stitches and decompressing compressed values.
+    pub fn read_node(&mut self) -> Result<Node> {
+        let resume = self.enter_value()?;
+        let block = self.open_block()?;
+        let node = self.read_node_commands();
+        if block {
+            self.close_block();
+        }
+        let node = node?;
+        if let Some(resume) = resume {
+            self.seek(resume)?;
+        }
+        Ok(node)
+    }
+
+    fn read_node_commands(&mut self) -> Result<Node> {
+        if self.probe::<dcmd::SerializeUnit>()?.is_some() {
+            return Ok(Node::Unit);
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeBool>()? {
+            return Ok(Node::Bool(cmd.v));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeU8>()? {
+            return Ok(Node::U8(cmd.v));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeI64>()? {
+            return Ok(Node::I64(cmd.i));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeU64>()? {
+            return Ok(Node::U64(cmd.u));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeF64>()? {
+            return Ok(Node::F64(cmd.f));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeStr>()? {
+            return Ok(Node::Str(cmd.s));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeTuple>()? {
+            let mut elements = Vec::new();
+            for _ in 0..cmd.len {
+                self.read::<dcmd::SerializeTupleElement>()?;
+                elements.push(self.read_node()?);
+            }
+            self.read::<dcmd::SerializeTupleEnd>()?;
+            return Ok(Node::Tuple(elements));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeSeq>()? {
+            let mut elements = Vec::new();
+            for _ in 0..cmd.items {
+                self.read::<dcmd::SerializeSeqElement>()?;
+                elements.push(self.read_node()?);
+            }
+            self.read::<dcmd::SerializeSeqEnd>()?;
+            return Ok(Node::Seq(elements));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeStruct>()? {
+            let mut fields = Vec::new();
+            while let Some(field) = self.probe::<dcmd::SerializeStructField>()? {
+                fields.push((field.key, self.read_node()?));
+            }
+            self.read::<dcmd::SerializeStructEnd>()?;
+            return Ok(Node::Struct { name: cmd.name, fields });
+        }
+        if self.probe::<dcmd::SerializeMap>()?.is_some() {
+            let mut entries = Vec::new();
+            while let Some(key) = self.probe::<dcmd::SerializeMapKey>()? {
+                entries.push((key.k, self.read_node()?));
+            }
+            self.read::<dcmd::SerializeMapEnd>()?;
+            return Ok(Node::Map(entries));
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeUnitVariant>()? {
+            return Ok(Node::Variant { name: cmd.name, variant: cmd.unit, value: None });
+        }
+        if let Some(cmd) = self.probe::<dcmd::SerializeVariant>()? {
+            let value = self.read_node()?;
+            self.read::<dcmd::SerializeVariantEnd>()?;
+            return Ok(Node::Variant { name: cmd.name, variant: cmd.variant, value: Some(Box::new(value)) });
+        }
+        let pos = self.pos()?;
+        Err(anyhow!("unrecognized command at {}", pos).into())
+    }
 }
@@ -1,12 +1,97 @@
 use serde::{Serialize, Deserialize};
 use anyhow::Result;
-use std::io::Cursor;
-use serdif::Serializer;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use serdif::{Serializer, Deserializer, Encoding, Compression, EncryptionKey, SigningKey, Options, FORMAT_VERSION};
+use serdif::{verify, recover, compact, import, diff, to_json_value, to_json_writer};
+use serdif::{diff_values, to_json_patch, apply_json_patch, blame, history};
+use serdif::{RecoveryMode, CommitInfo, Snapshot, Change, Operation, UndoManager, DEFAULT_BRANCH};
+use serdif::{merge, merge_branch, Resolution, LockMode};
 
 fn buffer() -> Cursor<Vec<u8>> {
     Cursor::new(Vec::<u8>::new())
 }
 
+/// A buffer that can be reopened after a serializer is done with it.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl Read for SharedBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SharedBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.lock().unwrap().seek(pos)
+    }
+}
+
+/// Bytes serialized with `serialize_bytes`, as `serde_bytes` would.
+struct Bytes(Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_bytes(&self.0)
+    }
+}
+
+/// A file opened more than once. Each handle has its own position.
+#[derive(Default)]
+struct SharedFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    pos: u64,
+}
+
+impl SharedFile {
+    fn open(&self) -> SharedFile {
+        SharedFile { data: self.data.clone(), pos: 0 }
+    }
+
+    fn with_cursor<T>(&mut self, f: impl FnOnce(&mut Cursor<&mut Vec<u8>>) -> io::Result<T>) -> io::Result<T> {
+        let mut data = self.data.lock().unwrap();
+        let mut cursor = Cursor::new(&mut *data);
+        cursor.set_position(self.pos);
+        let result = f(&mut cursor);
+        self.pos = cursor.position();
+        result
+    }
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with_cursor(|cursor| cursor.read(buf))
+    }
+}
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_cursor(|cursor| cursor.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.with_cursor(|cursor| cursor.seek(pos))
+    }
+}
+
 #[test]
 fn test_u8() -> Result<()> {
     let buf = buffer();
@@ -59,7 +144,6 @@ fn test_tuple() -> Result<()> {
 }
 
 #[test]
-#[ignore]
 fn test_struct() -> Result<()> {
     let buf = buffer();
     let mut ser = Serializer::new(buf)?;
@@ -82,6 +166,133 @@ fn test_struct() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_numbers_and_strings() -> Result<()> {
+    let buf = buffer();
+    let mut ser = Serializer::new(buf)?;
+
+    let val1 = (-5i32, 70000u32, 1.5f64, 'x', "hello".to_string(), ());
+    val1.serialize(&mut ser)?;
+
+    let mut de = ser.to_de()?;
+
+    let val2 = <(i32, u32, f64, char, String, ())>::deserialize(&mut de)?;
+
+    assert_eq!(val1, val2);
+
+    Ok(())
+}
+
+#[test]
+fn test_options_and_seqs() -> Result<()> {
+    let buf = buffer();
+    let mut ser = Serializer::new(buf)?;
+
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Meters(u64);
+
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Type1 {
+        name: Option<String>,
+        nickname: Option<String>,
+        lengths: Vec<Meters>,
+    }
+
+    let val1 = Type1 {
+        name: Some("a".to_string()),
+        nickname: None,
+        lengths: vec![Meters(1), Meters(2)],
+    };
+    val1.serialize(&mut ser)?;
+
+    let mut de = ser.to_de()?;
+
+    let val2 = Type1::deserialize(&mut de)?;
+
+    assert_eq!(val1, val2);
+
+    Ok(())
+}
+
+#[test]
+fn test_maps() -> Result<()> {
+    let buf = buffer();
+    let mut ser = Serializer::new(buf)?;
+
+    let mut val1 = BTreeMap::new();
+    val1.insert(3u32, vec![true]);
+    val1.insert(10u32, vec![false, true]);
+    val1.serialize(&mut ser)?;
+
+    let mut de = ser.to_de()?;
+
+    let val2 = BTreeMap::<u32, Vec<bool>>::deserialize(&mut de)?;
+
+    assert_eq!(val1, val2);
+
+    Ok(())
+}
+
+#[test]
+fn test_enums() -> Result<()> {
+    let buf = buffer();
+    let mut ser = Serializer::new(buf)?;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect(u32, u32),
+        Polygon { points: Vec<(i64, i64)>, closed: bool },
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Pair(String, u8);
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Drawing {
+        pair: Pair,
+        shapes: Vec<Shape>,
+    }
+
+    let val1 = Drawing {
+        pair: Pair("a".to_string(), 2),
+        shapes: vec![
+            Shape::Empty,
+            Shape::Circle(1.5),
+            Shape::Rect(3, 4),
+            Shape::Polygon { points: vec![(0, 0), (1, 1)], closed: false },
+        ],
+    };
+    val1.serialize(&mut ser)?;
+
+    let mut de = ser.to_de()?;
+
+    let val2 = Drawing::deserialize(&mut de)?;
+
+    assert_eq!(val1, val2);
+
+    Ok(())
+}
+
+#[test]
+fn test_bytes() -> Result<()> {
+    let buf = buffer();
+    let mut ser = Serializer::new(buf)?;
+
+    // Bytes are written like a `Vec<u8>`, and read back as one
+    let val1 = vec![1u8, 2, 3];
+    Bytes(val1.clone()).serialize(&mut ser)?;
+
+    let mut de = ser.to_de()?;
+
+    let val2 = Vec::<u8>::deserialize(&mut de)?;
+
+    assert_eq!(val1, val2);
+
+    Ok(())
+}
+
 #[test]
 fn test_no_change_tuple() -> Result<()> {
     let buf = buffer();
@@ -134,3 +345,2127 @@ fn test_diff_tuple() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_diff_struct() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Type1 {
+        field1: bool,
+        field2: (u8, bool),
+    }
+
+    let val1 = Type1 { field1: true, field2: (1, false) };
+    val1.serialize(&mut ser)?;
+    ser.finalize()?;
+
+    ser.reset()?;
+    let val2 = Type1 { field1: true, field2: (2, true) };
+    val2.serialize(&mut ser)?;
+    ser.finalize()?;
+
+    let mut de = Deserializer::new(buf)?;
+    let val3 = Type1::deserialize(&mut de)?;
+
+    assert_eq!(val2, val3);
+
+    Ok(())
+}
+
+#[test]
+fn test_diff_skipped_fields() -> Result<()> {
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Limits {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        a: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        b: Option<u8>,
+    }
+
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Config {
+        name: (u8, bool),
+        #[serde(skip_serializing_if = "Option::is_none")]
+        c: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        d: Option<u8>,
+        limits: Limits,
+    }
+
+    let versions = [
+        Config { name: (1, true), c: Some(1), d: None, limits: Limits { a: Some(1), b: None } },
+        // The same fields, but different keys
+        Config { name: (1, true), c: Some(1), d: None, limits: Limits { a: None, b: Some(2) } },
+        // A field changed before the keys differ is kept
+        Config { name: (2, true), c: None, d: Some(3), limits: Limits { a: None, b: Some(2) } },
+    ];
+
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    for config in &versions {
+        ser.reset()?;
+        config.serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+    // A map whose keys change, with the same number of entries
+    let maps = [
+        BTreeMap::from([("a".to_string(), 1u8), ("b".to_string(), 2)]),
+        BTreeMap::from([("a".to_string(), 3u8), ("c".to_string(), 2)]),
+        BTreeMap::from([("a".to_string(), 3u8), ("b".to_string(), 4)]),
+    ];
+    for map in &maps {
+        ser.reset()?;
+        map.serialize(ser.document("map")?)?;
+        ser.finalize()?;
+    }
+
+    let mut de = Deserializer::new(buf.clone())?;
+    for (i, config) in versions.iter().enumerate() {
+        de.select_version(i + 1)?;
+        assert_eq!(&Config::deserialize(&mut de)?, config);
+    }
+    for (i, map) in maps.iter().enumerate() {
+        de.select_version(versions.len() + i + 1)?;
+        assert_eq!(&BTreeMap::<String, u8>::deserialize(de.document("map")?)?, map);
+    }
+    let report = verify(buf);
+    assert!(report.is_ok(), "{}", report);
+
+    Ok(())
+}
+
+#[test]
+fn test_documents() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+
+    (true, 1u8).serialize(ser.document("config")?)?;
+    (false, false).serialize(ser.document("users")?)?;
+    ser.finalize()?;
+
+    ser.reset()?;
+    (true, 2u8).serialize(ser.document("config")?)?;
+    5u8.serialize(ser.document("schedule")?)?;
+    ser.finalize()?;
+
+    let mut de = Deserializer::new(buf.clone())?;
+    assert_eq!(de.documents().collect::<Vec<_>>(), ["config", "schedule", "users"]);
+    assert_eq!(<(bool, u8)>::deserialize(de.document("config")?)?, (true, 2));
+    assert_eq!(<(bool, bool)>::deserialize(de.document("users")?)?, (false, false));
+    assert_eq!(u8::deserialize(de.document("schedule")?)?, 5);
+    assert!(de.document("missing").is_err());
+
+    // There is no default document to read
+    let e = de.reset().unwrap_err();
+    assert_eq!(e.to_string(), "no document named \"\"");
+    let mut de = Deserializer::new(buf)?;
+    assert!(u8::deserialize(&mut de).is_err());
+    assert!(de.read_json().is_err());
+    assert_eq!(u8::deserialize(de.document("schedule")?)?, 5);
+
+    Ok(())
+}
+
+#[test]
+fn test_documents_uncommitted() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+
+    true.serialize(ser.document("config")?)?;
+    ser.finalize()?;
+
+    ser.reset()?;
+    false.serialize(ser.document("config")?)?;
+    true.serialize(ser.document("users")?)?;
+
+    // Neither change is visible until the next finalize
+    let mut de = Deserializer::new(buf.clone())?;
+    assert!(bool::deserialize(de.document("config")?)?);
+    assert!(de.document("users").is_err());
+
+    ser.finalize()?;
+
+    let mut de = Deserializer::new(buf)?;
+    assert!(!bool::deserialize(de.document("config")?)?);
+    assert!(bool::deserialize(de.document("users")?)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_diff_replace_value() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+
+    (true, (false, false)).serialize(&mut ser)?;
+    ser.finalize()?;
+
+    ser.reset()?;
+    (false, (true, false)).serialize(&mut ser)?;
+    ser.finalize()?;
+
+    // The inner value changes shape, so it is replaced as a whole
+    ser.reset()?;
+    (false, (7u8, true, true)).serialize(&mut ser)?;
+    ser.finalize()?;
+
+    ser.reset()?;
+    (true, (7u8, false, true)).serialize(&mut ser)?;
+    ser.finalize()?;
+
+    let mut de = Deserializer::new(buf)?;
+    let val = <(bool, (u8, bool, bool))>::deserialize(&mut de)?;
+    assert_eq!(val, (true, (7, false, true)));
+
+    Ok(())
+}
+
+#[test]
+fn test_diff_other_types() -> Result<()> {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Type1 {
+        name: String,
+        count: i64,
+        tags: Vec<String>,
+        sizes: BTreeMap<String, u32>,
+        shape: Shape,
+        note: Option<String>,
+    }
+
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+
+    let mut val = Type1 {
+        name: "a".to_string(),
+        count: -1,
+        tags: vec!["x".to_string()],
+        sizes: vec![("s".to_string(), 1)].into_iter().collect(),
+        shape: Shape::Circle(1.0),
+        note: None,
+    };
+    val.serialize(&mut ser)?;
+    ser.finalize()?;
+
+    // Values inside sequences, maps and variants are stitched on their own,
+    // and values of another kind replace the old ones
+    val.name = "b".to_string();
+    val.count = 5;
+    val.tags[0] = "y".to_string();
+    val.sizes.insert("s".to_string(), 2);
+    val.shape = Shape::Empty;
+    val.note = Some("n".to_string());
+    ser.reset()?;
+    val.serialize(&mut ser)?;
+    ser.finalize()?;
+
+    let mut de = Deserializer::new(buf)?;
+    assert_eq!(Type1::deserialize(&mut de)?, val);
+
+    Ok(())
+}
+
+/// A file written before headers were added, holding `(true, false)`
+/// followed by `(false, true)`.
+const V0_FILE: &str = r#"{
+  "len": 2
+}
+null
+{
+  "v": true
+}
+null
+{
+  "v": false
+}
+null
+{
+  "magic": 9516756151521719288,
+  "first_stitch": null,
+  "prev_trailer_pos": null
+}
+{
+  "old_pos": "1300000000000000",
+  "new_pos": "0401000000000000",
+  "next_stitch_pos": "1501000000000000"
+}
+{
+  "v": false
+}
+{
+  "old_pos": "2800000000000000",
+  "new_pos": "8301000000000000",
+  "next_stitch_pos": "9301000000000000"
+}
+{
+  "v": true
+}
+{
+  "magic": 9516756151521719288,
+  "first_stitch": 150,
+  "prev_trailer_pos": null
+}
+"#;
+
+#[test]
+fn test_header() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    (true, false).serialize(&mut ser)?;
+    ser.finalize()?;
+
+    let contents = buf.0.lock().unwrap().get_ref().clone();
+    let first_line = contents.split(|b| *b == b'\n').next().unwrap();
+    assert_eq!(first_line, &br#"{"magic":"serdif","version":1,"encoding":"json","features":[]}"#[..]);
+
+    let mut de = Deserializer::new(buf)?;
+    assert_eq!(de.header().version, FORMAT_VERSION);
+    assert_eq!(de.header().encoding, Encoding::Json);
+    assert_eq!(<(bool, bool)>::deserialize(&mut de)?, (true, false));
+
+    Ok(())
+}
+
+#[test]
+fn test_header_legacy() -> Result<()> {
+    let buf = Cursor::new(V0_FILE.as_bytes().to_vec());
+    let mut de = Deserializer::new(buf)?;
+    assert_eq!(de.header().version, 0);
+    assert_eq!(<(bool, bool)>::deserialize(&mut de)?, (false, true));
+
+    let buf = Cursor::new(V0_FILE.as_bytes().to_vec());
+    let mut ser = Serializer::new(buf)?;
+    (true, true).serialize(&mut ser)?;
+    ser.finalize()?;
+    let mut de = ser.to_de()?;
+    assert_eq!(de.header().version, 0);
+    assert_eq!(<(bool, bool)>::deserialize(&mut de)?, (true, true));
+
+    Ok(())
+}
+
+#[test]
+fn test_header_future_version() -> Result<()> {
+    let file = br#"{"magic":"serdif","version":99,"encoding":"quantum","features":[]}
+"#;
+    let err = Serializer::new(Cursor::new(file.to_vec())).err().unwrap();
+    assert!(err.to_string().contains("version 99"), "{}", err);
+    let err = Deserializer::new(Cursor::new(file.to_vec())).err().unwrap();
+    assert!(err.to_string().contains("version 99"), "{}", err);
+
+    Ok(())
+}
+
+#[test]
+fn test_header_unknown_feature() -> Result<()> {
+    let file = br#"{"magic":"serdif","version":1,"encoding":"json","features":["teleport"]}
+"#;
+    let err = Deserializer::new(Cursor::new(file.to_vec())).err().unwrap();
+    assert!(err.to_string().contains("teleport"), "{}", err);
+
+    Ok(())
+}
+
+#[test]
+fn test_header_corrupt() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    (true, false).serialize(&mut ser)?;
+    ser.finalize()?;
+    let contents = buf.0.lock().unwrap().get_ref().clone();
+
+    // A damaged header isn't mistaken for a file written before headers
+    for (from, to) in [(&b"\"version\":1"[..], &b"\"version\":x"[..]), (b"\"json\"", b"\"jsno\""), (b"}\n", b"]\n")] {
+        let i = contents.windows(from.len()).position(|w| w == from).unwrap();
+        let mut damaged = contents.clone();
+        damaged[i..i + from.len()].copy_from_slice(to);
+        let err = Deserializer::new(Cursor::new(damaged.clone())).err().unwrap();
+        assert!(err.to_string().contains("header is corrupt"), "{}", err);
+        let err = Serializer::new(Cursor::new(damaged)).err().unwrap();
+        assert!(err.to_string().contains("header is corrupt"), "{}", err);
+    }
+
+    Ok(())
+}
+
+/// Write a few versions of a couple of documents, returning the file's size.
+fn write_history(options: Options) -> Result<usize> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Config {
+        enabled: bool,
+        limits: (u8, u8),
+    }
+
+    let configs = [
+        Config { enabled: true, limits: (1, 2) },
+        Config { enabled: false, limits: (1, 2) },
+        Config { enabled: false, limits: (3, 200) },
+    ];
+    for (i, config) in configs.iter().enumerate() {
+        ser.reset()?;
+        config.serialize(ser.document("config")?)?;
+        (i % 2 == 0, i as u8).serialize(ser.document("users")?)?;
+        ser.finalize()?;
+    }
+
+    let mut de = Deserializer::with_options(buf.clone(), options.clone())?;
+    assert_eq!(de.header().encoding, options.encoding);
+    assert_eq!(Config::deserialize(de.document("config")?)?, configs[2]);
+    assert_eq!(<(bool, u8)>::deserialize(de.document("users")?)?, (true, 2));
+
+    let len = buf.0.lock().unwrap().get_ref().len();
+    Ok(len)
+}
+
+/// Write and read back a value holding every kind of value.
+fn read_every_kind(encoding: Encoding) -> Result<()> {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Empty,
+        Rect(u32, u32),
+    }
+
+    let value = (
+        (-5i64, 70000u32, 1.5f64, "s".to_string(), ()),
+        vec![Some(Shape::Empty), None, Some(Shape::Rect(1, 2))],
+        vec![(3u32, "three".to_string())].into_iter().collect::<BTreeMap<_, _>>(),
+    );
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::with_options(buf.clone(), Options { encoding, ..Options::default() })?;
+    value.serialize(&mut ser)?;
+    ser.finalize()?;
+    let mut de = Deserializer::new(buf)?;
+    let read: (_, _, BTreeMap<_, _>) = Deserialize::deserialize(&mut de)?;
+    assert_eq!(read, value);
+
+    Ok(())
+}
+
+#[test]
+fn test_binary_encoding() -> Result<()> {
+    let json_len = write_history(Options::default())?;
+    let binary_len = write_history(Options { encoding: Encoding::Binary, ..Options::default() })?;
+    assert!(binary_len * 3 < json_len, "binary {} json {}", binary_len, json_len);
+
+    read_every_kind(Encoding::Binary)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_msgpack_encoding() -> Result<()> {
+    let json_len = write_history(Options::default())?;
+    let msgpack_len = write_history(Options { encoding: Encoding::MessagePack, ..Options::default() })?;
+    assert!(msgpack_len < json_len, "msgpack {} json {}", msgpack_len, json_len);
+
+    read_every_kind(Encoding::MessagePack)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_compression() -> Result<()> {
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+    struct Image {
+        visible: bool,
+        rows: Vec<[u8; 32]>,
+    }
+
+    /// Write each version, returning the file with its size after each
+    /// commit.
+    fn write(options: Options, versions: &[Image]) -> Result<(SharedBuffer, Vec<usize>)> {
+        let buf = SharedBuffer::default();
+        let mut ser = Serializer::with_options(buf.clone(), options)?;
+        let mut lens = Vec::new();
+        for version in versions {
+            ser.reset()?;
+            version.serialize(&mut ser)?;
+            ser.finalize()?;
+            lens.push(buf.0.lock().unwrap().get_ref().len());
+
+            let mut de = Deserializer::new(buf.clone())?;
+            assert_eq!(&Image::deserialize(&mut de)?, version);
+        }
+        Ok((buf, lens))
+    }
+
+    /// The hex-encoded data of every compressed value in a JSON file.
+    fn compressed_data(buf: &SharedBuffer) -> Vec<Vec<u8>> {
+        let contents = String::from_utf8(buf.0.lock().unwrap().get_ref().clone()).unwrap();
+        contents.split("\"data\": \"").skip(1)
+            .map(|rest| hex::decode(&rest[..rest.find('"').unwrap()]).unwrap())
+            .collect()
+    }
+
+    let mut versions = vec![Image { visible: true, rows: vec![[7; 32]; 4] }];
+    let mut next = versions[0].clone();
+    next.visible = false;
+    versions.push(next.clone());
+    next.rows.push([8; 32]);
+    versions.push(next);
+
+    let (plain_buf, plain) = write(Options::default(), &versions)?;
+    let plain_compacted = SharedBuffer::default();
+    compact(plain_buf, plain_compacted.clone(), &Options::default())?;
+    let plain_compacted = plain_compacted.0.lock().unwrap().get_ref().len();
+    for compression in [Compression::Zstd, Compression::Lz4] {
+        let options = Options { compression: Some(compression), ..Options::default() };
+        let (buf, compressed) = write(options.clone(), &versions)?;
+        // New documents are written as they are, so a single changed bool
+        // is stitched on its own, and costs no more than without compression
+        assert!(compressed_data(&buf).len() == 1, "{:?}", compressed);
+        assert!(compressed[1] - compressed[0] <= plain[1] - plain[0], "{:?} {:?}", compressed, plain);
+        // A replaced sequence is compressed as a whole, and only once
+        assert!((compressed[2] - compressed[1]) * 3 < plain[2] - plain[1], "{:?} {:?}", compressed, plain);
+        for data in compressed_data(&buf) {
+            let commands = String::from_utf8(compression.decompress(&data)?)?;
+            assert!(!commands.contains("\"data\""), "{}", commands);
+        }
+
+        // Compaction compresses the document as a whole
+        let out = SharedBuffer::default();
+        compact(buf, out.clone(), &options)?;
+        assert_eq!(compressed_data(&out).len(), 1);
+        assert!(out.0.lock().unwrap().get_ref().len() * 2 < plain_compacted);
+        let mut de = Deserializer::new(out.clone())?;
+        assert_eq!(&Image::deserialize(&mut de)?, versions.last().unwrap());
+        let report = verify(out);
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    // Byte values are compressed like any other
+    let payload: Vec<u8> = (0..4096u32).map(|i| (i % 7) as u8).collect();
+    let mut growth = Vec::new();
+    for compression in [None, Some(Compression::Zstd)] {
+        let buf = SharedBuffer::default();
+        let options = Options { compression, ..Options::default() };
+        let mut ser = Serializer::with_options(buf.clone(), options)?;
+        (Bytes(payload[..100].to_vec()), 1u8).serialize(&mut ser)?;
+        ser.finalize()?;
+        let len = buf.0.lock().unwrap().get_ref().len();
+        ser.reset()?;
+        (Bytes(payload.clone()), 1u8).serialize(&mut ser)?;
+        ser.finalize()?;
+        growth.push(buf.0.lock().unwrap().get_ref().len() - len);
+
+        let mut de = Deserializer::new(buf.clone())?;
+        assert_eq!(<(Vec<u8>, u8)>::deserialize(&mut de)?, (payload.clone(), 1));
+    }
+    assert!(growth[1] * 10 < growth[0], "{:?}", growth);
+
+    Ok(())
+}
+
+#[test]
+fn test_encryption() -> Result<()> {
+    let key = EncryptionKey::generate();
+    let options = Options { key: Some(key.clone()), ..Options::default() };
+    write_history(options.clone())?;
+
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+    (true, 1u8).serialize(&mut ser)?;
+    ser.finalize()?;
+
+    // Commands aren't readable
+    let contents = buf.0.lock().unwrap().get_ref().clone();
+    let header_len = contents.iter().position(|b| *b == b'\n').unwrap() + 1;
+    assert!(!contents[header_len..].windows(3).any(|w| w == b"\"v\""));
+
+    // Appending to the file through a new serializer
+    let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+    (true, 2u8).serialize(&mut ser)?;
+    ser.finalize()?;
+    for i in 0..3u8 {
+        [[i; 32]; 4].serialize(ser.document("large")?)?;
+        ser.finalize()?;
+    }
+    assert!(buf.0.lock().unwrap().get_ref().len() > 3 * 4096);
+    let mut de = Deserializer::with_options(buf.clone(), options.clone())?;
+    assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (true, 2));
+    assert_eq!(<[[u8; 32]; 4]>::deserialize(de.document("large")?)?, [[2; 32]; 4]);
+
+    let err = Deserializer::new(buf.clone()).err().unwrap();
+    assert!(err.to_string().contains("no key"), "{}", err);
+    let wrong = Options { key: Some(EncryptionKey::generate()), ..Options::default() };
+    let err = Deserializer::with_options(buf.clone(), wrong).err().unwrap();
+    assert!(err.to_string().contains("wrong encryption key"), "{}", err);
+
+    let read = |contents: &[u8]| -> serdif::Result<(bool, u8)> {
+        let mut de = Deserializer::with_options(Cursor::new(contents.to_vec()), options.clone())?;
+        <(bool, u8)>::deserialize(&mut de)
+    };
+    let contents = buf.0.lock().unwrap().get_ref().clone();
+    assert_eq!(read(&contents)?, (true, 2));
+
+    // Chunks can't be cut off the end of the file
+    let stored_chunk = 24 + 4096 + 16;
+    let err = read(&contents[..header_len + 2 * stored_chunk]).unwrap_err();
+    assert!(err.to_string().contains("failed authentication"), "{}", err);
+
+    // The header can't be changed either, as to allow unhashed trailers
+    let contents_str = String::from_utf8_lossy(&contents[..header_len]).to_string();
+    assert!(contents_str.contains("\"version\":1"), "{}", contents_str);
+    let downgraded = contents_str.replace("\"version\":1", "\"version\":0");
+    let mut tampered = downgraded.into_bytes();
+    tampered.extend_from_slice(&contents[header_len..]);
+    let err = read(&tampered).unwrap_err();
+    assert!(err.to_string().contains("failed authentication"), "{}", err);
+
+    // Or copied from another file encrypted with the same key
+    let other = SharedBuffer::default();
+    let mut ser = Serializer::with_options(other.clone(), options.clone())?;
+    for i in 0..3u8 {
+        (true, i).serialize(&mut ser)?;
+        [[i; 32]; 4].serialize(ser.document("large")?)?;
+        ser.finalize()?;
+        ser.reset()?;
+    }
+    let other = other.0.lock().unwrap().get_ref().clone();
+    assert_eq!(other.iter().position(|b| *b == b'\n').unwrap() + 1, header_len);
+    let mut spliced = contents.clone();
+    spliced[header_len..header_len + stored_chunk].copy_from_slice(&other[header_len..header_len + stored_chunk]);
+    let err = read(&spliced).unwrap_err();
+    assert!(err.to_string().contains("failed authentication"), "{}", err);
+
+    // Tampering with the data is detected
+    buf.0.lock().unwrap().get_mut()[header_len + 30] ^= 1;
+    assert!(Deserializer::with_options(buf, Options { key: Some(key), ..Options::default() }).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_recover_cut_encrypted() -> Result<()> {
+    let options = Options { key: Some(EncryptionKey::generate()), ..Options::default() };
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+    for i in 0..100u64 {
+        ser.reset()?;
+        (true, i).serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+    let contents = buf.0.lock().unwrap().get_ref().clone();
+    let header_len = contents.iter().position(|b| *b == b'\n').unwrap() + 1;
+    let (stored_chunk, chunk) = (24 + 4096 + 16, 4096);
+    assert!(contents.len() > header_len + 4 * stored_chunk);
+
+    // Cut off between chunks, the new last chunk fails authentication when
+    // opened normally
+    let cut = contents[..header_len + 3 * stored_chunk].to_vec();
+    let err = Deserializer::with_options(Cursor::new(cut.clone()), options.clone()).err().unwrap();
+    assert!(err.to_string().contains("failed authentication"), "{}", err);
+
+    // But is still recovered from
+    let (mut de, recovery) = recover(Cursor::new(cut.clone()), &options, RecoveryMode::ReadOnly)?;
+    assert!(recovery.trailer_pos.unwrap() > (header_len + 2 * chunk) as u64, "{:?}", recovery);
+    assert!(recovery.discarded < 1000, "{:?}", recovery);
+    let (_, last) = <(bool, u64)>::deserialize(&mut de)?;
+    assert_eq!(de.versions()?.len() as u64, last + 1);
+
+    let out = SharedBuffer::default();
+    let mode = RecoveryMode::Repair(Box::new(out.clone()));
+    recover(Cursor::new(cut), &options, mode)?;
+    let mut de = Deserializer::with_options(out.clone(), options.clone())?;
+    assert_eq!(<(bool, u64)>::deserialize(&mut de)?, (true, last));
+    let report = serdif::verify_with_options(out, &options);
+    assert!(report.is_ok(), "{}", report);
+
+    Ok(())
+}
+
+#[test]
+fn test_hash_chain() -> Result<()> {
+    let signing_key = SigningKey(b"local secret".to_vec());
+    let options = Options { signing_key: Some(signing_key), ..Options::default() };
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+    for v in [(true, 1u8), (false, 1), (false, 2)] {
+        ser.reset()?;
+        v.serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+
+    let mut de = Deserializer::with_options(buf.clone(), options.clone())?;
+    assert_eq!(de.verify_chain()?, None);
+    let mut de = Deserializer::new(buf.clone())?;
+    assert_eq!(de.verify_chain()?, None);
+    let wrong = Options { signing_key: Some(SigningKey(b"guess".to_vec())), ..Options::default() };
+    let mut de = Deserializer::with_options(buf.clone(), wrong)?;
+    let broken = de.verify_chain()?.unwrap();
+    assert_eq!(broken.version, 1);
+    assert!(broken.reason.contains("signature"), "{:?}", broken);
+
+    // Stripping the hashes and signatures breaks the chain too
+    let contents = buf.0.lock().unwrap().get_ref().clone();
+    let mut unsigned = contents.clone();
+    strip_field(&mut unsigned, "signature");
+    let mut de = Deserializer::with_options(Cursor::new(unsigned), options.clone())?;
+    let broken = de.verify_chain()?.unwrap();
+    assert_eq!(broken.version, 1);
+    assert!(broken.reason.contains("not signed"), "{:?}", broken);
+    let mut stripped = contents;
+    for field in ["prev_hash", "data_hash", "signature"] {
+        strip_field(&mut stripped, field);
+    }
+    for options in [options.clone(), Options::default()] {
+        let mut de = Deserializer::with_options(Cursor::new(stripped.clone()), options)?;
+        assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (false, 2));
+        let broken = de.verify_chain()?.unwrap();
+        assert_eq!(broken.version, 1);
+        assert!(broken.reason.contains("no hashes"), "{:?}", broken);
+    }
+
+    // Rewrite the first version's value in place
+    {
+        let mut buf = buf.0.lock().unwrap();
+        let contents = buf.get_mut();
+        let pos = contents.windows(4).position(|w| w == b"true").unwrap();
+        contents[pos..pos + 4].copy_from_slice(b"ture");
+    }
+    let mut de = Deserializer::new(buf)?;
+    let broken = de.verify_chain()?.unwrap();
+    assert_eq!(broken.version, 1);
+    assert!(broken.reason.contains("committed data"), "{:?}", broken);
+
+    Ok(())
+}
+
+/// Replace every string value of `field` in a JSON file with null,
+/// keeping everything else where it is.
+fn strip_field(contents: &mut [u8], field: &str) {
+    let pattern = format!("\"{}\": \"", field);
+    let mut i = 0;
+    while let Some(found) = contents[i..].windows(pattern.len()).position(|w| w == pattern.as_bytes()) {
+        let start = i + found + pattern.len() - 1;
+        let end = start + 2 + contents[start + 1..].iter().position(|b| *b == b'"').unwrap();
+        contents[start..end].fill(b' ');
+        contents[start..start + 4].copy_from_slice(b"null");
+        i = end;
+    }
+}
+
+#[test]
+fn test_verify() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    for v in [(true, 1u8), (false, 1), (false, 2)] {
+        ser.reset()?;
+        v.serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+    let report = verify(buf.clone());
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(report.versions, 3);
+
+    {
+        let mut buf = buf.0.lock().unwrap();
+        let contents = buf.get_mut();
+        // Point the first stitch at the middle of a command
+        let key = b"\"old_pos\": \"";
+        let pos = contents.windows(key.len()).position(|w| w == key).unwrap() + key.len();
+        let low = u8::from_str_radix(std::str::from_utf8(&contents[pos..pos + 2])?, 16)?;
+        contents[pos..pos + 2].copy_from_slice(format!("{:02x}", low + 1).as_bytes());
+        // Break the last stitch's payload
+        let key = b"\"v\": 2";
+        let pos = contents.windows(key.len()).rposition(|w| w == key).unwrap();
+        contents[pos + 1] = b'x';
+    }
+    let report = verify(buf);
+    assert_eq!(report.versions, 3);
+    let problems: Vec<_> = report.problems.iter().map(|p| p.message.as_str()).collect();
+    assert!(problems.iter().any(|p| p.contains("where no value starts")), "{}", report);
+    assert!(problems.iter().any(|p| p.contains("stitch payload")), "{}", report);
+    assert!(problems.iter().any(|p| p.contains("version 3")), "{}", report);
+
+    Ok(())
+}
+
+#[test]
+fn test_recover() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    for v in [(true, 1u8), (false, 1), (false, 2)] {
+        ser.reset()?;
+        v.serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+    let contents = buf.0.lock().unwrap().get_ref().clone();
+
+    // A long damaged tail hides the last trailer
+    let mut damaged = contents.clone();
+    damaged.extend(std::iter::repeat_n(b'#', 2000));
+    assert!(Deserializer::new(Cursor::new(damaged.clone())).is_err());
+    let (mut de, recovery) = recover(Cursor::new(damaged), &Options::default(), RecoveryMode::ReadOnly)?;
+    assert_eq!(recovery.versions, 3);
+    assert!(recovery.lost.is_empty());
+    assert_eq!(recovery.discarded, 2000);
+    assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (false, 2));
+
+    // The last commit's payload is damaged
+    let mut damaged = contents.clone();
+    let key = b"\"v\": 2";
+    let pos = damaged.windows(key.len()).rposition(|w| w == key).unwrap();
+    damaged[pos + 5] = b'3';
+    let (mut de, recovery) = recover(Cursor::new(damaged.clone()), &Options::default(), RecoveryMode::ReadOnly)?;
+    assert_eq!(recovery.versions, 2);
+    assert_eq!(recovery.lost.len(), 1);
+    assert_eq!(recovery.lost[0].version, Some(3));
+    assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (false, 1));
+
+    let clean = SharedBuffer::default();
+    let mode = RecoveryMode::Repair(Box::new(clean.clone()));
+    let (de, _) = recover(Cursor::new(damaged), &Options::default(), mode)?;
+    let report = verify(clean.clone());
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(report.versions, 2);
+    let mut ser = de.to_ser()?;
+    (true, 5u8).serialize(&mut ser)?;
+    ser.finalize()?;
+    let mut de = Deserializer::new(clean)?;
+    assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (true, 5));
+    assert_eq!(de.verify_chain()?, None);
+
+    // A damaged commit in the middle loses the versions after it
+    let mut damaged = contents.clone();
+    let key = b"\"v\": false";
+    let pos = damaged.windows(key.len()).position(|w| w == key).unwrap();
+    damaged[pos + 5..pos + 10].copy_from_slice(b"true ");
+    let (mut de, recovery) = recover(Cursor::new(damaged), &Options::default(), RecoveryMode::ReadOnly)?;
+    assert_eq!(recovery.versions, 1);
+    assert_eq!(recovery.lost.iter().map(|lost| lost.version).collect::<Vec<_>>(), [Some(2), Some(3)]);
+    assert!(recovery.lost[0].reason.contains("hash"), "{:?}", recovery);
+    assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (true, 1));
+
+    // Damaged commits are numbered by the trailers before them, so two
+    // written on top of the last intact one are both version 4
+    let buf = SharedBuffer::default();
+    buf.0.lock().unwrap().get_mut().extend_from_slice(&contents);
+    let mut ser = Serializer::new(buf.clone())?;
+    (true, 9u8).serialize(&mut ser)?;
+    ser.finalize()?;
+    let mut damaged = buf.0.lock().unwrap().get_ref().clone();
+    let tail = damaged[contents.len()..].to_vec();
+    damaged.extend_from_slice(&tail);
+    let key = b"\"v\": 9";
+    let pos = damaged.windows(key.len()).position(|w| w == key).unwrap();
+    damaged[pos + 5] = b'8';
+    let (mut de, recovery) = recover(Cursor::new(damaged), &Options::default(), RecoveryMode::ReadOnly)?;
+    assert_eq!(recovery.versions, 3);
+    assert_eq!(recovery.lost.iter().map(|lost| lost.version).collect::<Vec<_>>(), [Some(4), Some(4)]);
+    assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (false, 2));
+
+    Ok(())
+}
+
+#[test]
+fn test_versions() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    for v in [(true, 1u8), (false, 1), (false, 2)] {
+        ser.reset()?;
+        v.serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+
+    let mut de = Deserializer::new(buf)?;
+    let versions = de.versions()?;
+    assert_eq!(versions.len(), 3);
+    assert_eq!(versions[2].version, 3);
+    assert_eq!(versions[0].stitches.len(), 0);
+    assert_eq!(versions[1].stitches.len(), 1);
+    assert_eq!(versions[2].trailer.prev_trailer_pos, Some(versions[1].trailer_pos));
+    assert_eq!(de.read_json()?, serde_json::json!([false, 2]));
+
+    de.select_version(2)?;
+    assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (false, 1));
+    assert_eq!(de.versions()?.len(), 3);
+    de.select_version(1)?;
+    assert_eq!(de.read_json()?, serde_json::json!([true, 1]));
+    assert!(de.select_version(4).is_err());
+    assert!(de.to_ser().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_compact() -> Result<()> {
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Config {
+        enabled: bool,
+        limits: (u8, u8),
+    }
+
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    for i in 0..10u8 {
+        ser.reset()?;
+        Config { enabled: i % 2 == 0, limits: (i, 2) }.serialize(ser.document("config")?)?;
+        (true, i).serialize(ser.document("users")?)?;
+        let sizes: BTreeMap<_, _> = (0..i).map(|j| (j.to_string(), -i64::from(j))).collect();
+        (format!("v{}", i), sizes, Some(1.5)).serialize(ser.document("misc")?)?;
+        ser.finalize()?;
+    }
+
+    let out = SharedBuffer::default();
+    compact(buf.clone(), out.clone(), &Options::default())?;
+    let report = verify(out.clone());
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(report.versions, 1);
+    assert!(out.0.lock().unwrap().get_ref().len() < buf.0.lock().unwrap().get_ref().len());
+
+    let mut de = Deserializer::new(out.clone())?;
+    assert_eq!(Config::deserialize(de.document("config")?)?, Config { enabled: false, limits: (9, 2) });
+    assert_eq!(<(bool, u8)>::deserialize(de.document("users")?)?, (true, 9));
+    let misc = de.document("misc")?.read_json()?;
+    assert_eq!(misc[0], "v9");
+    assert_eq!(misc[1]["8"], -8);
+    assert_eq!(misc[2], 1.5);
+
+    // The compacted file keeps being written as usual
+    let mut ser = de.to_ser()?;
+    Config { enabled: true, limits: (9, 2) }.serialize(ser.document("config")?)?;
+    ser.finalize()?;
+    let mut de = Deserializer::new(out.clone())?;
+    assert_eq!(Config::deserialize(de.document("config")?)?, Config { enabled: true, limits: (9, 2) });
+    assert!(compact(buf, out, &Options::default()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_to_json() -> Result<()> {
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Config {
+        enabled: bool,
+        limits: (u8, u8),
+        rows: [[u8; 32]; 2],
+    }
+
+    #[derive(Serialize)]
+    enum Mark {
+        Empty,
+        Label(String),
+    }
+
+    let options = Options {
+        compression: Some(Compression::Zstd),
+        compression_threshold: 32,
+        ..Options::default()
+    };
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+    for i in 0..3u8 {
+        ser.reset()?;
+        Config { enabled: i == 1, limits: (i, 2), rows: [[i; 32]; 2] }.serialize(ser.document("config")?)?;
+        (true, i).serialize(ser.document("users")?)?;
+        (Mark::Empty, Mark::Label(format!("v{}", i)), -1i64, ()).serialize(ser.document("marks")?)?;
+        ser.finalize()?;
+    }
+
+    let mut de = Deserializer::with_options(buf, options)?;
+    let value = to_json_value(de.document("config")?, 2)?;
+    assert_eq!(value["enabled"], serde_json::json!(true));
+    assert_eq!(value["limits"], serde_json::json!([1, 2]));
+    assert_eq!(value["rows"][1][31], serde_json::json!(1));
+    let value = to_json_value(de.document("marks")?, 3)?;
+    assert_eq!(value, serde_json::json!(["Empty", { "Label": "v2" }, -1, null]));
+
+    let mut json = Vec::new();
+    to_json_writer(de.document("users")?, 1, &mut json)?;
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&json)?, serde_json::json!([true, 0]));
+
+    // The deserializer still reads the latest version
+    assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (true, 2));
+    assert_eq!(Config::deserialize(de.document("config")?)?.limits, (2, 2));
+    assert!(to_json_value(&mut de, 4).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_commit_info() -> Result<()> {
+    for encoding in [Encoding::Json, Encoding::Binary, Encoding::MessagePack] {
+        let buf = SharedBuffer::default();
+        let options = Options { encoding, ..Options::default() };
+        let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+        (true, 1u8).serialize(&mut ser)?;
+        ser.finalize_with(&CommitInfo { timestamp: Some(1000), message: Some("first".to_string()), ..CommitInfo::default() })?;
+        // Nothing changed, but the message is still committed
+        ser.reset()?;
+        (true, 1u8).serialize(&mut ser)?;
+        ser.finalize_with(&CommitInfo { message: Some("again".to_string()), ..CommitInfo::default() })?;
+        // Without info, nothing changed means no commit
+        ser.reset()?;
+        (true, 1u8).serialize(&mut ser)?;
+        ser.finalize()?;
+        ser.reset()?;
+        (false, 1u8).serialize(&mut ser)?;
+        ser.finalize()?;
+
+        let mut de = Deserializer::with_options(buf.clone(), options)?;
+        let versions = de.versions()?;
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[0].info.timestamp, Some(1000));
+        assert_eq!(versions[0].info.message.as_deref(), Some("first"));
+        assert_eq!(versions[1].info.timestamp, None);
+        assert_eq!(versions[1].info.message.as_deref(), Some("again"));
+        assert!(versions[2].info.is_empty());
+        assert_eq!(<(bool, u8)>::deserialize(&mut de)?, (false, 1));
+        let report = verify(buf);
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_import() -> Result<()> {
+    let snapshots = [
+        serde_json::json!({ "name": "prod", "replicas": 3, "ratio": 0.5, "owner": null,
+                            "hosts": ["a", "b"], "limits": { "cpu": -1, "mem": 512 } }),
+        serde_json::json!({ "name": "prod", "replicas": 4, "ratio": 0.5, "owner": null,
+                            "hosts": ["a", "b"], "limits": { "cpu": -1, "mem": 512 } }),
+        serde_json::json!({ "name": "prod", "replicas": 4, "ratio": 0.5, "owner": "ops",
+                            "hosts": ["a", "b", "c"], "limits": { "cpu": 2, "disk": 10 } }),
+        serde_json::json!([1, "two", 3.5]),
+    ];
+
+    for encoding in [Encoding::Json, Encoding::Binary, Encoding::MessagePack] {
+        let buf = SharedBuffer::default();
+        let options = Options { encoding, ..Options::default() };
+        let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+        let imported = import(&mut ser, snapshots.iter().cloned().enumerate().map(|(i, value)| {
+            Snapshot::new(value).message(format!("snapshot {}", i)).timestamp(1000 + i as u64)
+        }))?;
+        assert_eq!(imported, 4);
+        // The same snapshot again is committed for its message
+        import(&mut ser, Some(Snapshot::new(snapshots[3].clone()).message("again")))?;
+
+        let mut de = Deserializer::with_options(buf.clone(), options)?;
+        let versions = de.versions()?;
+        assert_eq!(versions.len(), 5);
+        assert_eq!(versions[1].info.message.as_deref(), Some("snapshot 1"));
+        assert_eq!(versions[1].info.timestamp, Some(1001));
+        assert_eq!(versions[4].info.timestamp, None);
+        // Changing one number stitches just that number
+        assert_eq!(versions[1].stitches.len(), 1);
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            assert_eq!(&to_json_value(&mut de, i + 1)?, snapshot);
+        }
+        assert_eq!(&to_json_value(&mut de, 5)?, &snapshots[3]);
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Config {
+            name: String,
+            replicas: u8,
+            owner: Option<String>,
+            hosts: Vec<String>,
+        }
+        de.select_version(3)?;
+        let config = Config::deserialize(&mut de)?;
+        assert_eq!(config.replicas, 4);
+        assert_eq!(config.owner.as_deref(), Some("ops"));
+        assert_eq!(config.hosts.len(), 3);
+        let report = verify(buf);
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_diff() -> Result<()> {
+    use serde_json::json;
+
+    let snapshots = [
+        json!({ "name": "prod", "replicas": 3, "owner": null, "hosts": ["a", "b"],
+                "limits": { "cpu": 1, "mem": 512 }, "big table": [1, 2, 3] }),
+        json!({ "name": "prod", "replicas": 4, "owner": null, "hosts": ["a", "b"],
+                "limits": { "cpu": 1, "mem": 512 }, "big table": [1, 2, 3] }),
+        json!({ "name": "prod", "replicas": 4, "owner": "ops", "hosts": ["a", "b", "c"],
+                "limits": { "cpu": 2, "disk": 10 }, "big table": [1, 2, 3] }),
+    ];
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    import(&mut ser, snapshots.iter().cloned())?;
+    let mut de = Deserializer::new(buf)?;
+
+    let change = |path: &str, op, old: Option<serde_json::Value>, new: Option<serde_json::Value>| Change {
+        path: path.to_string(), op, old, new,
+    };
+    assert_eq!(diff(&mut de, 1, 2)?, vec![
+        change(".replicas", Operation::Replace, Some(json!(3)), Some(json!(4))),
+    ]);
+    assert_eq!(diff(&mut de, 2, 3)?, vec![
+        change(".hosts[2]", Operation::Add, None, Some(json!("c"))),
+        change(".limits.cpu", Operation::Replace, Some(json!(1)), Some(json!(2))),
+        change(".limits.mem", Operation::Remove, Some(json!(512)), None),
+        change(".limits.disk", Operation::Add, None, Some(json!(10))),
+        change(".owner", Operation::Replace, Some(json!(null)), Some(json!("ops"))),
+    ]);
+    // Across several commits, and backwards
+    assert_eq!(diff(&mut de, 3, 1)?.len(), 6);
+    assert_eq!(diff(&mut de, 1, 3)?[0].path, ".hosts[2]");
+    assert!(diff(&mut de, 2, 2)?.is_empty());
+    assert_eq!(diff(&mut de, 0, 1)?, vec![change("", Operation::Add, None, Some(snapshots[0].clone()))]);
+
+    #[derive(Serialize)]
+    struct User {
+        name: String,
+        admin: bool,
+    }
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    let mut users = vec![User { name: "a".to_string(), admin: false }, User { name: "b".to_string(), admin: false }];
+    (true, &users).serialize(&mut ser)?;
+    ser.finalize()?;
+    users[1].name = "c".to_string();
+    ser.reset()?;
+    (true, &users).serialize(&mut ser)?;
+    ser.finalize()?;
+    let mut de = Deserializer::new(buf)?;
+    assert_eq!(diff(&mut de, 1, 2)?, vec![
+        change("[1][1].name", Operation::Replace, Some(json!("b")), Some(json!("c"))),
+    ]);
+
+    #[derive(Serialize)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+    }
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    (Shape::Rect { w: 1, h: 2 }, Shape::Circle(1.5)).serialize(&mut ser)?;
+    ser.finalize()?;
+    ser.reset()?;
+    (Shape::Rect { w: 1, h: 3 }, Shape::Empty).serialize(&mut ser)?;
+    ser.finalize()?;
+    let mut de = Deserializer::new(buf)?;
+    assert_eq!(diff(&mut de, 1, 2)?, vec![
+        change("[0].Rect.h", Operation::Replace, Some(json!(2)), Some(json!(3))),
+        change("[1]", Operation::Replace, Some(json!({ "Circle": 1.5 })), Some(json!("Empty"))),
+    ]);
+
+    Ok(())
+}
+
+#[test]
+fn test_json_patch() -> Result<()> {
+    use serde_json::json;
+
+    let snapshots = [
+        json!({ "name": "prod", "hosts": ["a", "b"], "limits": { "cpu": 1, "mem": 512 }, "a/b~c": 1 }),
+        json!({ "name": "prod", "hosts": ["a"], "limits": { "cpu": 2, "mem": 512 }, "a/b~c": 2 }),
+    ];
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    import(&mut ser, snapshots.iter().cloned())?;
+    let mut de = Deserializer::new(buf)?;
+
+    let patch = to_json_patch(&mut de, 1, 2)?;
+    assert_eq!(patch, json!([
+        { "op": "replace", "path": "/a~1b~0c", "value": 2 },
+        { "op": "remove", "path": "/hosts/1" },
+        { "op": "replace", "path": "/limits/cpu", "value": 2 },
+    ]));
+    assert_eq!(to_json_patch(&mut de, 0, 1)?, json!([{ "op": "add", "path": "", "value": snapshots[0] }]));
+
+    // Applying the patch to the first version gives the second
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    import(&mut ser, Some(snapshots[0].clone()))?;
+    ser.reset()?;
+    apply_json_patch(&mut ser, &patch)?;
+    let mut de = Deserializer::new(buf)?;
+    assert_eq!(de.versions()?.len(), 2);
+    assert_eq!(de.read_json()?, snapshots[1]);
+
+    // Documents written from Rust types keep their types
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Config {
+        name: String,
+        limits: (u8, u8),
+        hosts: Vec<String>,
+    }
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    Config { name: "prod".to_string(), limits: (1, 2), hosts: vec!["a".to_string()] }.serialize(&mut ser)?;
+    ser.finalize()?;
+    apply_json_patch(&mut ser, &json!([
+        { "op": "test", "path": "/name", "value": "prod" },
+        { "op": "replace", "path": "/limits/1", "value": 3 },
+        { "op": "add", "path": "/hosts/-", "value": "b" },
+        { "op": "copy", "from": "/hosts/0", "path": "/hosts/0" },
+        { "op": "move", "from": "/hosts/0", "path": "/name" },
+    ]))?;
+    let mut de = Deserializer::new(buf.clone())?;
+    assert_eq!(Config::deserialize(&mut de)?, Config {
+        name: "a".to_string(),
+        limits: (1, 3),
+        hosts: vec!["a".to_string(), "b".to_string()],
+    });
+
+    // A failed operation writes nothing
+    let len = buf.0.lock().unwrap().get_ref().len();
+    ser.reset()?;
+    assert!(apply_json_patch(&mut ser, &json!([
+        { "op": "replace", "path": "/limits/0", "value": 5 },
+        { "op": "test", "path": "/name", "value": "prod" },
+    ])).is_err());
+    assert!(apply_json_patch(&mut ser, &json!([{ "op": "remove", "path": "/missing" }])).is_err());
+    assert_eq!(buf.0.lock().unwrap().get_ref().len(), len);
+
+    // Tuples and structs keep the fields their types give them
+    for operation in [
+        json!({ "op": "add", "path": "/limits/1", "value": 4 }),
+        json!({ "op": "add", "path": "/limits/-", "value": 4 }),
+        json!({ "op": "remove", "path": "/limits/0" }),
+        json!({ "op": "move", "from": "/limits/0", "path": "/hosts/0" }),
+        json!({ "op": "add", "path": "/region", "value": "eu" }),
+        json!({ "op": "remove", "path": "/hosts" }),
+        json!({ "op": "move", "from": "/name", "path": "/title" }),
+    ] {
+        assert!(apply_json_patch(&mut ser, &json!([operation])).is_err(), "{}", operation);
+    }
+    assert_eq!(buf.0.lock().unwrap().get_ref().len(), len);
+    let mut de = Deserializer::new(buf.clone())?;
+    assert_eq!(Config::deserialize(&mut de)?.limits, (1, 3));
+
+    // Enum variants keep the types of their values, and can change variant
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Empty,
+        Rect(u8, u8),
+    }
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    (Shape::Rect(1, 2), Shape::Rect(3, 4)).serialize(&mut ser)?;
+    ser.finalize()?;
+    apply_json_patch(&mut ser, &json!([
+        { "op": "replace", "path": "/0/Rect/1", "value": 5 },
+        { "op": "replace", "path": "/1", "value": "Empty" },
+    ]))?;
+    let mut de = Deserializer::new(buf)?;
+    assert_eq!(<(Shape, Shape)>::deserialize(&mut de)?, (Shape::Rect(1, 5), Shape::Empty));
+
+    // Numbers are tested by value, so 1 matches a stored 1.0
+    let mut ser = Serializer::new(SharedBuffer::default())?;
+    import(&mut ser, Some(json!({ "ratio": 1.0, "limits": [2.5, 3], "max": u64::MAX })))?;
+    ser.reset()?;
+    apply_json_patch(&mut ser, &json!([
+        { "op": "test", "path": "/ratio", "value": 1 },
+        { "op": "test", "path": "", "value": { "ratio": 1, "limits": [2.5, 3.0], "max": u64::MAX } },
+    ]))?;
+    for value in [json!(1.5), json!("1"), json!(u64::MAX - 1)] {
+        let path = if value.is_u64() { "/max" } else { "/ratio" };
+        assert!(apply_json_patch(&mut ser, &json!([{ "op": "test", "path": path, "value": value }])).is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_diff_values() -> Result<()> {
+    use serde_json::json;
+
+    #[derive(Serialize, Clone)]
+    struct Service {
+        name: String,
+        port: u16,
+        weight: f64,
+        tags: Vec<&'static str>,
+        labels: BTreeMap<String, u8>,
+        owner: Option<String>,
+    }
+
+    let old = Service {
+        name: "api".to_string(),
+        port: 80,
+        weight: 0.0,
+        tags: vec!["a", "b"],
+        labels: vec![("env".to_string(), 1), ("tier".to_string(), 2)].into_iter().collect(),
+        owner: None,
+    };
+    assert!(diff_values(&old, &old)?.is_empty());
+
+    let mut new = old.clone();
+    new.port = 8080;
+    new.weight = -0.0;
+    new.tags.pop();
+    new.labels.remove("tier");
+    new.owner = Some("ops".to_string());
+    let change = |path: &str, op, old: Option<serde_json::Value>, new: Option<serde_json::Value>| Change {
+        path: path.to_string(), op, old, new,
+    };
+    assert_eq!(diff_values(&old, &new)?, vec![
+        change(".port", Operation::Replace, Some(json!(80)), Some(json!(8080))),
+        change(".weight", Operation::Replace, Some(json!(0.0)), Some(json!(-0.0))),
+        change(".tags[1]", Operation::Remove, Some(json!("b")), None),
+        change(".labels.tier", Operation::Remove, Some(json!(2)), None),
+        change(".owner", Operation::Replace, Some(json!(null)), Some(json!("ops"))),
+    ]);
+
+    // The same changes as between versions of a file
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    let versions = [(1u8, vec!["a", "b"]), (2, vec!["a", "c", "d"])];
+    for version in &versions {
+        ser.reset()?;
+        version.serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+    let mut de = Deserializer::new(buf)?;
+    assert_eq!(diff_values(&versions[0], &versions[1])?, diff(&mut de, 1, 2)?);
+
+    // Elements are changed one by one where the serializer would replace
+    // the whole sequence
+    assert_eq!(diff_values(&versions[0].1, &versions[1].1)?, vec![
+        change("[1]", Operation::Replace, Some(json!("b")), Some(json!("c"))),
+        change("[2]", Operation::Add, None, Some(json!("d"))),
+    ]);
+    let mut ser = Serializer::new(Cursor::new(Vec::new()))?;
+    versions[0].1.serialize(&mut ser)?;
+    ser.finalize()?;
+    ser.reset()?;
+    let changes = ser.dry_run(&versions[1].1)?;
+    assert_eq!(changes.stitches.iter().map(|c| c.path.as_str()).collect::<Vec<_>>(), [""]);
+
+    assert!(diff_values(&f64::NAN, &0.0).is_err());
+
+    // Anything the serializer writes can be compared
+    #[derive(Serialize)]
+    struct Point(i32, i32);
+
+    #[derive(Serialize)]
+    enum Shape {
+        Dot(Point),
+        Line { from: Point, to: Point },
+    }
+
+    let old = vec![Shape::Dot(Point(0, 0)), Shape::Line { from: Point(0, 0), to: Point(1, 1) }];
+    let new = vec![Shape::Dot(Point(0, 0)), Shape::Line { from: Point(0, 0), to: Point(1, 2) }];
+    assert_eq!(diff_values(&old, &new)?, vec![
+        change("[1].Line.to[1]", Operation::Replace, Some(json!(1)), Some(json!(2))),
+    ]);
+    assert_eq!(diff_values(&old[0], &old[1])?, vec![
+        change("", Operation::Replace, Some(json!({"Dot": [0, 0]})), Some(json!({"Line": {"from": [0, 0], "to": [1, 1]}}))),
+    ]);
+    assert_eq!(diff_values(&Bytes(vec![1, 2, 3]), &Bytes(vec![1, 4, 3, 5]))?, vec![
+        change("[1]", Operation::Replace, Some(json!(2)), Some(json!(4))),
+        change("[3]", Operation::Add, None, Some(json!(5))),
+    ]);
+    let labels = |label: &str| vec![(label.to_string(), Bytes(vec![1, 2, 3]))].into_iter().collect::<BTreeMap<_, _>>();
+    assert_eq!(diff_values(&labels("a"), &labels("b"))?, vec![
+        change(".a", Operation::Remove, Some(json!([1, 2, 3])), None),
+        change(".b", Operation::Add, None, Some(json!([1, 2, 3]))),
+    ]);
+
+    Ok(())
+}
+
+#[test]
+fn test_dry_run() -> Result<()> {
+    #[derive(Serialize, Clone)]
+    enum Shape {
+        Rect(u8, u8),
+    }
+
+    #[derive(Serialize, Clone)]
+    struct Config {
+        name: String,
+        limits: (u8, u8),
+        hosts: Vec<String>,
+        labels: BTreeMap<String, u8>,
+        shape: Shape,
+    }
+
+    for compression in [None, Some(Compression::Zstd)].iter().copied() {
+        let buf = SharedBuffer::default();
+        let options = Options { compression, compression_threshold: 16, ..Options::default() };
+        let mut ser = Serializer::with_options(buf.clone(), options)?;
+        let mut config = Config {
+            name: "prod".to_string(),
+            limits: (1, 2),
+            hosts: vec!["a".to_string(), "b".to_string()],
+            labels: BTreeMap::from([("env".to_string(), 1)]),
+            shape: Shape::Rect(1, 2),
+        };
+
+        let changes = ser.dry_run(&config)?;
+        assert!(changes.created && changes.stitches.is_empty() && changes.size > 0);
+        config.serialize(&mut ser)?;
+        ser.finalize()?;
+        let len = buf.0.lock().unwrap().get_ref().len();
+
+        ser.reset()?;
+        assert!(ser.dry_run(&config)?.is_empty());
+
+        config.limits.1 = 3;
+        config.hosts[0] = "c".to_string();
+        config.labels.insert("env".to_string(), 2);
+        config.shape = Shape::Rect(1, 3);
+        let changes = ser.dry_run(&config)?;
+        let paths: Vec<&str> = changes.stitches.iter().map(|stitch| stitch.path.as_str()).collect();
+        if compression.is_none() {
+            assert_eq!(paths, [".limits[1]", ".hosts[0]", ".labels.env", ".shape.Rect[1]"]);
+        }
+        assert_eq!(changes.size, changes.stitches.iter().map(|stitch| stitch.size).sum::<u64>());
+        assert_eq!(buf.0.lock().unwrap().get_ref().len(), len);
+
+        // The serializer is ready to write what was reported
+        config.serialize(&mut ser)?;
+        assert_eq!(buf.0.lock().unwrap().get_ref().len() as u64, len as u64 + changes.size);
+        // Pending changes are compared against
+        ser.reset()?;
+        assert!(ser.dry_run(&config)?.is_empty());
+        ser.finalize()?;
+        assert_eq!(Deserializer::new(buf)?.versions()?.len(), 2);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_paths() -> Result<()> {
+    #[derive(Serialize, Deserialize, Clone)]
+    struct Limits {
+        weight: f64,
+        sizes: (u8, u8),
+    }
+    #[derive(Serialize, Deserialize, Clone)]
+    struct Config {
+        name: String,
+        hosts: Vec<String>,
+        limits: Limits,
+    }
+
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    let mut config = Config {
+        name: "prod".to_string(),
+        hosts: vec!["a".to_string(), "b".to_string()],
+        limits: Limits { weight: 1.0, sizes: (1, 2) },
+    };
+    config.serialize(&mut ser)?;
+    ser.finalize()?;
+    ser.reset()?;
+    config.hosts[1] = "c".to_string();
+    config.limits.sizes.1 = 3;
+    config.serialize(&mut ser)?;
+    ser.finalize()?;
+
+    // Stitches record where the values they replace are
+    let mut de = Deserializer::new(buf.clone())?;
+    let versions = de.versions()?;
+    assert!(versions[0].paths.is_empty());
+    assert_eq!(versions[1].paths, [".hosts[1]", ".limits.sizes[1]"]);
+    assert!(verify(buf.clone()).is_ok());
+
+    // Errors say where they happened
+    ser.reset()?;
+    config.limits.weight = f64::NAN;
+    let e = config.serialize(&mut ser).unwrap_err();
+    assert_eq!(e.path(), Some(".limits.weight"));
+    assert!(e.to_string().starts_with(".limits.weight: "));
+
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Numbers {
+        name: String,
+        hosts: Vec<u8>,
+    }
+    let mut de = Deserializer::new(buf)?;
+    let e = Numbers::deserialize(&mut de).unwrap_err();
+    assert_eq!(e.path(), Some(".hosts[0]"));
+
+    // Inside map entries and enum variants too
+    #[derive(Serialize)]
+    enum Shape {
+        Circle(f64),
+    }
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    enum Count {
+        Circle(u8),
+    }
+    let mut ser = Serializer::new(Cursor::new(Vec::new()))?;
+    let e = BTreeMap::from([("a b", Shape::Circle(f64::NAN))]).serialize(&mut ser).unwrap_err();
+    assert_eq!(e.path(), Some("[\"a b\"].Circle"));
+    let mut ser = Serializer::new(Cursor::new(Vec::new()))?;
+    BTreeMap::from([("a b", Shape::Circle(1.5))]).serialize(&mut ser)?;
+    let mut de = ser.to_de()?;
+    let e = BTreeMap::<String, Count>::deserialize(&mut de).unwrap_err();
+    assert_eq!(e.path(), Some("[\"a b\"].Circle"));
+
+    Ok(())
+}
+
+#[test]
+fn test_blame() -> Result<()> {
+    use serde_json::json;
+
+    let snapshots = vec![
+        Snapshot::new(json!({ "name": "prod", "limits": { "cpu": 1, "max_conn": 10 }, "hosts": ["a"] })),
+        Snapshot::new(json!({ "name": "prod", "limits": { "cpu": 1, "max_conn": 20 }, "hosts": ["a"] })),
+        Snapshot::new(json!({ "name": "prod", "limits": { "cpu": 1, "max_conn": 20 }, "hosts": ["a", "b"] })),
+        Snapshot::new(json!({ "name": "prod", "limits": { "cpu": 1 }, "hosts": ["a", "b"] })).message("drop max_conn"),
+    ];
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    import(&mut ser, snapshots)?;
+    let mut de = Deserializer::new(buf)?;
+
+    let changes: Vec<(usize, Option<serde_json::Value>)> = history(&mut de, ".limits.max_conn")?
+        .into_iter()
+        .map(|(version, value)| (version.version, value))
+        .collect();
+    assert_eq!(changes, [(1, Some(json!(10))), (2, Some(json!(20))), (4, None)]);
+    assert_eq!(history(&mut de, ".hosts[1]")?.len(), 1);
+    assert!(history(&mut de, ".missing")?.is_empty());
+
+    // Values rewritten along with the map or sequence around them keep
+    // the version that last changed them
+    let annotated: Vec<(String, usize)> = blame(&mut de)?
+        .into_iter()
+        .map(|blame| (blame.path, blame.version))
+        .collect();
+    assert_eq!(annotated, [
+        (".hosts[0]".to_string(), 1),
+        (".hosts[1]".to_string(), 3),
+        (".limits.cpu".to_string(), 1),
+        (".name".to_string(), 1),
+    ]);
+    de.select_version(2)?;
+    assert_eq!(blame(&mut de)?[2].path, ".limits.max_conn");
+    assert_eq!(blame(&mut de)?[2].version, 2);
+
+    // Through enum variants
+    #[derive(Serialize)]
+    enum Shape {
+        Rect(u8, u8),
+    }
+    #[derive(Serialize)]
+    struct Drawing {
+        shape: Shape,
+    }
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    for h in [1, 2] {
+        ser.reset()?;
+        Drawing { shape: Shape::Rect(1, h) }.serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+    let mut de = Deserializer::new(buf)?;
+    let changes: Vec<(usize, Option<serde_json::Value>)> = history(&mut de, ".shape.Rect[1]")?
+        .into_iter()
+        .map(|(version, value)| (version.version, value))
+        .collect();
+    assert_eq!(changes, [(1, Some(json!(1))), (2, Some(json!(2)))]);
+    let annotated: Vec<(String, usize)> = blame(&mut de)?
+        .into_iter()
+        .map(|blame| (blame.path, blame.version))
+        .collect();
+    assert_eq!(annotated, [(".shape.Rect[0]".to_string(), 1), (".shape.Rect[1]".to_string(), 2)]);
+
+    Ok(())
+}
+
+#[test]
+fn test_revert_to() -> Result<()> {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Config {
+        name: String,
+        limits: (u8, u8),
+        hosts: Vec<String>,
+    }
+
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    let mut config = Config { name: "prod".to_string(), limits: (1, 2), hosts: vec!["a".to_string()] };
+    let first = config.clone();
+    for i in 0..3 {
+        ser.reset()?;
+        config.limits.1 += i;
+        config.hosts.push(i.to_string());
+        config.serialize(&mut ser)?;
+        ser.finalize()?;
+    }
+
+    ser.reset()?;
+    ser.revert_to(1)?;
+    let mut de = Deserializer::new(buf.clone())?;
+    assert_eq!(de.versions()?.len(), 4);
+    assert!(diff(&mut de, 1, 4)?.is_empty());
+    // Each part replaced by later versions is stitched back
+    assert_eq!(de.versions()?[3].paths, [".limits[1]", ".hosts"]);
+    let mut expected = first;
+    expected.hosts.push("0".to_string());
+    assert_eq!(Config::deserialize(&mut de)?, expected);
+
+    // Reverting leaves the serializer ready for the next pass
+    ser.reset()?;
+    config.serialize(&mut ser)?;
+    ser.finalize()?;
+    assert!(diff(&mut Deserializer::new(buf.clone())?, 3, 5)?.is_empty());
+
+    let len = buf.0.lock().unwrap().get_ref().len();
+    assert!(ser.revert_to(6).is_err());
+    assert!(ser.document("other")?.revert_to(1).is_err());
+    assert_eq!(buf.0.lock().unwrap().get_ref().len(), len);
+
+    Ok(())
+}
+
+#[test]
+fn test_undo() -> Result<()> {
+    let buf = SharedBuffer::default();
+    let mut undo = UndoManager::new(Serializer::new(buf.clone())?)?;
+    assert!(!undo.can_undo() && !undo.can_redo());
+    for v in 1..=3u8 {
+        undo.edit(&(v, true))?;
+    }
+    // Edits that change nothing aren't versions
+    undo.edit(&(3u8, true))?;
+    assert_eq!(undo.version(), Some(3));
+    let read = |buf: &SharedBuffer| -> Result<(u8, bool)> {
+        Ok(<(u8, bool)>::deserialize(&mut Deserializer::new(buf.clone())?)?)
+    };
+
+    assert!(undo.undo()?);
+    assert!(undo.undo()?);
+    assert_eq!(read(&buf)?, (1, true));
+    assert!(undo.redo()?);
+    assert_eq!(read(&buf)?, (2, true));
+    assert_eq!(undo.version(), Some(2));
+
+    // The history survives reopening the file
+    let ser = undo.into_serializer();
+    let mut undo = UndoManager::new(ser.to_de()?.to_ser()?)?;
+    assert!(undo.can_undo() && undo.can_redo());
+    assert_eq!(undo.version(), Some(2));
+
+    // A new edit drops what could have been redone
+    undo.edit(&(4u8, false))?;
+    assert!(!undo.can_redo());
+    assert!(!undo.redo()?);
+    assert!(undo.undo()?);
+    assert_eq!(read(&buf)?, (2, true));
+    assert!(undo.undo()?);
+    assert!(!undo.undo()?);
+    assert_eq!(read(&buf)?, (1, true));
+
+    let mut undo = UndoManager::new(Serializer::new(buf.clone())?)?;
+    assert!(!undo.can_undo());
+    assert!(undo.redo()?);
+    assert_eq!(read(&buf)?, (2, true));
+    let versions = Deserializer::new(buf)?.versions()?;
+    assert_eq!(versions.len(), 10);
+    assert_eq!(versions[4].info.undo, Some(1));
+
+    // Only commits that change the document are edits
+    let buf = SharedBuffer::default();
+    let mut undo = UndoManager::new(Serializer::new(buf.clone())?)?;
+    undo.edit(&(1u8, true))?;
+    undo.edit(&(2u8, true))?;
+    let info = CommitInfo { message: Some("unchanged".to_string()), ..Default::default() };
+    undo.edit_with(&(2u8, true), &info)?;
+    assert_eq!(undo.version(), Some(2));
+    let mut ser = undo.into_serializer();
+    ser.tag("v2")?;
+    (5u8, false).serialize(ser.document("other")?)?;
+    ser.finalize()?;
+    ser.reset()?;
+    let mut undo = UndoManager::new(ser)?;
+    assert_eq!(undo.version(), Some(2));
+    assert!(undo.undo()?);
+    assert_eq!(read(&buf)?, (1, true));
+    assert!(!undo.can_undo());
+    assert!(undo.redo()?);
+    assert_eq!(read(&buf)?, (2, true));
+    assert_eq!(Deserializer::new(buf)?.versions()?.len(), 7);
+
+    Ok(())
+}
+
+#[test]
+fn test_branches() -> Result<()> {
+    for encoding in [Encoding::Json, Encoding::Binary, Encoding::MessagePack] {
+        let buf = SharedBuffer::default();
+        let options = Options { encoding, ..Options::default() };
+        let mut ser = Serializer::with_options(buf.clone(), options.clone())?;
+        for limit in 1..=2u8 {
+            ser.reset()?;
+            (limit, "main").serialize(&mut ser)?;
+            ser.finalize()?;
+        }
+        ser.tag("release")?;
+        assert!(ser.tag("release").is_err());
+
+        ser.branch("production", "release")?;
+        assert_eq!(ser.selected_branch(), "production");
+        assert!(ser.branch("production", "release").is_err());
+        ser.reset()?;
+        (5u8, "production").serialize(&mut ser)?;
+        ser.finalize()?;
+
+        ser.checkout(DEFAULT_BRANCH)?;
+        ser.reset()?;
+        (3u8, "main").serialize(&mut ser)?;
+        ser.finalize()?;
+        assert!(ser.checkout("staging").is_err());
+        assert!(ser.branch("staging", "nightly").is_err());
+
+        // Each branch reads its own head, with the history it was made from
+        let mut de = Deserializer::with_options(buf.clone(), options.clone())?;
+        assert_eq!(<(u8, String)>::deserialize(&mut de)?, (3, "main".to_string()));
+        assert_eq!(de.versions()?.len(), 4);
+        let production = Options { branch: "production".to_string(), ..options.clone() };
+        let mut de = Deserializer::with_options(buf.clone(), production)?;
+        assert_eq!(<(u8, String)>::deserialize(&mut de)?, (5, "production".to_string()));
+        let versions = de.versions()?;
+        assert_eq!(versions.len(), 4);
+        assert!(versions[2].stitches.is_empty());
+        assert_eq!(diff(&mut de, 2, 4)?.len(), 2);
+        assert_eq!(de.verify_chain()?, None);
+
+        let refs = de.refs().clone();
+        assert_eq!(refs.heads.keys().collect::<Vec<_>>(), ["main", "production"]);
+        assert_eq!(refs.tags["release"], versions[1].trailer_pos);
+        de.select_tag("release")?;
+        assert_eq!(<(u8, String)>::deserialize(&mut de)?, (2, "main".to_string()));
+        assert!(de.to_ser().is_err());
+
+        let mut de = Deserializer::with_options(buf.clone(), options.clone())?;
+        de.checkout("production")?;
+        assert_eq!(<(u8, String)>::deserialize(&mut de)?, (5, "production".to_string()));
+        let report = serdif::verify_with_options(buf.clone(), &options);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.versions, 6);
+
+        // Branches other than the last committed to are checked too
+        if encoding == Encoding::Json {
+            let mut contents = buf.0.lock().unwrap().get_ref().clone();
+            let pos = contents.windows(17).position(|w| w == b"\"s\": \"production\"").unwrap();
+            contents[pos + 1] = b'x';
+            let report = serdif::verify_with_options(Cursor::new(contents), &options);
+            assert!(report.problems.iter().any(|p| p.message.contains("document")), "{}", report);
+            let head = refs.heads["production"];
+            assert!(report.problems.iter().any(|p| p.pos == Some(head) && p.message.contains("hash")), "{}", report);
+        }
+
+        // Compacting would lose the other branch and the tag
+        let err = compact(buf.clone(), Cursor::new(Vec::new()), &options).unwrap_err();
+        assert!(err.to_string().contains("production"), "{}", err);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_merge() -> Result<()> {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Config {
+        name: String,
+        limits: (u8, u8),
+        hosts: Vec<String>,
+    }
+
+    let write = |ser: &mut Serializer, config: &Config| -> Result<()> {
+        ser.reset()?;
+        config.serialize(&mut *ser)?;
+        ser.finalize()?;
+        Ok(())
+    };
+    let base = Config { name: "a".to_string(), limits: (1, 2), hosts: vec!["x".to_string()] };
+
+    // Two copies of a file edited apart
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    write(&mut ser, &base)?;
+    let copy = SharedBuffer(Arc::new(Mutex::new(buf.0.lock().unwrap().clone())));
+    write(&mut ser, &Config { limits: (5, 2), hosts: vec!["x".to_string(), "y".to_string()], ..base.clone() })?;
+    write(&mut Serializer::new(copy.clone())?, &Config {
+        name: "b".to_string(),
+        limits: (1, 7),
+        hosts: vec!["z".to_string()],
+    })?;
+
+    let mut base_de = Deserializer::new(buf.clone())?;
+    base_de.select_version(1)?;
+    let mut theirs = Deserializer::new(copy)?;
+    ser.reset()?;
+    let conflicts = merge(&mut ser, &mut base_de, &mut theirs, |conflict| {
+        assert_eq!(conflict.path, ".hosts");
+        Ok(Resolution::Value(serde_json::json!(["x", "y", "z"])))
+    })?;
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].theirs, Some(serde_json::json!(["z"])));
+    let merged = Config::deserialize(&mut Deserializer::new(buf.clone())?)?;
+    assert_eq!(merged, Config {
+        name: "b".to_string(),
+        limits: (5, 7),
+        hosts: vec!["x".to_string(), "y".to_string(), "z".to_string()],
+    });
+
+    // Branches of one file
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    write(&mut ser, &base)?;
+    ser.tag("base")?;
+    ser.branch("staging", "base")?;
+    write(&mut ser, &Config { limits: (1, 9), ..base.clone() })?;
+    ser.checkout(DEFAULT_BRANCH)?;
+    write(&mut ser, &Config { name: "c".to_string(), ..base.clone() })?;
+    let conflicts = merge_branch(&mut ser, "staging", |_| unreachable!())?;
+    assert!(conflicts.is_empty());
+    let expected = Config { name: "c".to_string(), limits: (1, 9), ..base.clone() };
+    let mut de = Deserializer::new(buf.clone())?;
+    assert_eq!(Config::deserialize(&mut de)?, expected);
+    let versions = de.versions()?;
+    assert_eq!(versions.len(), 4);
+    assert_eq!(versions[3].info.merge, Some(ser.refs().heads["staging"]));
+
+    // Merged changes aren't merged again
+    assert!(merge_branch(&mut ser, "staging", |_| unreachable!())?.is_empty());
+    assert_eq!(ser.versions()?.len(), 4);
+    ser.checkout("staging")?;
+    write(&mut ser, &Config { limits: (1, 10), ..base.clone() })?;
+    ser.checkout(DEFAULT_BRANCH)?;
+    write(&mut ser, &Config { limits: (1, 11), ..expected.clone() })?;
+    let conflicts = merge_branch(&mut ser, "staging", |conflict| {
+        assert_eq!((conflict.document.as_str(), conflict.path.as_str()), ("", ".limits[1]"));
+        assert_eq!(conflict.base, Some(9.into()));
+        Ok(Resolution::Ours)
+    })?;
+    assert_eq!(conflicts.len(), 1);
+    let mut de = Deserializer::new(buf.clone())?;
+    assert_eq!(Config::deserialize(&mut de)?, Config { limits: (1, 11), ..expected });
+
+    // Failing to resolve writes nothing
+    ser.checkout("staging")?;
+    write(&mut ser, &Config { limits: (1, 12), ..base.clone() })?;
+    ser.checkout(DEFAULT_BRANCH)?;
+    let len = buf.0.lock().unwrap().get_ref().len();
+    let result = merge_branch(&mut ser, "staging", |_| Err(anyhow::anyhow!("unresolved").into()));
+    assert_eq!(result.unwrap_err().path(), Some(".limits[1]"));
+    assert_eq!(buf.0.lock().unwrap().get_ref().len(), len);
+    assert!(serdif::verify(buf).is_ok());
+
+    // Values inside the same enum variant are merged
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Rect(u8, u8),
+    }
+    let buf = SharedBuffer::default();
+    let mut ser = Serializer::new(buf.clone())?;
+    Shape::Rect(1, 2).serialize(&mut ser)?;
+    ser.finalize()?;
+    ser.tag("base")?;
+    ser.branch("wide", "base")?;
+    ser.reset()?;
+    Shape::Rect(5, 2).serialize(&mut ser)?;
+    ser.finalize()?;
+    ser.checkout(DEFAULT_BRANCH)?;
+    ser.reset()?;
+    Shape::Rect(1, 7).serialize(&mut ser)?;
+    ser.finalize()?;
+    assert!(merge_branch(&mut ser, "wide", |_| unreachable!())?.is_empty());
+    assert_eq!(Shape::deserialize(&mut Deserializer::new(buf)?)?, Shape::Rect(5, 7));
+
+    Ok(())
+}
+
+#[test]
+fn test_conflict() -> Result<()> {
+    let file = SharedFile::default();
+    let mut a = Serializer::new(file.open())?;
+    (1u8, "a").serialize(&mut a)?;
+    a.finalize()?;
+
+    // Another writer commits between reading and writing
+    let mut b = Serializer::new(file.open())?;
+    a.reset()?;
+    (2u8, "a").serialize(&mut a)?;
+    a.finalize()?;
+    let e = (3u8, "b").serialize(&mut b).unwrap_err();
+    assert!(e.is_conflict(), "{}", e);
+
+    // Or between writing and committing, which isn't dropped by reset
+    b.reload()?;
+    (3u8, "b").serialize(&mut b)?;
+    a.reset()?;
+    (4u8, "a").serialize(&mut a)?;
+    a.finalize()?;
+    let e = b.finalize().unwrap_err();
+    assert!(e.is_conflict(), "{}", e);
+    assert!(e.to_string().contains("adding the trailer at"), "{}", e);
+    assert!(b.finalize().unwrap_err().is_conflict());
+    assert!(b.reset().unwrap_err().is_conflict());
+
+    // Reading again drops what was written and lets the write be retried
+    b.reload()?;
+    (3u8, "b").serialize(&mut b)?;
+    b.finalize()?;
+    assert!(a.reset().unwrap_err().is_conflict());
+    a.reload()?;
+    a.finalize()?;
+
+    let mut de = Deserializer::new(file.open())?;
+    assert_eq!(<(u8, String)>::deserialize(&mut de)?, (3, "b".to_string()));
+    let versions = de.versions()?;
+    assert_eq!(versions.len(), 4);
+    assert_eq!(de.verify_chain()?, None);
+    assert_eq!(diff(&mut de, 2, 3)?.len(), 1);
+    assert!(verify(file.open()).is_ok());
+
+    // A branch that fails to commit isn't switched to
+    a.tag("release")?;
+    b.reload()?;
+    a.reset()?;
+    (5u8, "a").serialize(&mut a)?;
+    a.finalize()?;
+    let e = b.branch("production", "release").unwrap_err();
+    assert!(e.is_conflict(), "{}", e);
+    assert_eq!(b.selected_branch(), DEFAULT_BRANCH);
+    assert!(!b.refs().heads.contains_key("production"));
+    b.reload()?;
+    assert_eq!(b.selected_branch(), DEFAULT_BRANCH);
+    b.branch("production", "release")?;
+    assert_eq!(b.selected_branch(), "production");
+    let mut de = Deserializer::new(file.open())?;
+    assert_eq!(<(u8, String)>::deserialize(&mut de)?, (5, "a".to_string()));
+
+    Ok(())
+}
+
+#[test]
+fn test_conflict_encrypted() -> Result<()> {
+    let options = Options { key: Some(EncryptionKey::generate()), ..Options::default() };
+    let file = SharedFile::default();
+    let mut a = Serializer::with_options(file.open(), options.clone())?;
+    (1u8, "a").serialize(&mut a)?;
+    a.finalize()?;
+
+    // Appends are seen through the other writer's cached chunk
+    let mut b = Serializer::with_options(file.open(), options.clone())?;
+    a.reset()?;
+    (2u8, "a").serialize(&mut a)?;
+    a.finalize()?;
+    assert!((3u8, "b").serialize(&mut b).unwrap_err().is_conflict());
+
+    // Changes not yet stored can't be once someone else commits
+    b.reload()?;
+    (3u8, "b").serialize(&mut b)?;
+    a.reset()?;
+    (4u8, "a").serialize(&mut a)?;
+    a.finalize()?;
+    let e = b.finalize().unwrap_err();
+    assert!(e.is_conflict(), "{}", e);
+    assert!(b.finalize().unwrap_err().is_conflict());
+    assert!(b.reset().unwrap_err().is_conflict());
+
+    b.reload()?;
+    (3u8, "b").serialize(&mut b)?;
+    [[3u8; 32]; 200].serialize(b.document("large")?)?;
+    b.finalize()?;
+    assert!(a.reset().unwrap_err().is_conflict());
+    a.reload()?;
+
+    let mut de = Deserializer::with_options(file.open(), options.clone())?;
+    assert_eq!(<(u8, String)>::deserialize(&mut de)?, (3, "b".to_string()));
+    assert_eq!(<Vec<[u8; 32]>>::deserialize(de.document("large")?)?, vec![[3; 32]; 200]);
+    assert_eq!(de.versions()?.len(), 4);
+    assert_eq!(de.verify_chain()?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_lock() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("serdif-test-lock-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut ser = Serializer::open_file(&path, LockMode::Exclusive)?;
+    (1u8, "a").serialize(&mut ser)?;
+    ser.finalize()?;
+    assert!(Serializer::try_open_file(&path, LockMode::Exclusive).err().unwrap().is_would_block());
+    assert!(Deserializer::try_open_file(&path, LockMode::Shared).err().unwrap().is_would_block());
+    let mut de = Deserializer::try_open_file(&path, LockMode::Unlocked)?;
+    assert_eq!(<(u8, String)>::deserialize(&mut de)?, (1, "a".to_string()));
+    drop(ser);
+
+    let mut de = Deserializer::try_open_file(&path, LockMode::Shared)?;
+    let other = Deserializer::try_open_file(&path, LockMode::Shared)?;
+    assert!(Serializer::try_open_file(&path, LockMode::Exclusive).err().unwrap().is_would_block());
+    assert_eq!(<(u8, String)>::deserialize(&mut de)?, (1, "a".to_string()));
+    drop((de, other));
+
+    let mut ser = Serializer::try_open_file(&path, LockMode::Exclusive)?;
+    (2u8, "b").serialize(&mut ser)?;
+    ser.finalize()?;
+    let mut de = ser.to_de()?;
+    assert_eq!(<(u8, String)>::deserialize(&mut de)?, (2, "b".to_string()));
+    assert_eq!(de.versions()?.len(), 2);
+    drop(de);
+    std::fs::remove_file(&path)?;
+
+    // Options such as a key are passed through
+    let options = Options { key: Some(EncryptionKey::generate()), ..Options::default() };
+    let mut ser = Serializer::open_file_with_options(&path, LockMode::Exclusive, options.clone())?;
+    (3u8, "c").serialize(&mut ser)?;
+    ser.finalize()?;
+    assert!(Deserializer::try_open_file_with_options(&path, LockMode::Shared, options.clone())
+        .err().unwrap().is_would_block());
+    drop(ser);
+    assert!(Deserializer::open_file(&path, LockMode::Shared).err().unwrap().to_string().contains("no key"));
+    let mut de = Deserializer::open_file_with_options(&path, LockMode::Shared, options.clone())?;
+    assert_eq!(<(u8, String)>::deserialize(&mut de)?, (3, "c".to_string()));
+    assert!(Serializer::try_open_file_with_options(&path, LockMode::Exclusive, options)
+        .err().unwrap().is_would_block());
+    drop(de);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_snapshot() -> Result<()> {
+    #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+    struct Config {
+        name: String,
+        limits: Vec<u32>,
+    }
+
+    let plain = Options::default();
+    let encrypted = Options { key: Some(EncryptionKey::generate()), ..Options::default() };
+    for options in [plain, encrypted] {
+        let mut ser = Serializer::with_options(Cursor::new(Vec::new()), options)?;
+        let mut empty = ser.snapshot()?;
+        assert!(empty.versions()?.is_empty());
+        Config { name: "a".to_string(), limits: vec![1, 2] }.serialize(&mut ser)?;
+        ser.finalize()?;
+        let mut first = ser.snapshot()?;
+
+        // Pending writes aren't seen
+        ser.reset()?;
+        Config { name: "b".to_string(), limits: vec![1, 2, 3] }.serialize(&mut ser)?;
+        assert_eq!(Config::deserialize(&mut first)?.name, "a");
+        ser.finalize()?;
+        first.reset()?;
+        assert_eq!(Config::deserialize(&mut first)?.name, "a");
+        assert_eq!(first.versions()?.len(), 1);
+
+        // Readers on other threads keep reading their version
+        let mut second = ser.snapshot()?;
+        let reader = std::thread::spawn(move || -> serdif::Result<Vec<Config>> {
+            let mut read = Vec::new();
+            for _ in 0..20 {
+                second.reset()?;
+                read.push(Config::deserialize(&mut second)?);
+            }
+            assert_eq!(second.versions()?.len(), 2);
+            Ok(read)
+        });
+        for i in 0..20 {
+            ser.reset()?;
+            Config { name: format!("c{}", i), limits: vec![i] }.serialize(&mut ser)?;
+            ser.finalize()?;
+        }
+        for config in reader.join().unwrap()? {
+            assert_eq!(config, Config { name: "b".to_string(), limits: vec![1, 2, 3] });
+        }
+
+        let mut de = ser.to_de()?;
+        assert_eq!(de.versions()?.len(), 22);
+        assert_eq!(Config::deserialize(&mut de)?.name, "c19");
+        first.reset()?;
+        assert_eq!(Config::deserialize(&mut first)?.name, "a");
+    }
+    Ok(())
+}
+
+/// Run the serdif command, returning its exit code and what it printed.
+fn serdif_cli(args: &[&str]) -> (i32, String, String) {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_serdif")).args(args).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.code().unwrap(), stdout, stderr)
+}
+
+#[test]
+fn test_cli() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("serdif-test-cli-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir)?;
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let file = &path("file.serdif");
+    let (one, two) = (&path("one.json"), &path("two.json"));
+    std::fs::write(one, r#"{"name": "a", "limits": {"max": 1}}"#)?;
+    std::fs::write(two, r#"{"name": "a", "limits": {"max": 2}}"#)?;
+
+    let (code, _, stderr) = serdif_cli(&["import", file, one, two]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    let (code, stdout, _) = serdif_cli(&["log", file]);
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("version 2\n"), "{}", stdout);
+    assert!(stdout.contains(&format!("message: {}", one)), "{}", stdout);
+
+    let (code, stdout, _) = serdif_cli(&["cat", file]);
+    assert_eq!(code, 0);
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&stdout)?, serde_json::json!({"name": "a", "limits": {"max": 2}}));
+    let (_, stdout, _) = serdif_cli(&["cat", "--version", "1", file]);
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&stdout)?["limits"]["max"], 1);
+
+    let (code, stdout, _) = serdif_cli(&["stitches", file]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("version 2 stitch at") && stdout.contains("(.limits.max)"), "{}", stdout);
+
+    let (code, stdout, _) = serdif_cli(&["history", file, ".limits.max"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("version 1: 1") && stdout.contains("version 2: 2"), "{}", stdout);
+
+    let (code, stdout, _) = serdif_cli(&["blame", file]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("   1 .name = \"a\"") && stdout.contains("   2 .limits.max = 2"), "{}", stdout);
+
+    let (code, stdout, _) = serdif_cli(&["refs", file]);
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with(&format!("branch {}: trailer at", DEFAULT_BRANCH)), "{}", stdout);
+
+    let (code, stdout, _) = serdif_cli(&["verify", file]);
+    assert_eq!(code, 0, "{}", stdout);
+
+    let out = &path("out.serdif");
+    let (code, _, stderr) = serdif_cli(&["compact", file, out]);
+    assert_eq!(code, 0, "{}", stderr);
+    let (_, stdout, _) = serdif_cli(&["log", out]);
+    assert!(stdout.starts_with("version 1\n") && !stdout.contains("version 2"), "{}", stdout);
+    let (code, _, stderr) = serdif_cli(&["compact", file, out]);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("creating"), "{}", stderr);
+
+    // Problems are reported with exit code 1, and errors with 2
+    let mut contents = std::fs::read(file)?;
+    strip_field(&mut contents, "data_hash");
+    std::fs::write(file, contents)?;
+    let (code, stdout, _) = serdif_cli(&["verify", file]);
+    assert_eq!(code, 1, "{}", stdout);
+    let (code, _, stderr) = serdif_cli(&["frobnicate", file]);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("unknown command"), "{}", stderr);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
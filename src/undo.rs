@@ -0,0 +1,162 @@
+//! Undo and redo, recorded in the file as commits.
+
+use serde::Serialize;
+
+use crate::diff::{self, LoadedDocument};
+use crate::error::Result;
+use crate::history;
+use crate::meta::CommitInfo;
+use crate::ser::Serializer;
+
+/// Undo and redo for the document selected in a serializer when it is
+/// wrapped.
+///
+/// Every version that changes the document is an edit, except those
+/// committed by `undo` and `redo`, which restore the document of an earlier
+/// edit and are marked in their `CommitInfo`. The undo history is worked
+/// out from those marks, so it survives reopening the file, and undoing
+/// never removes versions. Versions that only add tags or branches, or
+/// change other documents, aren't part of it.
+pub struct UndoManager {
+    ser: Serializer,
+    /// The versions committed by edits that can still be reached by undo
+    /// and redo, oldest first.
+    edits: Vec<usize>,
+    /// The edit the document is at.
+    cursor: usize,
+    /// The number of versions in the file.
+    versions: usize,
+    /// The document as the last version has it, to find whether the next
+    /// commit changes it.
+    last: LoadedDocument,
+}
+
+impl UndoManager {
+    /// Wrap `ser`, picking up the undo history recorded in its file.
+    ///
+    /// The versions are read once, and each is compared with the one
+    /// before it.
+    pub fn new(mut ser: Serializer) -> Result<UndoManager> {
+        let (edits, cursor, versions, last) = ser.with_state(|state, document| {
+            let versions = history::versions(state)?;
+            let mut edits = Vec::new();
+            let mut cursor: usize = 0;
+            let mut last = LoadedDocument::default();
+            for version in &versions {
+                let root = version.documents.get(document).copied();
+                if root == last.root() && version.stitches.is_empty() {
+                    continue;
+                }
+                let mut loaded = last.next(document, version);
+                let changed = diff::changed(state, &mut last, &mut loaded)?;
+                last = loaded;
+                if !changed {
+                    continue;
+                }
+                if version.info.undo.is_some() {
+                    cursor = cursor.saturating_sub(1);
+                } else if version.info.redo.is_some() {
+                    cursor = (cursor + 1).min(edits.len().saturating_sub(1));
+                } else {
+                    // Anything that could have been redone is lost
+                    edits.truncate(cursor + 1);
+                    edits.push(version.version);
+                    cursor = edits.len() - 1;
+                }
+            }
+            Ok((edits, cursor, versions.len(), last))
+        })?;
+        Ok(UndoManager { ser, edits, cursor, versions, last })
+    }
+
+    /// Serialize `value` as the selected document and commit it as an edit,
+    /// discarding anything that could be redone. Nothing is committed if the
+    /// document didn't change.
+    pub fn edit<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.edit_with(value, &CommitInfo::default())
+    }
+
+    /// Edit like `edit`, recording `info` with the commit. A commit made for
+    /// `info` when the document didn't change isn't an edit.
+    pub fn edit_with<T: ?Sized + Serialize>(&mut self, value: &T, info: &CommitInfo) -> Result<()> {
+        let document = self.ser.selected_document().to_string();
+        let last = self.ser.last_commit();
+        value.serialize(self.ser.document(&document)?)?;
+        self.ser.finalize_with(info)?;
+        if self.ser.last_commit() == last {
+            return Ok(());
+        }
+        self.versions += 1;
+        if self.commit_changed()? {
+            self.edits.truncate(self.cursor + 1);
+            self.edits.push(self.versions);
+            self.cursor = self.edits.len() - 1;
+        }
+        Ok(())
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.cursor > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.cursor + 1 < self.edits.len()
+    }
+
+    /// Restore the document as it was before the last edit, committing it.
+    /// Returns false if there is nothing to undo.
+    pub fn undo(&mut self) -> Result<bool> {
+        if !self.can_undo() {
+            return Ok(false);
+        }
+        let version = self.edits[self.cursor - 1];
+        let info = CommitInfo {
+            undo: Some(version as u64),
+            ..CommitInfo::default()
+        };
+        self.ser.revert_to_with(version, &info)?;
+        self.versions += 1;
+        self.last = self.ser.with_state(|state, document| Ok(LoadedDocument::current(state, document)))?;
+        self.cursor -= 1;
+        Ok(true)
+    }
+
+    /// Restore the document as it was after the last edit undone, committing
+    /// it. Returns false if there is nothing to redo.
+    pub fn redo(&mut self) -> Result<bool> {
+        if !self.can_redo() {
+            return Ok(false);
+        }
+        let version = self.edits[self.cursor + 1];
+        let info = CommitInfo {
+            redo: Some(version as u64),
+            ..CommitInfo::default()
+        };
+        self.ser.revert_to_with(version, &info)?;
+        self.versions += 1;
+        self.last = self.ser.with_state(|state, document| Ok(LoadedDocument::current(state, document)))?;
+        self.cursor += 1;
+        Ok(true)
+    }
+
+    /// Whether the version just committed changed the document, comparing
+    /// it with the one before.
+    fn commit_changed(&mut self) -> Result<bool> {
+        let last = &mut self.last;
+        self.ser.with_state(|state, document| {
+            let mut loaded = LoadedDocument::current(state, document);
+            let changed = diff::changed(state, last, &mut loaded)?;
+            *last = loaded;
+            Ok(changed)
+        })
+    }
+
+    /// The version of the edit the document is at, if there is one.
+    pub fn version(&self) -> Option<usize> {
+        self.edits.get(self.cursor).copied()
+    }
+
+    pub fn into_serializer(self) -> Serializer {
+        self.ser
+    }
+}
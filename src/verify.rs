@@ -0,0 +1,375 @@
+//! Checking the structure of a file.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::SeekFrom;
+
+use crate::chain;
+use crate::codec::Codec;
+use crate::error::{Result, StdResultExt};
+use crate::meta::{self, Stitch, Trailer, MAGIC};
+use crate::state::{Buffer, Options, State};
+
+/// The problems found in a file by `verify`.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// The number of versions committed by the trailers that could be read,
+    /// on every branch.
+    pub versions: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Something wrong with a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// Where the problem is, if it has a place.
+    pub pos: Option<u64>,
+    pub message: String,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn problem(&mut self, pos: impl Into<Option<u64>>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            pos: pos.into(),
+            message: message.into(),
+        });
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pos {
+            Some(pos) => write!(f, "at {}: {}", pos, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} versions, {} problems", self.versions, self.problems.len())?;
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the structure of every version in `buf`.
+///
+/// Every trailer is visited through `prev_trailer_pos`, from the head of
+/// every branch, and every stitch through `next_stitch_pos`. Their offsets must fall within the file and
+/// point at decodable commands, every stitch must replace a value that was
+/// written somewhere in the file, and every document must read as a
+/// well-formed tree of commands in every version. The hash chain of every
+/// branch is checked too, and signatures if `options` has a signing key.
+/// All problems found are reported, not just the first.
+pub fn verify(buf: impl Buffer) -> Report {
+    verify_with_options(buf, &Options::default())
+}
+
+/// Like `verify`, with options such as the key of an encrypted file.
+pub fn verify_with_options(buf: impl Buffer, options: &Options) -> Report {
+    let mut report = Report::default();
+    if let Err(e) = check(Box::new(buf), options, &mut report) {
+        report.problem(None, e.to_string());
+    }
+    report
+}
+
+/// A stitch as read by `verify`.
+struct StitchRecord {
+    pos: u64,
+    /// The position of the trailer committing it.
+    trailer_pos: u64,
+    stitch: Stitch,
+}
+
+fn check(buf: Box<dyn Buffer>, options: &Options, report: &mut Report) -> Result<()> {
+    let (mut buf, header, data_start) = State::open(buf, options)?;
+    let encoding = header.encoding;
+    let format_version = header.version;
+    let len = buf.seek(SeekFrom::End(0)).e()?;
+    let (last, last_pos) = match meta::find_last_trailer(&mut *buf, encoding, data_start) {
+        Ok(Some(last)) => last,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            report.problem(None, e.to_string());
+            return Ok(());
+        }
+    };
+    let in_file = |pos: u64| pos >= data_start && pos < len;
+
+    // The head of every branch. Those that can't be read are reported with
+    // the refs
+    let mut heads = vec![last_pos];
+    if let Ok(refs) = meta::read_refs(&mut *buf, encoding, &last) {
+        for &head in refs.heads.values() {
+            let found = head < last_pos && in_file(head)
+                && matches!(meta::read_trailer_at(&mut *buf, encoding, head), Ok(t) if t.magic == MAGIC);
+            if found {
+                heads.push(head);
+            }
+        }
+    }
+
+    // Every trailer that can be reached from a head, with the version it
+    // commits on its branch
+    let mut trailers: BTreeMap<u64, (Trailer, usize)> = BTreeMap::new();
+    for &head in &heads {
+        let mut chain = Vec::new();
+        let mut base = 0;
+        let mut next = Some(head);
+        while let Some(pos) = next {
+            if let Some((_, version)) = trailers.get(&pos) {
+                base = *version;
+                break;
+            }
+            let trailer = match meta::read_trailer_at(&mut *buf, encoding, pos) {
+                Ok(trailer) if trailer.magic == MAGIC => trailer,
+                Ok(_) => {
+                    report.problem(pos, "trailer has the wrong magic number");
+                    break;
+                }
+                Err(e) => {
+                    report.problem(pos, format!("undecodable trailer: {}", e));
+                    break;
+                }
+            };
+            next = match trailer.prev_trailer_pos {
+                Some(prev_pos) if prev_pos >= pos || !in_file(prev_pos) => {
+                    report.problem(pos, format!("previous trailer position {} is out of bounds", prev_pos));
+                    None
+                }
+                prev_pos => prev_pos,
+            };
+            chain.push((trailer, pos));
+        }
+        for (i, (trailer, pos)) in chain.into_iter().rev().enumerate() {
+            trailers.insert(pos, (trailer, base + i + 1));
+        }
+    }
+    report.versions = trailers.len();
+
+    let mut state = State::load_at(buf, options, header, data_start, None)?;
+    let mut stitches = Vec::new();
+    let mut catalogs = BTreeMap::new();
+    for (&pos, (trailer, _)) in &trailers {
+        let start = trailer.prev_trailer_pos.filter(|prev_pos| trailers.contains_key(prev_pos))
+            .unwrap_or(data_start);
+        catalogs.insert(pos, check_catalog(&mut state, report, trailer, pos, data_start));
+        check_info(&mut state, report, trailer, pos, start);
+        let count = stitches.len();
+        check_stitches(&mut state, report, trailer, pos, start, &mut stitches);
+        check_paths(&mut state, report, trailer, pos, start, stitches.len() - count);
+        check_refs(&mut state, report, trailer, pos, start);
+    }
+
+    // The position of every value written, as stitches must replace one
+    let mut starts = BTreeSet::new();
+    let roots: BTreeSet<(u64, &str)> = catalogs.values()
+        .flat_map(|documents| documents.iter().map(|(name, root)| (*root, name.as_str())))
+        .collect();
+    for (root, name) in roots {
+        state.seek(root)?;
+        if let Err(e) = state.scan_value(&mut |pos| { starts.insert(pos); }) {
+            report.problem(root, format!("document {:?}: {}", name, e));
+        }
+    }
+    for record in &stitches {
+        state.seek(record.stitch.new_pos)?;
+        let scanned = state.scan_value(&mut |pos| { starts.insert(pos); });
+        match scanned.and_then(|()| state.pos()) {
+            Ok(end) if end > record.stitch.next_stitch_pos => {
+                report.problem(record.pos, "stitch payload runs past the next stitch");
+            }
+            Ok(_) => { }
+            Err(e) => report.problem(record.pos, format!("stitch payload: {}", e)),
+        }
+    }
+    for record in &stitches {
+        if !starts.contains(&record.stitch.old_pos) {
+            report.problem(record.pos, format!("stitch replaces position {}, where no value starts",
+                                               record.stitch.old_pos));
+        }
+    }
+
+    // Every document of every version, with the stitches in effect on its
+    // branch, kept for as long as later trailers follow on from it
+    let mut followers: HashMap<u64, usize> = HashMap::new();
+    for (trailer, _) in trailers.values() {
+        if let Some(prev_pos) = trailer.prev_trailer_pos {
+            *followers.entry(prev_pos).or_default() += 1;
+        }
+    }
+    let mut in_effect: HashMap<u64, HashMap<u64, u64>> = HashMap::new();
+    let mut records = stitches.iter().peekable();
+    for (&pos, (trailer, version)) in &trailers {
+        let inherited = trailer.prev_trailer_pos.and_then(|prev_pos| {
+            let left = followers.get_mut(&prev_pos)?;
+            *left -= 1;
+            if *left == 0 { in_effect.remove(&prev_pos) } else { in_effect.get(&prev_pos).cloned() }
+        });
+        state.stitches = inherited.unwrap_or_default();
+        while let Some(record) = records.next_if(|record| record.trailer_pos == pos) {
+            state.stitches.insert(record.stitch.old_pos, record.stitch.new_pos);
+        }
+        for (name, root) in &catalogs[&pos] {
+            let node = state.seek(*root).and_then(|()| state.read_node());
+            if let Err(e) = node {
+                report.problem(*root, format!("version {}, document {:?}: {}", version, name, e));
+            }
+        }
+        if followers.get(&pos).is_some_and(|left| *left > 0) {
+            in_effect.insert(pos, std::mem::take(&mut state.stitches));
+        }
+    }
+
+    // The hash chain of every branch
+    let key = options.signing_key.as_ref();
+    let mut broken = BTreeSet::new();
+    for head in heads {
+        match chain::verify_chain(&mut *state.buf, encoding, format_version, key, Some(head)) {
+            Ok(Some(link)) if broken.insert(link.trailer_pos) => {
+                report.problem(link.trailer_pos, format!("hash chain broken: {}", link.reason));
+            }
+            Ok(_) => { }
+            Err(e) => report.problem(head, format!("hash chain: {}", e)),
+        }
+    }
+
+    Ok(())
+}
+
+/// The documents of a trailer's catalog, with roots that are out of bounds
+/// left out.
+fn check_catalog(state: &mut State, report: &mut Report, trailer: &Trailer, pos: u64, data_start: u64) -> BTreeMap<String, u64> {
+    if let Some(catalog_pos) = trailer.catalog {
+        // Catalogs are shared with earlier trailers until documents are added
+        if catalog_pos < data_start || catalog_pos >= pos {
+            report.problem(pos, format!("catalog position {} is out of bounds", catalog_pos));
+            return BTreeMap::new();
+        }
+    }
+    let catalog = match meta::read_catalog(&mut *state.buf, state.header.encoding, trailer) {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            report.problem(trailer.catalog, format!("undecodable catalog: {}", e));
+            return BTreeMap::new();
+        }
+    };
+    let mut documents = catalog.documents;
+    documents.retain(|name, root| {
+        let ok = *root < pos;
+        if !ok {
+            report.problem(trailer.catalog, format!("document {:?} root {} is out of bounds", name, root));
+        }
+        ok
+    });
+    documents
+}
+
+/// Read the commit info of a trailer, which is written with its commit.
+fn check_info(state: &mut State, report: &mut Report, trailer: &Trailer, pos: u64, start: u64) {
+    if let Some(info_pos) = trailer.info {
+        if info_pos < start || info_pos >= pos {
+            report.problem(pos, format!("commit info position {} is out of bounds", info_pos));
+        } else if let Err(e) = meta::read_info(&mut *state.buf, state.header.encoding, trailer) {
+            report.problem(info_pos, format!("undecodable commit info: {}", e));
+        }
+    }
+}
+
+/// Read the paths of a trailer's stitches, which are written with its
+/// commit and name every stitch.
+fn check_paths(state: &mut State, report: &mut Report, trailer: &Trailer, pos: u64, start: u64, stitches: usize) {
+    if let Some(paths_pos) = trailer.paths {
+        if paths_pos < start || paths_pos >= pos {
+            report.problem(pos, format!("stitch paths position {} is out of bounds", paths_pos));
+            return;
+        }
+        match meta::read_paths(&mut *state.buf, state.header.encoding, trailer) {
+            Ok(paths) if paths.len() != stitches => {
+                report.problem(paths_pos, format!("{} stitch paths for {} stitches", paths.len(), stitches));
+            }
+            Ok(_) => { }
+            Err(e) => report.problem(paths_pos, format!("undecodable stitch paths: {}", e)),
+        }
+    }
+}
+
+/// Read the branches and tags of a trailer, which are written with its
+/// commit and must point at earlier trailers.
+fn check_refs(state: &mut State, report: &mut Report, trailer: &Trailer, pos: u64, start: u64) {
+    let refs_pos = match trailer.refs {
+        Some(refs_pos) => refs_pos,
+        None => return,
+    };
+    if refs_pos < start || refs_pos >= pos {
+        report.problem(pos, format!("refs position {} is out of bounds", refs_pos));
+        return;
+    }
+    let encoding = state.header.encoding;
+    let refs = match meta::read_refs(&mut *state.buf, encoding, trailer) {
+        Ok(refs) => refs,
+        Err(e) => {
+            report.problem(refs_pos, format!("undecodable refs: {}", e));
+            return;
+        }
+    };
+    let heads = refs.heads.iter().map(|(name, pos)| ("branch", name, *pos));
+    let tags = refs.tags.iter().map(|(name, pos)| ("tag", name, *pos));
+    for (kind, name, target) in heads.chain(tags) {
+        let message = if target >= pos {
+            format!("{} {:?} points at {}, which is out of bounds", kind, name, target)
+        } else {
+            match meta::read_trailer_at(&mut *state.buf, encoding, target) {
+                Ok(found) if found.magic == MAGIC => continue,
+                Ok(_) => format!("{} {:?} points at {}, which has the wrong magic number", kind, name, target),
+                Err(e) => format!("{} {:?} points at {}, which is undecodable: {}", kind, name, target, e),
+            }
+        };
+        report.problem(refs_pos, message);
+    }
+}
+
+/// Read the stitches a trailer commits, which must all lie between the
+/// previous trailer, or the start of the data, and this one.
+fn check_stitches(state: &mut State, report: &mut Report, trailer: &Trailer, pos: u64, start: u64, stitches: &mut Vec<StitchRecord>) {
+    let encoding = state.header.encoding;
+    let mut stitch_pos = match trailer.first_stitch {
+        Some(stitch_pos) => stitch_pos,
+        None => return,
+    };
+    while stitch_pos != pos {
+        if stitch_pos < start || stitch_pos > pos {
+            report.problem(pos, format!("stitch position {} is out of bounds", stitch_pos));
+            return;
+        }
+        let stitch = state.seek(stitch_pos).and_then(|()| encoding.read_stitch(&mut *state.buf));
+        let stitch = match stitch {
+            Ok(stitch) => stitch,
+            Err(e) => {
+                report.problem(stitch_pos, format!("undecodable stitch: {}", e));
+                return;
+            }
+        };
+        if stitch.next_stitch_pos <= stitch_pos || stitch.next_stitch_pos > pos {
+            report.problem(stitch_pos, format!("next stitch position {} is out of bounds",
+                                               stitch.next_stitch_pos));
+            return;
+        }
+        if stitch.new_pos <= stitch_pos || stitch.new_pos >= stitch.next_stitch_pos {
+            report.problem(stitch_pos, format!("payload position {} is out of bounds", stitch.new_pos));
+        } else if stitch.old_pos >= stitch_pos {
+            report.problem(stitch_pos, format!("replaced position {} is out of bounds", stitch.old_pos));
+        } else {
+            stitches.push(StitchRecord { pos: stitch_pos, trailer_pos: pos, stitch });
+        }
+        stitch_pos = stitch.next_stitch_pos;
+    }
+}